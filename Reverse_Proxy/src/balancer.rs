@@ -0,0 +1,409 @@
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use arc_swap::ArcSwap;
+use crate::circuit::Circuit;
+use crate::config::Config;
+use crate::report;
+
+/// How requests are spread over the backends
+///
+/// * `RoundRobin` - Each backend in turn.
+/// * `LeastConnections` - The backend with the fewest requests being forwarded.
+/// * `Weighted` - Each backend in turn, as many times in a row as its weight.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Strategy {
+    RoundRobin,
+    LeastConnections,
+    Weighted,
+}
+
+impl FromStr for Strategy {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Strategy, String> {
+        match text.to_lowercase().as_str() {
+            "round-robin" => Ok(Strategy::RoundRobin),
+            "least-connections" => Ok(Strategy::LeastConnections),
+            "weighted" => Ok(Strategy::Weighted),
+            _ => Err(format!("Unknown balancing strategy ({})", text))
+        }
+    }
+}
+
+/// A backend as written in ```./proxy.conf```, like ```127.0.0.1:1446 weight=3```
+///
+/// # Arguments
+/// * `address` - Address the backend listens at.
+/// * `weight` - Share of the requests it gets with the weighted strategy.
+#[derive(Clone, Copy)]
+pub struct BackendEntry {
+    pub address: SocketAddr,
+    pub weight: usize,
+}
+
+impl FromStr for BackendEntry {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<BackendEntry, String> {
+        let mut parts = text.split_whitespace();
+        let address = parts.next().and_then(|address| address.parse().ok())
+            .ok_or_else(|| format!("Invalid backend address ({})", text))?;
+        let weight = match parts.next() {
+            Some(weight) => weight.strip_prefix("weight=").and_then(|weight| weight.parse().ok())
+                .filter(|weight| *weight > 0)
+                .ok_or_else(|| format!("Invalid backend weight ({})", text))?,
+            None => 1
+        };
+
+        Ok(BackendEntry { address, weight })
+    }
+}
+
+/// What a server tells about itself when it joins the pool, through the headers of ```/register-secret```
+///
+/// # Arguments
+/// * `id` - Name of the backend, unique in the pool.
+/// * `entry` - Address and weight of the backend.
+/// * `prefixes` - Paths the backend serves, every path when empty.
+pub struct Registration {
+    pub id: String,
+    pub entry: BackendEntry,
+    pub prefixes: Vec<String>,
+}
+
+/// A backend of the pool, along with the requests being forwarded to it
+///
+/// # Arguments
+/// * `id` - Name of the backend, the address for the ones set in ```./proxy.conf```.
+/// * `entry` - Address and weight of the backend.
+/// * `prefixes` - Paths the backend serves, every path when empty.
+/// * `pinned` - Whether it is set in ```./proxy.conf```, so it stays in the pool when it leaves.
+/// * `active` - Requests being forwarded to it right now.
+/// * `circuit` - Circuit breaker, which fails requests fast while the backend keeps failing them.
+pub struct Backend {
+    pub id: String,
+    pub entry: BackendEntry,
+    pub prefixes: Vec<String>,
+    pub pinned: bool,
+    active: AtomicUsize,
+    circuit: Circuit,
+}
+
+impl Backend {
+    /// Requests being forwarded to the backend right now
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    /// Where the backend's circuit is: closed, open or half-open
+    pub fn circuit(&self) -> &'static str {
+        self.circuit.name()
+    }
+
+    /// How well the backend serves a path
+    ///
+    /// # Arguments
+    /// * `uri: &str` - Path of the request.
+    ///
+    /// ## Returns
+    /// The length of the longest prefix that matches, 0 for a backend that serves every path
+    /// None if the backend does not serve the path
+    fn serves(&self, uri: &str) -> Option<usize> {
+        if self.prefixes.is_empty() {
+            return Some(0);
+        }
+        self.prefixes.iter().filter(|prefix| uri.starts_with(prefix.as_str())).map(|prefix| prefix.len()).max()
+    }
+}
+
+/// Backend picked for a request, which counts as an active request until it is dropped
+///
+/// # Arguments
+/// * `backend` - Picked backend.
+/// * `settled` - Whether the outcome of the request was recorded in the backend's circuit.
+pub struct Lease {
+    backend: Arc<Backend>,
+    settled: bool,
+}
+
+impl Lease {
+    /// Address the picked backend listens at
+    pub fn address(&self) -> SocketAddr {
+        self.backend.entry.address
+    }
+
+    /// Records that the backend answered the request
+    pub fn succeeded(&mut self) {
+        self.settled = true;
+        if self.backend.circuit.success() {
+            report(format!("Server ({}) answered again >>> Closing its circuit", self.address()));
+        }
+    }
+
+    /// Records that the backend failed the request
+    pub fn failed(&mut self) {
+        self.settled = true;
+        if self.backend.circuit.failure() {
+            report(format!("Server ({}) keeps failing requests >>> Opening its circuit for {}s", self.address(), self.backend.circuit.cooldown().as_secs()));
+        }
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        self.backend.active.fetch_sub(1, Ordering::Relaxed);
+        //A probe whose request ended for another reason lets the next request probe instead
+        if !self.settled {
+            self.backend.circuit.release();
+        }
+    }
+}
+
+/// Pool of backends, which spreads requests over them
+///
+/// Backends set in ```./proxy.conf``` are always in it, other servers join and leave while the proxy runs.
+/// Requests read the pool without a lock, joining and leaving swap in a new one.
+///
+/// # Arguments
+/// * `backends` - Every backend, in the order they joined.
+/// * `strategy` - How requests are spread.
+/// * `next` - Turn counter of the round-robin and weighted strategies.
+/// * `circuit_failures` - Failures in a row that open a backend's circuit.
+/// * `circuit_cooldown` - How long a circuit stays open before a probe is let through.
+pub struct Balancer {
+    backends: ArcSwap<Vec<Arc<Backend>>>,
+    strategy: Strategy,
+    next: AtomicUsize,
+    circuit_failures: u32,
+    circuit_cooldown: Duration,
+}
+
+impl Balancer {
+    pub fn new(config: &Config) -> Balancer {
+        let circuit_cooldown = Duration::from_secs(config.circuit_cooldown);
+        let backends = config.backends.iter().map(|entry| Arc::new(Backend {
+            id: entry.address.to_string(),
+            entry: *entry,
+            prefixes: Vec::new(),
+            pinned: true,
+            active: AtomicUsize::new(0),
+            circuit: Circuit::new(config.circuit_failures, circuit_cooldown),
+        })).collect();
+
+        Balancer {
+            backends: ArcSwap::from_pointee(backends),
+            strategy: config.balancing,
+            next: AtomicUsize::new(0),
+            circuit_failures: config.circuit_failures,
+            circuit_cooldown
+        }
+    }
+
+    /// Every backend of the pool
+    pub fn backends(&self) -> Vec<Arc<Backend>> {
+        self.backends.load().iter().cloned().collect()
+    }
+
+    /// Adds a backend to the pool, or updates it when its address is already there
+    ///
+    /// Only the server of a live address may update it, which the caller checks with its key.
+    ///
+    /// # Arguments
+    /// * `registration: Registration` - What the server told about itself.
+    ///
+    /// ## Returns
+    /// Nothing if the backend is in the pool
+    /// Why it was refused, like an ID that another backend already has
+    pub fn register(&self, registration: Registration) -> Result<(), String> {
+        let mut refused = None;
+        self.backends.rcu(|backends| {
+            let mut backends = Vec::clone(backends);
+            let address = registration.entry.address;
+            refused = backends.iter()
+                .find(|backend| backend.id == registration.id && backend.entry.address != address)
+                .map(|backend| format!("Backend ID ({}) is already used by ({})", backend.id, backend.entry.address));
+            if refused.is_some() {
+                return backends;
+            }
+
+            let pinned = backends.iter().any(|backend| backend.entry.address == address && backend.pinned);
+            let backend = Arc::new(Backend {
+                id: registration.id.clone(),
+                entry: registration.entry,
+                prefixes: registration.prefixes.clone(),
+                pinned,
+                active: AtomicUsize::new(0),
+                circuit: Circuit::new(self.circuit_failures, self.circuit_cooldown),
+            });
+            match backends.iter().position(|backend| backend.entry.address == address) {
+                Some(index) => backends[index] = backend,
+                None => backends.push(backend)
+            }
+            backends
+        });
+
+        match refused {
+            Some(reason) => Err(reason),
+            None => Ok(())
+        }
+    }
+
+    /// Takes a backend out of the pool, unless it is set in ```./proxy.conf```
+    ///
+    /// # Arguments
+    /// * `address: &SocketAddr` - Address the backend listens at.
+    pub fn deregister(&self, address: &SocketAddr) {
+        self.backends.rcu(|backends| {
+            let mut backends = Vec::clone(backends);
+            backends.retain(|backend| backend.pinned || backend.entry.address != *address);
+            backends
+        });
+    }
+
+    /// Picks the backend that gets a request
+    ///
+    /// # Arguments
+    /// * `uri: &str` - Path of the request, only backends that serve it are picked.
+    /// * `usable: F` - Whether a backend may get requests, like when it has a key and is healthy.
+    ///
+    /// ## Returns
+    /// The picked backend, counted as active until the lease is dropped
+    /// None if no backend is usable or every usable one has an open circuit
+    pub fn pick<F: Fn(&SocketAddr) -> bool>(&self, uri: &str, usable: F) -> Option<Lease> {
+        let backends = self.backends.load();
+        let usable: Vec<(&Arc<Backend>, usize)> = backends.iter()
+            .filter(|backend| usable(&backend.entry.address) && backend.circuit.allows())
+            .filter_map(|backend| backend.serves(uri).map(|matched| (backend, matched)))
+            .collect();
+        //The backends with the most specific prefix get the request
+        let longest = usable.iter().map(|(_, matched)| *matched).max()?;
+        let mut usable: Vec<&Arc<Backend>> = usable.into_iter().filter(|(_, matched)| *matched == longest).map(|(backend, _)| backend).collect();
+
+        //Another request may have taken the probe of a half-open backend in the meantime
+        while !usable.is_empty() {
+            let backend = self.choose(&usable);
+            if backend.circuit.admit() {
+                backend.active.fetch_add(1, Ordering::Relaxed);
+                return Some(Lease { backend: Arc::clone(backend), settled: false });
+            }
+            usable.retain(|other| !Arc::ptr_eq(other, backend));
+        }
+        None
+    }
+
+    /// Applies the strategy to the backends that may get a request
+    ///
+    /// # Arguments
+    /// * `usable: &[&Arc<Backend>]` - Backends that may get the request, at least one.
+    fn choose<'a>(&self, usable: &[&'a Arc<Backend>]) -> &'a Arc<Backend> {
+        let turn = self.next.fetch_add(1, Ordering::Relaxed);
+        match self.strategy {
+            Strategy::RoundRobin => usable[turn % usable.len()],
+            //Ties start from a different backend each time, so idle backends share the load
+            Strategy::LeastConnections => (0..usable.len())
+                .map(|offset| usable[(turn + offset) % usable.len()])
+                .min_by_key(|backend| backend.active.load(Ordering::Relaxed))
+                .unwrap_or(usable[0]),
+            Strategy::Weighted => {
+                let total: usize = usable.iter().map(|backend| backend.entry.weight).sum();
+                let mut slot = turn % total;
+                let mut picked = usable[0];
+                for backend in usable {
+                    if slot < backend.entry.weight {
+                        picked = backend;
+                        break;
+                    }
+                    slot -= backend.entry.weight;
+                }
+                picked
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn balancer(strategy: Strategy, backends: &[(&str, usize)]) -> Balancer {
+        Balancer::new(&Config {
+            balancing: strategy,
+            backends: backends.iter().map(|(address, weight)| BackendEntry { address: address.parse().unwrap(), weight: *weight }).collect(),
+            ..Config::default()
+        })
+    }
+
+    fn registration(id: &str, address: &str, prefixes: &[&str]) -> Registration {
+        Registration {
+            id: id.to_string(),
+            entry: BackendEntry { address: address.parse().unwrap(), weight: 1 },
+            prefixes: prefixes.iter().map(|prefix| prefix.to_string()).collect(),
+        }
+    }
+
+    /// Ports of the backends picked for a few requests in a row, each one ended before the next
+    fn picks(balancer: &Balancer, uri: &str, requests: usize) -> Vec<u16> {
+        (0..requests).map(|_| balancer.pick(uri, |_| true).unwrap().address().port()).collect()
+    }
+
+    #[test]
+    fn takes_turns_with_round_robin() {
+        let balancer = balancer(Strategy::RoundRobin, &[("127.0.0.1:1", 1), ("127.0.0.1:2", 5), ("127.0.0.1:3", 1)]);
+        assert_eq!(picks(&balancer, "/", 6), [1, 2, 3, 1, 2, 3]);
+    }
+
+    #[test]
+    fn picks_the_least_busy_backend() {
+        let balancer = balancer(Strategy::LeastConnections, &[("127.0.0.1:1", 1), ("127.0.0.1:2", 1)]);
+        let first = balancer.pick("/", |_| true).unwrap();
+        let second = balancer.pick("/", |_| true).unwrap();
+        assert_ne!(first.address(), second.address());
+
+        //The backend whose request ended gets the next ones
+        let busy = second.address();
+        drop(first);
+        assert!(picks(&balancer, "/", 4).iter().all(|port| *port != busy.port()));
+    }
+
+    #[test]
+    fn spreads_requests_by_weight() {
+        let balancer = balancer(Strategy::Weighted, &[("127.0.0.1:1", 3), ("127.0.0.1:2", 1)]);
+        let picked = picks(&balancer, "/", 8);
+        assert_eq!(picked.iter().filter(|port| **port == 1).count(), 6);
+        assert_eq!(picked.iter().filter(|port| **port == 2).count(), 2);
+    }
+
+    #[test]
+    fn routes_by_the_longest_prefix() {
+        let balancer = balancer(Strategy::RoundRobin, &[("127.0.0.1:1", 1)]);
+        balancer.register(registration("api", "127.0.0.1:2", &["/api"])).unwrap();
+        balancer.register(registration("admin", "127.0.0.1:3", &["/api/admin", "/admin"])).unwrap();
+
+        assert_eq!(picks(&balancer, "/api/files", 2), [2, 2]);
+        assert_eq!(picks(&balancer, "/api/admin/users", 2), [3, 3]);
+        assert_eq!(picks(&balancer, "/admin", 1), [3]);
+        assert_eq!(picks(&balancer, "/index.html", 2), [1, 1]);
+        //Without a usable backend for the prefix, the request gets none
+        assert!(balancer.pick("/api/files", |backend| backend.port() != 2).is_some_and(|lease| lease.address().port() == 1));
+        assert!(balancer.pick("/", |_| false).is_none());
+    }
+
+    #[test]
+    fn keeps_ids_unique_and_pinned_backends_in_the_pool() {
+        let balancer = balancer(Strategy::RoundRobin, &[("127.0.0.1:1", 1)]);
+        balancer.register(registration("files", "127.0.0.1:2", &[])).unwrap();
+        assert!(balancer.register(registration("files", "127.0.0.1:3", &[])).is_err());
+
+        //A pinned backend that registers keeps its place, and stays when it leaves
+        balancer.register(registration("main", "127.0.0.1:1", &["/data"])).unwrap();
+        balancer.deregister(&"127.0.0.1:1".parse().unwrap());
+        balancer.deregister(&"127.0.0.1:2".parse().unwrap());
+        let backends = balancer.backends();
+        assert_eq!(backends.len(), 1);
+        assert_eq!(backends[0].id, "main");
+        assert!(backends[0].pinned);
+    }
+}
@@ -0,0 +1,559 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use crate::config::Config;
+use crate::Request;
+
+/// A server's response kept in the cache
+///
+/// # Arguments
+/// * `head` - Response head as sent to clients, without the security headers.
+/// * `body` - Whole response body.
+/// * `etag` - Validator given by the server, used to ask whether the response is still current.
+/// * `stored` - When the response was stored, or last confirmed by the server.
+/// * `fresh_for` - How long after `stored` the response is answered without asking the server.
+pub struct Cached {
+    pub head: String,
+    pub body: Vec<u8>,
+    pub etag: Option<String>,
+    stored: Instant,
+    fresh_for: Duration,
+}
+
+impl Cached {
+    /// Whether the response may be answered without asking the server
+    fn is_fresh(&self) -> bool {
+        self.stored.elapsed() < self.fresh_for
+    }
+
+    /// Seconds since the server gave or confirmed the response, sent to clients as ```Age```
+    pub fn age(&self) -> u64 {
+        self.stored.elapsed().as_secs()
+    }
+
+    /// Bytes the response takes from the memory budget
+    fn size(&self) -> usize {
+        self.head.len() + self.body.len()
+    }
+}
+
+/// What the cache holds for a request
+///
+/// * `Fresh` - A response that answers the request without the server.
+/// * `Stale` - A response that may still be current, which the server is asked about through its ETag.
+/// * `Miss` - Nothing usable, the request goes to the server.
+pub enum Lookup {
+    Fresh(Arc<Cached>),
+    Stale(Arc<Cached>),
+    Miss,
+}
+
+/// A stored response along with its place in the LRU order
+///
+/// # Arguments
+/// * `cached` - Stored response.
+/// * `used` - Tick of its last use, its key in `recency`.
+struct Slot {
+    cached: Arc<Cached>,
+    used: u64,
+}
+
+/// Everything the cache holds, behind a single lock
+///
+/// # Arguments
+/// * `slots` - Stored responses, by URI and the values of the request headers they vary on.
+/// * `vary` - Request headers that the responses of each URI vary on, as told by the server's ```Vary```.
+/// * `recency` - Keys of the stored responses by last use, the least recently used first.
+/// * `size` - Bytes taken by every stored response.
+/// * `clock` - Tick of the last use.
+/// * `generation` - Purges made so far, so answers asked for before a purge are not stored after it.
+#[derive(Default)]
+struct Entries {
+    slots: HashMap<String, Slot>,
+    vary: HashMap<String, Vec<String>>,
+    recency: BTreeMap<u64, String>,
+    size: usize,
+    clock: u64,
+    generation: u64,
+}
+
+impl Entries {
+    /// Key of the response that answers a request, given the headers its URI varies on
+    ///
+    /// # Arguments
+    /// * `request: &Request` - Request of the client.
+    fn key(&self, request: &Request) -> String {
+        let mut key = request.uri.clone();
+        for name in self.vary.get(&request.uri).into_iter().flatten() {
+            key.push('\n');
+            key.push_str(request.header(name).unwrap_or_default());
+        }
+        key
+    }
+
+    /// Returns a stored response and marks it as the most recently used
+    ///
+    /// # Arguments
+    /// * `key: &str` - Key of the response.
+    fn touch(&mut self, key: &str) -> Option<Arc<Cached>> {
+        self.clock += 1;
+        let slot = self.slots.get_mut(key)?;
+        self.recency.remove(&slot.used);
+        slot.used = self.clock;
+        self.recency.insert(self.clock, key.to_string());
+        Some(Arc::clone(&slot.cached))
+    }
+
+    /// Stores a response, replacing the one with the same key
+    ///
+    /// # Arguments
+    /// * `key: String` - Key of the response.
+    /// * `cached: Arc<Cached>` - Response to store.
+    fn insert(&mut self, key: String, cached: Arc<Cached>) {
+        self.remove(&key);
+        self.clock += 1;
+        self.size += cached.size();
+        self.recency.insert(self.clock, key.clone());
+        self.slots.insert(key, Slot { cached, used: self.clock });
+    }
+
+    /// Removes a stored response
+    ///
+    /// # Arguments
+    /// * `key: &str` - Key of the response.
+    fn remove(&mut self, key: &str) -> bool {
+        let Some(slot) = self.slots.remove(key) else {
+            return false;
+        };
+        self.recency.remove(&slot.used);
+        self.size -= slot.cached.size();
+        true
+    }
+
+    /// Removes every stored response of a URI, whatever the headers it varies on
+    ///
+    /// # Arguments
+    /// * `uri: &str` - URI of the responses.
+    ///
+    /// ## Returns
+    /// The amount of removed responses
+    fn remove_uri(&mut self, uri: &str) -> usize {
+        let keys: Vec<String> = self.slots.keys()
+            .filter(|key| key.split('\n').next() == Some(uri))
+            .cloned()
+            .collect();
+        self.vary.remove(uri);
+        keys.iter().filter(|key| self.remove(key)).count()
+    }
+
+    /// Removes the least recently used response
+    fn evict(&mut self) {
+        if let Some((_, key)) = self.recency.pop_first()
+            && let Some(slot) = self.slots.remove(&key) {
+            self.size -= slot.cached.size();
+        }
+    }
+}
+
+/// LRU cache of the servers' responses, bounded by a memory budget
+///
+/// Only GET responses that the server marks as shareable are stored, following its ```Cache-Control```,
+/// ```ETag``` and ```Vary``` headers. Stale responses with an ETag are confirmed by the server with a 304.
+///
+/// # Arguments
+/// * `entries` - Stored responses and their LRU order.
+/// * `budget` - Bytes every stored response may take together. Disabled when 0.
+/// * `max_entry` - Bytes a single response may take.
+pub struct ResponseCache {
+    entries: Mutex<Entries>,
+    budget: usize,
+    max_entry: usize,
+}
+
+impl ResponseCache {
+    pub fn new(config: &Config) -> ResponseCache {
+        ResponseCache {
+            entries: Mutex::new(Entries::default()),
+            budget: config.cache_size,
+            max_entry: config.cache_max_entry.min(config.cache_size),
+        }
+    }
+
+    /// Locks the entries, which are always whole since no change can panic halfway
+    fn entries(&self) -> MutexGuard<'_, Entries> {
+        self.entries.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Purges made so far, taken before a request is sent to the server and given back to [`ResponseCache::store`]
+    /// or [`ResponseCache::refresh`], which store nothing if a purge happened while the server answered
+    pub fn generation(&self) -> u64 {
+        self.entries().generation
+    }
+
+    /// Looks for the response that answers a request
+    ///
+    /// # Arguments
+    /// * `request: &Request` - Request of the client.
+    pub fn lookup(&self, request: &Request) -> Lookup {
+        if self.budget == 0 || request.method != "GET" {
+            return Lookup::Miss;
+        }
+        let mut entries = self.entries();
+        let key = entries.key(request);
+        let Some(cached) = entries.touch(&key) else {
+            return Lookup::Miss;
+        };
+
+        //A reload asks the server again, as if the response were stale
+        let reload = ["Cache-Control", "Pragma"].iter()
+            .any(|name| request.header(name).is_some_and(|value| value.to_lowercase().contains("no-cache")));
+        if cached.is_fresh() && !reload {
+            Lookup::Fresh(cached)
+        } else if cached.etag.is_some() {
+            Lookup::Stale(cached)
+        } else {
+            Lookup::Miss
+        }
+    }
+
+    /// Decides whether the server's answer to a request is stored
+    ///
+    /// # Arguments
+    /// * `request: &Request` - Request of the client.
+    /// * `head: &str` - Response head of the server.
+    /// * `length: Option<u64>` - Size of the response body, when the server told it.
+    ///
+    /// ## Returns
+    /// How long the response is answered without asking the server
+    /// None if it must not be stored
+    pub fn storable(&self, request: &Request, head: &str, length: Option<u64>) -> Option<Duration> {
+        if self.budget == 0 || request.method != "GET" || head.split_whitespace().nth(1) != Some("200") {
+            return None;
+        }
+        if length.is_none_or(|length| length as usize + head.len() > self.max_entry) {
+            return None;
+        }
+        let directives = directives(head);
+        //Answers meant for one user, or that set cookies, are never shared with other clients
+        if directives.iter().any(|directive| directive == "no-store" || directive == "private")
+            || header(head, "Set-Cookie").is_some()
+            || header(head, "Vary").is_some_and(|vary| vary.trim() == "*") {
+            return None;
+        }
+        let shared = directives.iter().any(|directive| directive == "public" || directive.starts_with("s-maxage="));
+        if request.header("Authorization").is_some() && !shared {
+            return None;
+        }
+
+        let fresh_for = lifetime(&directives)?;
+        //A response that is always stale is only worth keeping if the server can confirm it
+        if fresh_for.is_zero() && header(head, "ETag").is_none() {
+            return None;
+        }
+        Some(fresh_for)
+    }
+
+    /// Stores the server's answer to a request
+    ///
+    /// # Arguments
+    /// * `request: &Request` - Request of the client.
+    /// * `head: String` - Response head as sent to clients, without the security headers.
+    /// * `body: Vec<u8>` - Whole response body.
+    /// * `fresh_for: Duration` - How long the response is answered without asking the server.
+    /// * `generation: u64` - Generation of the cache when the request was sent to the server.
+    pub fn store(&self, request: &Request, head: String, body: Vec<u8>, fresh_for: Duration, generation: u64) {
+        let vary: Vec<String> = header(&head, "Vary")
+            .map(|vary| vary.split(',').map(|name| name.trim().to_string()).filter(|name| !name.is_empty()).collect())
+            .unwrap_or_default();
+        let etag = header(&head, "ETag").map(|etag| etag.trim().to_string());
+        let cached = Arc::new(Cached { head, body, etag, stored: Instant::now(), fresh_for });
+
+        let mut entries = self.entries();
+        if entries.generation != generation {
+            return;
+        }
+        //Responses stored under other Vary headers would never be found again
+        if entries.vary.get(&request.uri).map_or(!vary.is_empty(), |names| *names != vary) {
+            entries.remove_uri(&request.uri);
+            entries.vary.insert(request.uri.clone(), vary);
+        }
+        let key = entries.key(request);
+        entries.insert(key, cached);
+        while entries.size > self.budget {
+            entries.evict();
+        }
+    }
+
+    /// Marks a stale response as current again, after the server answered 304
+    ///
+    /// # Arguments
+    /// * `request: &Request` - Request of the client.
+    /// * `stale: &Cached` - Response that was confirmed.
+    /// * `head: &str` - Head of the server's 304 answer, which may give a new lifetime.
+    /// * `generation: u64` - Generation of the cache when the request was sent to the server.
+    ///
+    /// ## Returns
+    /// The confirmed response, which is only stored again if no purge happened meanwhile
+    pub fn refresh(&self, request: &Request, stale: &Cached, head: &str, generation: u64) -> Arc<Cached> {
+        let fresh_for = lifetime(&directives(head)).unwrap_or(stale.fresh_for);
+        let cached = Arc::new(Cached {
+            head: stale.head.clone(),
+            body: stale.body.clone(),
+            etag: stale.etag.clone(),
+            stored: Instant::now(),
+            fresh_for
+        });
+
+        let mut entries = self.entries();
+        if entries.generation == generation {
+            let key = entries.key(request);
+            entries.insert(key, Arc::clone(&cached));
+        }
+        cached
+    }
+
+    /// Removes stored responses
+    ///
+    /// # Arguments
+    /// * `uri: Option<&str>` - URI whose responses are removed, every response when None.
+    ///
+    /// ## Returns
+    /// The amount of removed responses
+    pub fn purge(&self, uri: Option<&str>) -> usize {
+        let mut entries = self.entries();
+        entries.generation += 1;
+        match uri {
+            Some(uri) => entries.remove_uri(uri),
+            None => {
+                let removed = entries.slots.len();
+                let generation = entries.generation;
+                *entries = Entries { generation, ..Entries::default() };
+                removed
+            }
+        }
+    }
+
+    /// Removes the stored responses that an upload of a file may have changed: the pages that
+    /// show the file, like ```/name``` or ```/?file=name```, and the index that lists every file
+    ///
+    /// # Arguments
+    /// * `file_name: &str` - Name of the uploaded file.
+    ///
+    /// ## Returns
+    /// The amount of removed responses
+    pub fn purge_file(&self, file_name: &str) -> usize {
+        let mut entries = self.entries();
+        entries.generation += 1;
+        let mut uris: Vec<String> = entries.slots.keys()
+            .filter_map(|key| key.split('\n').next())
+            .filter(|uri| shows_file(uri, file_name))
+            .map(|uri| uri.to_string())
+            .collect();
+        uris.sort();
+        uris.dedup();
+        uris.iter().map(|uri| entries.remove_uri(uri)).sum()
+    }
+}
+
+/// Whether the page of a URI shows a file, or lists the files as the index does
+///
+/// # Arguments
+/// * `uri: &str` - URI of a stored response.
+/// * `file_name: &str` - Name of the file.
+fn shows_file(uri: &str, file_name: &str) -> bool {
+    let (path, query) = uri.split_once('?').unwrap_or((uri, ""));
+    path == "/"
+        || path.strip_prefix('/') == Some(file_name)
+        || query.split('&').filter_map(|pair| pair.split_once('=')).any(|(_, value)| value == file_name)
+}
+
+/// Finds a header in a response head
+///
+/// # Arguments
+/// * `head: &str` - Response head.
+/// * `name: &str` - Header's name.
+fn header<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+    head.lines().skip(1)
+        .filter_map(|line| line.split_once(':'))
+        .find(|(n, _)| n.trim().eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim())
+}
+
+/// Directives of a response's ```Cache-Control```, in lowercase
+///
+/// # Arguments
+/// * `head: &str` - Response head.
+fn directives(head: &str) -> Vec<String> {
+    header(head, "Cache-Control")
+        .map(|value| value.split(',').map(|directive| directive.trim().to_lowercase()).collect())
+        .unwrap_or_default()
+}
+
+/// How long a response stays fresh, ```s-maxage``` taking over ```max-age``` since the proxy is a shared cache
+///
+/// # Arguments
+/// * `directives: &[String]` - Directives of the response's ```Cache-Control```.
+///
+/// ## Returns
+/// The lifetime, zero for ```no-cache```
+/// None if the server did not give one
+fn lifetime(directives: &[String]) -> Option<Duration> {
+    if directives.iter().any(|directive| directive == "no-cache") {
+        return Some(Duration::ZERO);
+    }
+    let seconds = |prefix: &str| directives.iter()
+        .find_map(|directive| directive.strip_prefix(prefix).and_then(|seconds| seconds.trim_matches('"').parse().ok()));
+
+    seconds("s-maxage=").or_else(|| seconds("max-age=")).map(Duration::from_secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache(size: usize) -> ResponseCache {
+        ResponseCache::new(&Config { cache_size: size, cache_max_entry: size, ..Config::default() })
+    }
+
+    fn get(uri: &str, headers: &[(&str, &str)]) -> Request {
+        Request {
+            signature: String::new(),
+            method: "GET".to_string(),
+            uri: uri.to_string(),
+            version: "HTTP/1.1".to_string(),
+            host: "localhost".to_string(),
+            body: String::new(),
+            headers: headers.iter().map(|(n, v)| (n.to_string(), v.to_string())).collect(),
+            upload: None,
+            keep_alive: true,
+            forwarded_tls: false
+        }
+    }
+
+    fn head(headers: &str) -> String {
+        format!("HTTP/1.1 200 OK\r\nContent-Length: 4\r\n{}\r\n", headers)
+    }
+
+    /// Stores a response the way the proxy does, when the cache takes it
+    fn store(cache: &ResponseCache, request: &Request, head: String, body: &[u8]) -> bool {
+        let generation = cache.generation();
+        let Some(fresh_for) = cache.storable(request, &head, Some(body.len() as u64)) else {
+            return false;
+        };
+        cache.store(request, head, body.to_vec(), fresh_for, generation);
+        true
+    }
+
+    #[test]
+    fn reads_lifetimes() {
+        let directives = |value: &str| directives(&head(&format!("Cache-Control: {}\r\n", value)));
+
+        assert_eq!(lifetime(&directives("public, max-age=60")), Some(Duration::from_secs(60)));
+        assert_eq!(lifetime(&directives("max-age=60, s-maxage=\"5\"")), Some(Duration::from_secs(5)));
+        assert_eq!(lifetime(&directives("max-age=60, no-cache")), Some(Duration::ZERO));
+        assert_eq!(lifetime(&directives("public")), None);
+    }
+
+    #[test]
+    fn stores_only_shareable_responses() {
+        let cache = cache(1024);
+        let request = get("/a.txt", &[]);
+
+        assert!(cache.storable(&request, &head("Cache-Control: max-age=60\r\n"), Some(4)).is_some());
+        assert!(cache.storable(&request, &head("Cache-Control: max-age=60\r\n"), None).is_none());
+        assert!(cache.storable(&request, &head("Cache-Control: private, max-age=60\r\n"), Some(4)).is_none());
+        assert!(cache.storable(&request, &head("Cache-Control: no-store\r\n"), Some(4)).is_none());
+        assert!(cache.storable(&request, &head("Cache-Control: max-age=60\r\nSet-Cookie: a=1\r\n"), Some(4)).is_none());
+        assert!(cache.storable(&request, &head("Cache-Control: max-age=60\r\nVary: *\r\n"), Some(4)).is_none());
+        assert!(cache.storable(&request, &head("Cache-Control: no-cache\r\n"), Some(4)).is_none());
+        assert!(cache.storable(&request, &head("Cache-Control: no-cache\r\nETag: \"1\"\r\n"), Some(4)).is_some());
+        assert!(cache.storable(&request, &head(""), Some(4)).is_none());
+        assert!(cache.storable(&request, "HTTP/1.1 404 Not Found\r\nCache-Control: max-age=60\r\n\r\n", Some(4)).is_none());
+        assert!(cache.storable(&request, &head("Cache-Control: max-age=60\r\n"), Some(4096)).is_none());
+
+        let authorized = get("/a.txt", &[("Authorization", "Basic YTpi")]);
+        assert!(cache.storable(&authorized, &head("Cache-Control: max-age=60\r\n"), Some(4)).is_none());
+        assert!(cache.storable(&authorized, &head("Cache-Control: public, max-age=60\r\n"), Some(4)).is_some());
+    }
+
+    #[test]
+    fn answers_fresh_and_stale_responses() {
+        let cache = cache(1024);
+        let fresh = get("/fresh", &[]);
+        let stale = get("/stale", &[]);
+
+        assert!(store(&cache, &fresh, head("Cache-Control: max-age=60\r\n"), b"data"));
+        assert!(store(&cache, &stale, head("Cache-Control: no-cache\r\nETag: \"v1\"\r\n"), b"data"));
+
+        assert!(matches!(cache.lookup(&fresh), Lookup::Fresh(cached) if cached.body == b"data"));
+        assert!(matches!(cache.lookup(&get("/fresh", &[("Cache-Control", "no-cache")])), Lookup::Miss));
+        assert!(matches!(cache.lookup(&get("/missing", &[])), Lookup::Miss));
+
+        let Lookup::Stale(cached) = cache.lookup(&stale) else {
+            panic!("Stale response was not found");
+        };
+        assert_eq!(cached.etag.as_deref(), Some("\"v1\""));
+        let refreshed = cache.refresh(&stale, &cached, "HTTP/1.1 304 Not Modified\r\nCache-Control: max-age=60\r\n\r\n", cache.generation());
+        assert_eq!(refreshed.body, b"data");
+        assert!(matches!(cache.lookup(&stale), Lookup::Fresh(_)));
+    }
+
+    #[test]
+    fn keeps_responses_apart_by_vary() {
+        let cache = cache(1024);
+        let english = get("/page", &[("Accept-Language", "en")]);
+        let portuguese = get("/page", &[("Accept-Language", "pt")]);
+        let vary = head("Cache-Control: max-age=60\r\nVary: Accept-Language\r\n");
+
+        assert!(store(&cache, &english, vary.clone(), b"hello"));
+        assert!(matches!(cache.lookup(&portuguese), Lookup::Miss));
+        assert!(store(&cache, &portuguese, vary, b"ola!"));
+
+        assert!(matches!(cache.lookup(&english), Lookup::Fresh(cached) if cached.body == b"hello"));
+        assert!(matches!(cache.lookup(&portuguese), Lookup::Fresh(cached) if cached.body == b"ola!"));
+        assert_eq!(cache.purge(Some("/page")), 2);
+    }
+
+    #[test]
+    fn evicts_the_least_recently_used() {
+        let response = head("Cache-Control: max-age=60\r\n");
+        let cache = cache(2 * (response.len() + 4) + 1);
+        let (a, b, c) = (get("/a", &[]), get("/b", &[]), get("/c", &[]));
+
+        assert!(store(&cache, &a, response.clone(), b"aaaa"));
+        assert!(store(&cache, &b, response.clone(), b"bbbb"));
+        assert!(matches!(cache.lookup(&a), Lookup::Fresh(_)));
+        assert!(store(&cache, &c, response, b"cccc"));
+
+        assert!(matches!(cache.lookup(&a), Lookup::Fresh(_)));
+        assert!(matches!(cache.lookup(&b), Lookup::Miss));
+        assert!(matches!(cache.lookup(&c), Lookup::Fresh(_)));
+    }
+
+    #[test]
+    fn stores_nothing_asked_before_a_purge() {
+        let cache = cache(1024);
+        let request = get("/a.txt", &[]);
+        let response = head("Cache-Control: max-age=60\r\n");
+        let generation = cache.generation();
+
+        cache.purge(None);
+        cache.store(&request, response, b"data".to_vec(), Duration::from_secs(60), generation);
+        assert!(matches!(cache.lookup(&request), Lookup::Miss));
+    }
+
+    #[test]
+    fn purges_what_an_upload_changes() {
+        let cache = cache(4096);
+        let response = head("Cache-Control: max-age=60\r\n");
+        for uri in ["/", "/notes.txt", "/?file=notes.txt", "/other.txt", "/?file=other.txt"] {
+            assert!(store(&cache, &get(uri, &[]), response.clone(), b"data"));
+        }
+
+        //Queries on the index are the index too, which lists the new file
+        assert_eq!(cache.purge_file("notes.txt"), 4);
+        assert!(matches!(cache.lookup(&get("/other.txt", &[])), Lookup::Fresh(_)));
+        assert!(matches!(cache.lookup(&get("/notes.txt", &[])), Lookup::Miss));
+        assert_eq!(cache.purge(None), 1);
+    }
+}
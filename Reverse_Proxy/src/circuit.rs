@@ -0,0 +1,182 @@
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// Where a circuit is
+///
+/// * `Closed` - Requests pass, along with the failures in a row so far.
+/// * `Open` - Requests fail fast until the given instant.
+/// * `HalfOpen` - A single request is probing whether the backend recovered.
+#[derive(Clone, Copy)]
+enum State {
+    Closed(u32),
+    Open(Instant),
+    HalfOpen,
+}
+
+/// Circuit breaker of a backend, which stops sending it requests after repeated failures
+///
+/// # Arguments
+/// * `state` - Where the circuit is.
+/// * `failures` - Failures in a row that open the circuit.
+/// * `cooldown` - How long the circuit stays open before a probe is let through.
+pub struct Circuit {
+    state: Mutex<State>,
+    failures: u32,
+    cooldown: Duration,
+}
+
+impl Circuit {
+    pub fn new(failures: u32, cooldown: Duration) -> Circuit {
+        Circuit { state: Mutex::new(State::Closed(0)), failures, cooldown }
+    }
+
+    /// Locks the state, which is always whole since every change is a single assignment
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// How long the circuit stays open before a probe is let through
+    pub fn cooldown(&self) -> Duration {
+        self.cooldown
+    }
+
+    /// Whether a request could pass right now, without taking the probe
+    pub fn allows(&self) -> bool {
+        match *self.state() {
+            State::Closed(_) => true,
+            State::Open(until) => Instant::now() >= until,
+            State::HalfOpen => false
+        }
+    }
+
+    /// Lets a request pass, the first one after the cooldown becomes the probe
+    ///
+    /// ## Returns
+    /// Whether the request may be sent to the backend
+    pub fn admit(&self) -> bool {
+        let mut state = self.state();
+        match *state {
+            State::Closed(_) => true,
+            State::Open(until) if Instant::now() >= until => {
+                *state = State::HalfOpen;
+                true
+            },
+            _ => false
+        }
+    }
+
+    /// Records a request the backend answered, which closes the circuit
+    ///
+    /// ## Returns
+    /// Whether the circuit was not closed before
+    pub fn success(&self) -> bool {
+        let mut state = self.state();
+        let recovered = !matches!(*state, State::Closed(_));
+        *state = State::Closed(0);
+        recovered
+    }
+
+    /// Records a request the backend failed
+    ///
+    /// ## Returns
+    /// Whether the circuit opened because of it
+    pub fn failure(&self) -> bool {
+        let mut state = self.state();
+        match *state {
+            State::Closed(failed) if failed + 1 < self.failures => {
+                *state = State::Closed(failed + 1);
+                false
+            },
+            //Requests sent before it opened only push the cooldown further
+            State::Open(_) => {
+                *state = State::Open(Instant::now() + self.cooldown);
+                false
+            },
+            _ => {
+                *state = State::Open(Instant::now() + self.cooldown);
+                true
+            }
+        }
+    }
+
+    /// Gives the probe back when its request said nothing about the backend, so the next request probes
+    pub fn release(&self) {
+        let mut state = self.state();
+        if let State::HalfOpen = *state {
+            *state = State::Open(Instant::now());
+        }
+    }
+
+    /// Name of where the circuit is, as shown by ```/backends```
+    pub fn name(&self) -> &'static str {
+        match *self.state() {
+            State::Closed(_) => "closed",
+            State::Open(until) if Instant::now() >= until => "half-open",
+            State::Open(_) => "open",
+            State::HalfOpen => "half-open"
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opens_after_failures_in_a_row() {
+        let circuit = Circuit::new(3, Duration::from_secs(60));
+
+        assert!(!circuit.failure());
+        assert!(!circuit.failure());
+        assert!(circuit.allows());
+        assert!(circuit.failure());
+        assert_eq!(circuit.name(), "open");
+        assert!(!circuit.allows());
+        assert!(!circuit.admit());
+        assert!(!circuit.failure());
+    }
+
+    #[test]
+    fn success_resets_the_failures() {
+        let circuit = Circuit::new(2, Duration::from_secs(60));
+
+        assert!(!circuit.failure());
+        assert!(!circuit.success());
+        assert!(!circuit.failure());
+        assert_eq!(circuit.name(), "closed");
+    }
+
+    #[test]
+    fn lets_a_single_probe_through_after_the_cooldown() {
+        let circuit = Circuit::new(1, Duration::ZERO);
+
+        assert!(circuit.failure());
+        assert_eq!(circuit.name(), "half-open");
+        assert!(circuit.admit());
+        assert!(!circuit.admit());
+        assert!(!circuit.allows());
+        assert!(circuit.success());
+        assert_eq!(circuit.name(), "closed");
+    }
+
+    #[test]
+    fn a_failed_probe_opens_again() {
+        let circuit = Circuit::new(1, Duration::from_millis(20));
+
+        assert!(circuit.failure());
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(circuit.admit());
+        assert!(circuit.failure());
+        assert_eq!(circuit.name(), "open");
+    }
+
+    #[test]
+    fn a_released_probe_is_taken_by_the_next_request() {
+        let circuit = Circuit::new(1, Duration::ZERO);
+
+        assert!(circuit.failure());
+        assert!(circuit.admit());
+        circuit.release();
+        assert!(circuit.admit());
+    }
+}
@@ -0,0 +1,308 @@
+use std::fs;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use crate::report;
+use crate::balancer::{BackendEntry, Strategy};
+use crate::ip_filter::Cidr;
+
+/// Container that store proxy's settings, read from ```./proxy.conf```
+///
+/// # Arguments
+/// * `read_rate` - Read (GET) requests a client earns per second.
+/// * `read_burst` - Maximum read requests a client can make at once.
+/// * `upload_rate` - Uploads a client earns per second.
+/// * `upload_burst` - Maximum uploads a client can make at once.
+/// * `allow` - Networks allowed to use the proxy, everyone when empty.
+/// * `deny` - Networks that can never use the proxy.
+/// * `max_body_size` - Maximum size of an upload body, in bytes. Other bodies are read whole and never take more than 64 KiB.
+/// * `headers` - Security headers added to every response, as (name, value).
+/// * `route_headers` - Per-route header overrides, as (URI pattern, name, value).
+/// * `tls_enabled` - Whether clients reach the proxy through TLS.
+/// * `tls_terminators` - Networks of TLS terminators whose ```X-Forwarded-Proto: https``` tells that a client used TLS.
+/// * `hsts` - Strict-Transport-Security value, only sent to clients that used TLS, by `tls_enabled` or `tls_terminators`.
+/// * `idle_timeout` - Seconds a client may take to send the first byte of a request.
+/// * `header_timeout` - Seconds a request head may take to arrive.
+/// * `body_timeout` - Seconds a request or response body may take to arrive.
+/// * `backend_timeout` - Seconds the server may take to accept a connection and to send its response head.
+/// * `write_timeout` - Seconds a write to a client or to the server may block.
+/// * `workers` - Amount of threads that handle connections.
+/// * `queue_size` - Amount of accepted connections that may wait for a free worker.
+/// * `io_mode` - Whether connections are handled by the worker threads or by an event loop.
+/// * `max_connections` - Amount of connections the event loop holds at once.
+/// * `shutdown_timeout` - Seconds active connections may take to finish once a shutdown is asked for.
+/// * `health_interval` - Seconds between health checks of each backend.
+/// * `health_timeout` - Seconds a backend may take to answer a health check.
+/// * `health_failures` - Failed health checks in a row that eject a backend.
+/// * `backends` - Servers that requests are spread over, with their weights.
+/// * `balancing` - How requests are spread over the backends.
+/// * `registry_allow` - Networks that may add servers to the backend pool and list it.
+/// * `keep_alive_idle` - Idle connections kept open to each backend for the next requests. Disabled when 0.
+/// * `keep_alive_timeout` - Seconds an idle connection to a backend is kept open.
+/// * `client_keep_alive_timeout` - Seconds a client's connection is kept open waiting for its next request. Disabled when 0.
+/// * `cache_size` - Bytes the cached responses may take together. Disabled when 0.
+/// * `cache_max_entry` - Bytes a single cached response may take.
+/// * `retries` - Times a GET request is sent again when its backend fails before answering.
+/// * `retry_backoff` - Milliseconds the first retry waits at most, doubled on each retry and randomized.
+/// * `circuit_failures` - Failed requests in a row that open a backend's circuit.
+/// * `circuit_cooldown` - Seconds a backend's circuit stays open before a request probes it.
+pub struct Config {
+    pub read_rate: f64,
+    pub read_burst: f64,
+    pub upload_rate: f64,
+    pub upload_burst: f64,
+    pub allow: Vec<Cidr>,
+    pub deny: Vec<Cidr>,
+    pub max_body_size: u64,
+    pub headers: Vec<(String, String)>,
+    pub route_headers: Vec<(String, String, String)>,
+    pub tls_enabled: bool,
+    pub tls_terminators: Vec<Cidr>,
+    pub hsts: String,
+    pub idle_timeout: u64,
+    pub header_timeout: u64,
+    pub body_timeout: u64,
+    pub backend_timeout: u64,
+    pub write_timeout: u64,
+    pub workers: usize,
+    pub queue_size: usize,
+    pub io_mode: IoMode,
+    pub max_connections: usize,
+    pub shutdown_timeout: u64,
+    pub health_interval: u64,
+    pub health_timeout: u64,
+    pub health_failures: u32,
+    pub backends: Vec<BackendEntry>,
+    pub balancing: Strategy,
+    pub registry_allow: Vec<Cidr>,
+    pub keep_alive_idle: usize,
+    pub keep_alive_timeout: u64,
+    pub client_keep_alive_timeout: u64,
+    pub cache_size: usize,
+    pub cache_max_entry: usize,
+    pub retries: u32,
+    pub retry_backoff: u64,
+    pub circuit_failures: u32,
+    pub circuit_cooldown: u64,
+}
+
+/// How connections are handled
+///
+/// * `Threads` - Each connection takes a worker thread from the pool until it ends.
+/// * `Async` - A few threads run an event loop that waits on every connection at once.
+#[derive(Clone, Copy, PartialEq)]
+pub enum IoMode {
+    Threads,
+    Async,
+}
+
+impl FromStr for IoMode {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<IoMode, String> {
+        match text.to_lowercase().as_str() {
+            "threads" => Ok(IoMode::Threads),
+            "async" => Ok(IoMode::Async),
+            _ => Err(format!("Unknown io_mode ({})", text))
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            read_rate: 5.0,
+            read_burst: 20.0,
+            upload_rate: 0.2,
+            upload_burst: 3.0,
+            allow: Vec::new(),
+            deny: Vec::new(),
+            max_body_size: 2 * 1024 * 1024,
+            headers: vec![
+                ("Content-Security-Policy".to_string(), "default-src 'self'; style-src 'self' 'unsafe-inline'; object-src 'none'; frame-ancestors 'none'; base-uri 'self'; form-action 'self'".to_string()),
+                ("X-Content-Type-Options".to_string(), "nosniff".to_string()),
+                ("X-Frame-Options".to_string(), "DENY".to_string()),
+                ("Referrer-Policy".to_string(), "no-referrer".to_string()),
+            ],
+            route_headers: Vec::new(),
+            tls_enabled: false,
+            tls_terminators: Vec::new(),
+            hsts: "max-age=31536000; includeSubDomains".to_string(),
+            idle_timeout: 15,
+            header_timeout: 10,
+            body_timeout: 30,
+            backend_timeout: 10,
+            write_timeout: 30,
+            workers: 32,
+            queue_size: 128,
+            io_mode: IoMode::Threads,
+            max_connections: 10000,
+            shutdown_timeout: 30,
+            health_interval: 10,
+            health_timeout: 2,
+            health_failures: 2,
+            backends: vec![BackendEntry { address: SocketAddr::from(([127, 0, 0, 1], 1445)), weight: 1 }],
+            balancing: Strategy::RoundRobin,
+            registry_allow: ["127.0.0.0/8", "::1/128"].iter().filter_map(|network| network.parse().ok()).collect(),
+            keep_alive_idle: 8,
+            keep_alive_timeout: 4,
+            client_keep_alive_timeout: 5,
+            cache_size: 8 * 1024 * 1024,
+            cache_max_entry: 1024 * 1024,
+            retries: 2,
+            retry_backoff: 100,
+            circuit_failures: 5,
+            circuit_cooldown: 10,
+        }
+    }
+}
+
+/// Parses a config value into its field, keeping the default one when it is invalid
+///
+/// # Arguments
+/// * `key: &str` - Config key, used to report errors.
+/// * `value: &str` - Value written in the config file.
+/// * `field: &mut T` - Field that will hold the value.
+fn set<T: FromStr>(key: &str, value: &str, field: &mut T) {
+    match value.parse() {
+        Ok(v) => *field = v,
+        Err(_) => report(format!("Invalid value ({}) for config key ({}) >>> Keeping default", value, key))
+    }
+}
+
+/// Parses a comma separated config value into a list field, skipping invalid items
+///
+/// # Arguments
+/// * `key: &str` - Config key, used to report errors.
+/// * `value: &str` - Value written in the config file.
+/// * `field: &mut Vec<T>` - Field that will hold the list.
+fn set_list<T: FromStr>(key: &str, value: &str, field: &mut Vec<T>) {
+    *field = value.split(',')
+        .map(|item| item.trim())
+        .filter(|item| !item.is_empty())
+        .filter_map(|item| match item.parse() {
+            Ok(v) => Some(v),
+            Err(_) => {
+                report(format!("Invalid item ({}) for config key ({}) >>> Ignoring", item, key));
+                None
+            }
+        })
+        .collect();
+}
+
+impl Config {
+    /// Read settings from a ```key = value``` file. Missing keys keep their default values.
+    ///
+    /// # Arguments
+    /// * `path: &str` - Path of the config file.
+    pub fn load(path: &str) -> Config {
+        let mut config = Config::default();
+        let Ok(contents) = fs::read_to_string(path) else {
+            report(format!("Config file ({}) not found >>> Using defaults", path));
+            return config;
+        };
+
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                report(format!("Malformed config line ({}) >>> Ignoring", line));
+                continue;
+            };
+            let (key, value) = (key.trim(), value.trim());
+
+            match key {
+                "read_rate" => set(key, value, &mut config.read_rate),
+                "read_burst" => set(key, value, &mut config.read_burst),
+                "upload_rate" => set(key, value, &mut config.upload_rate),
+                "upload_burst" => set(key, value, &mut config.upload_burst),
+                "allow" => set_list(key, value, &mut config.allow),
+                "deny" => set_list(key, value, &mut config.deny),
+                "max_body_size" => set(key, value, &mut config.max_body_size),
+                "tls_enabled" => set(key, value, &mut config.tls_enabled),
+                "tls_terminators" => set_list(key, value, &mut config.tls_terminators),
+                "hsts" => config.hsts = value.to_string(),
+                "idle_timeout" => set(key, value, &mut config.idle_timeout),
+                "header_timeout" => set(key, value, &mut config.header_timeout),
+                "body_timeout" => set(key, value, &mut config.body_timeout),
+                "backend_timeout" => set(key, value, &mut config.backend_timeout),
+                "write_timeout" => set(key, value, &mut config.write_timeout),
+                "workers" => set(key, value, &mut config.workers),
+                "queue_size" => set(key, value, &mut config.queue_size),
+                "io_mode" => set(key, value, &mut config.io_mode),
+                "max_connections" => set(key, value, &mut config.max_connections),
+                "shutdown_timeout" => set(key, value, &mut config.shutdown_timeout),
+                "health_interval" => set(key, value, &mut config.health_interval),
+                "health_timeout" => set(key, value, &mut config.health_timeout),
+                "health_failures" => set(key, value, &mut config.health_failures),
+                "backends" => set_list(key, value, &mut config.backends),
+                "balancing" => set(key, value, &mut config.balancing),
+                "registry_allow" => set_list(key, value, &mut config.registry_allow),
+                "keep_alive_idle" => set(key, value, &mut config.keep_alive_idle),
+                "keep_alive_timeout" => set(key, value, &mut config.keep_alive_timeout),
+                "client_keep_alive_timeout" => set(key, value, &mut config.client_keep_alive_timeout),
+                "cache_size" => set(key, value, &mut config.cache_size),
+                "cache_max_entry" => set(key, value, &mut config.cache_max_entry),
+                "retries" => set(key, value, &mut config.retries),
+                "retry_backoff" => set(key, value, &mut config.retry_backoff),
+                "circuit_failures" => set(key, value, &mut config.circuit_failures),
+                "circuit_cooldown" => set(key, value, &mut config.circuit_cooldown),
+                //header <name> = <value>
+                k if k.starts_with("header ") => {
+                    let name = k["header ".len()..].trim();
+                    config.headers.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+                    if !value.is_empty() {
+                        config.headers.push((name.to_string(), value.to_string()));
+                    }
+                },
+                //route <pattern> <name> = <value>
+                k if k.starts_with("route ") => match k["route ".len()..].split_whitespace().collect::<Vec<_>>()[..] {
+                    [pattern, name] => config.route_headers.push((pattern.to_string(), name.to_string(), value.to_string())),
+                    _ => report(format!("Malformed route header ({}) >>> Ignoring", k))
+                },
+                _ => report(format!("Unknown config key ({}) >>> Ignoring", key))
+            }
+        }
+
+        //Sockets do not accept a zero timeout
+        for timeout in [&mut config.idle_timeout, &mut config.header_timeout, &mut config.body_timeout, &mut config.backend_timeout, &mut config.write_timeout, &mut config.health_timeout] {
+            if *timeout == 0 {
+                report("Timeouts must be at least 1 second >>> Using 1 second".to_string());
+                *timeout = 1;
+            }
+        }
+
+        if config.health_interval == 0 {
+            report("Health check interval must be at least 1 second >>> Using 1 second".to_string());
+            config.health_interval = 1;
+        }
+
+        if config.health_failures == 0 {
+            report("A backend must fail at least 1 health check to be ejected >>> Using 1 check".to_string());
+            config.health_failures = 1;
+        }
+
+        if config.circuit_failures == 0 {
+            report("A circuit must take at least 1 failure to open >>> Using 1 failure".to_string());
+            config.circuit_failures = 1;
+        }
+
+        if config.circuit_cooldown == 0 {
+            report("Circuit cooldown must be at least 1 second >>> Using 1 second".to_string());
+            config.circuit_cooldown = 1;
+        }
+
+        if config.backends.is_empty() {
+            report("There must be at least 1 backend >>> Using 127.0.0.1:1445".to_string());
+            config.backends = Config::default().backends;
+        }
+
+        if config.workers == 0 {
+            report("There must be at least 1 worker >>> Using 1 worker".to_string());
+            config.workers = 1;
+        }
+
+        config
+    }
+}
@@ -0,0 +1,133 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use crate::http;
+
+/// Error pages of the proxy, read once when it starts
+pub const PAGES: [&str; 9] = [
+    "./pages/400.html",
+    "./pages/403.html",
+    "./pages/408.html",
+    "./pages/413.html",
+    "./pages/429.html",
+    "./pages/431.html",
+    "./pages/502.html",
+    "./pages/503.html",
+    "./pages/504.html",
+];
+
+/// Everything that makes the proxy give up a request
+///
+/// Each variant carries the context that is reported along with it:
+/// * `BadRequest` - The request could not be understood or forwarded (400).
+/// * `Forbidden` - The client is not allowed by the IP filter (403).
+/// * `ClientTimeout` - The client was too slow (408, nothing is sent if it never sent a byte).
+/// * `PayloadTooLarge` - The body is bigger than the allowed size (413).
+/// * `TooManyRequests` - The client exceeded its rate limit and may retry after `retry_after` seconds (429).
+/// * `HeaderTooLarge` - The request head is longer than the proxy reads (431).
+/// * `BadGateway` - The server could not be reached or did not answer properly (502).
+/// * `Unavailable` - No backend has registered a key and passes its health checks (503).
+/// * `GatewayTimeout` - The server took longer than `backend_timeout` to accept or to start answering (504).
+/// * `BackendTimeout` - The server was too slow after its answer had started, the connection is closed.
+/// * `BackendFailed` - The connection with the server failed after its answer had started, the connection is closed.
+/// * `Connection` - The connection with the client failed, so no answer can be sent.
+#[derive(Debug)]
+pub enum ProxyError {
+    BadRequest(String),
+    Forbidden(String),
+    ClientTimeout(http::Timeout),
+    PayloadTooLarge(String),
+    TooManyRequests { context: String, retry_after: u64 },
+    HeaderTooLarge(String),
+    BadGateway(String),
+    Unavailable(String),
+    GatewayTimeout(String),
+    BackendTimeout(String),
+    BackendFailed(String),
+    Connection(io::Error),
+}
+
+impl ProxyError {
+    /// Status line of the response, None when no response can or should be sent
+    fn status(&self) -> Option<&'static str> {
+        match self {
+            ProxyError::BadRequest(_) => Some("400 BAD REQUEST"),
+            ProxyError::Forbidden(_) => Some("403 FORBIDDEN"),
+            ProxyError::ClientTimeout(http::Timeout::Idle) => None,
+            ProxyError::ClientTimeout(_) => Some("408 REQUEST TIMEOUT"),
+            ProxyError::PayloadTooLarge(_) => Some("413 PAYLOAD TOO LARGE"),
+            ProxyError::TooManyRequests { .. } => Some("429 TOO MANY REQUESTS"),
+            ProxyError::HeaderTooLarge(_) => Some("431 REQUEST HEADER FIELDS TOO LARGE"),
+            ProxyError::BadGateway(_) => Some("502 BAD GATEWAY"),
+            ProxyError::Unavailable(_) => Some("503 SERVICE UNAVAIBLE"),
+            ProxyError::GatewayTimeout(_) => Some("504 GATEWAY TIMEOUT"),
+            ProxyError::BackendTimeout(_) | ProxyError::BackendFailed(_) | ProxyError::Connection(_) => None
+        }
+    }
+
+    /// Status code sent to the client, like ```502```, or ```none``` when there is no response
+    pub fn code(&self) -> &'static str {
+        self.status().and_then(|status| status.split_whitespace().next()).unwrap_or("none")
+    }
+
+    /// Builds the response that tells the client about the error
+    ///
+    /// # Arguments
+    /// * `pages: &HashMap<&str, String>` - Error pages read when the proxy started, by path.
+    ///
+    /// ## Returns
+    /// The whole response, head and body
+    /// None if the client must not be answered
+    pub fn response(&self, pages: &HashMap<&str, String>) -> Option<String> {
+        let status = self.status()?;
+        let (page, extra_headers) = match self {
+            ProxyError::BadRequest(_) => ("./pages/400.html", String::new()),
+            ProxyError::ClientTimeout(_) => ("./pages/408.html", String::new()),
+            ProxyError::PayloadTooLarge(_) => ("./pages/413.html", String::new()),
+            ProxyError::TooManyRequests { retry_after, .. } => ("./pages/429.html", format!("Retry-After: {}\r\n", retry_after)),
+            ProxyError::HeaderTooLarge(_) => ("./pages/431.html", String::new()),
+            ProxyError::BadGateway(_) => ("./pages/502.html", String::new()),
+            ProxyError::Unavailable(_) => ("./pages/503.html", String::new()),
+            ProxyError::GatewayTimeout(_) => ("./pages/504.html", String::new()),
+            _ => ("./pages/403.html", String::new())
+        };
+
+        Some(crate::error_page(status, pages.get(page).map(String::as_str), &extra_headers))
+    }
+}
+
+impl fmt::Display for ProxyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProxyError::BadRequest(context)
+            | ProxyError::Forbidden(context)
+            | ProxyError::PayloadTooLarge(context)
+            | ProxyError::HeaderTooLarge(context)
+            | ProxyError::TooManyRequests { context, .. }
+            | ProxyError::BadGateway(context)
+            | ProxyError::BackendFailed(context)
+            | ProxyError::Unavailable(context) => write!(f, "{}", context),
+            ProxyError::ClientTimeout(timeout) => write!(f, "Client timed out ({:?})", timeout),
+            ProxyError::GatewayTimeout(cause) | ProxyError::BackendTimeout(cause) => write!(f, "Server timed out ({})", cause),
+            ProxyError::Connection(e) => write!(f, "Connection with client failed: {}", e)
+        }
+    }
+}
+
+impl From<io::Error> for ProxyError {
+    fn from(e: io::Error) -> Self {
+        ProxyError::Connection(e)
+    }
+}
+
+impl From<http::Error> for ProxyError {
+    fn from(e: http::Error) -> Self {
+        match e {
+            http::Error::Timeout(timeout) => ProxyError::ClientTimeout(timeout),
+            http::Error::Io(e) => ProxyError::Connection(e),
+            http::Error::Malformed(context) => ProxyError::BadRequest(context),
+            http::Error::TooLarge(context) => ProxyError::PayloadTooLarge(context),
+            http::Error::HeadTooLarge(context) => ProxyError::HeaderTooLarge(context)
+        }
+    }
+}
@@ -0,0 +1,160 @@
+use std::io;
+use std::net::TcpListener;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tokio::runtime::Runtime;
+use crate::error::ProxyError;
+use shared::shutdown::Shutdown;
+use crate::{error_response, proxy_handler, report, write_all, ProxyState};
+
+thread_local! {
+    /// Event loop of a worker thread, which only ever waits on the connection its worker holds
+    static WORKER_RUNTIME: io::Result<Runtime> = tokio::runtime::Builder::new_current_thread()
+        .enable_io()
+        .enable_time()
+        .build();
+}
+
+/// Place taken by an open connection, given back when it is dropped
+///
+/// # Arguments
+/// * `0` - Counter of open connections.
+struct Slot(Arc<AtomicUsize>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Runs the proxy on an event loop, where a few threads wait on every connection at once
+///
+/// # Arguments
+/// * `listener: TcpListener` - Listener already bound to the proxy's address.
+/// * `state: Arc<ProxyState>` - Proxy's state, shared by every connection.
+/// * `shutdown: Arc<Shutdown>` - Tells when to stop accepting connections.
+///
+/// Connections go through the same pipeline as with the worker threads, only the waiting is different.
+///
+/// ## Returns
+/// The amount of connections that were still open when the shutdown deadline passed
+pub fn run(listener: TcpListener, state: Arc<ProxyState>, shutdown: Arc<Shutdown>) -> io::Result<usize> {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(state.config.workers)
+        .enable_io()
+        .enable_time()
+        .build()?;
+
+    let deadline = Duration::from_secs(state.config.shutdown_timeout);
+    let open = runtime.block_on(accept_loop(listener, state, shutdown, deadline))?;
+    //Connections still open after the deadline are dropped along with the runtime
+    runtime.shutdown_timeout(Duration::from_secs(1));
+
+    Ok(open)
+}
+
+/// Accepts connections and gives each one a task, until a shutdown is asked for
+///
+/// # Arguments
+/// * `listener: TcpListener` - Listener already bound to the proxy's address.
+/// * `state: Arc<ProxyState>` - Proxy's state, shared by every connection.
+/// * `shutdown: Arc<Shutdown>` - Tells when to stop accepting connections.
+/// * `deadline: Duration` - How long open connections may take to finish after a shutdown.
+///
+/// ## Returns
+/// The amount of connections that were still open when the deadline passed
+async fn accept_loop(listener: TcpListener, state: Arc<ProxyState>, shutdown: Arc<Shutdown>, deadline: Duration) -> io::Result<usize> {
+    listener.set_nonblocking(true)?;
+    let listener = tokio::net::TcpListener::from_std(listener)?;
+    let open = Arc::new(AtomicUsize::new(0));
+
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = shutdown.wait() => break
+        };
+        if shutdown.is_requested() {
+            break;
+        }
+
+        //A connection that failed before being accepted does not stop the proxy
+        let (stream, address) = match accepted {
+            Ok(connection) => connection,
+            Err(e) => {
+                report(format!("Could not accept a connection: {}", e));
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let client_ip = address.ip();
+        let state = Arc::clone(&state);
+
+        //Connections beyond the limit are refused right away instead of piling up
+        if open.fetch_add(1, Ordering::Relaxed) >= state.config.max_connections {
+            open.fetch_sub(1, Ordering::Relaxed);
+            tokio::spawn(async move {
+                let mut stream = stream;
+                let error = ProxyError::Unavailable(format!("Client ({}) was refused, the connection limit was reached", client_ip));
+                if let Some(response) = error_response(&state, &format!("Client ({})", client_ip), &error) {
+                    let _ = write_all(&mut stream, response.as_bytes(), Duration::from_secs(1)).await;
+                }
+            });
+            continue;
+        }
+        let slot = Slot(Arc::clone(&open));
+        let shutdown = Arc::clone(&shutdown);
+
+        tokio::spawn(async move {
+            let _slot = slot;
+            proxy_handler(stream, state, client_ip, shutdown).await;
+        });
+    }
+
+    //New connections are refused from now on, the open ones get until the deadline
+    drop(listener);
+    report("Shutting down >>> Waiting for open connections to finish".to_string());
+
+    let end = Instant::now() + deadline;
+    while open.load(Ordering::Relaxed) > 0 && Instant::now() < end {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+
+    Ok(open.load(Ordering::Relaxed))
+}
+
+/// Handles a connection on one of the worker threads, which waits on it alone
+///
+/// # Arguments
+/// * `stream: std::net::TcpStream` - Stream that holds connection with client, as it was accepted.
+/// * `state: Arc<ProxyState>` - Proxy's state, shared by every worker.
+/// * `shutdown: Arc<Shutdown>` - Tells when to stop waiting for the next request of the client.
+///
+/// The connection goes through the same pipeline as on the event loop, run on an event loop of the worker's own.
+pub fn serve(stream: std::net::TcpStream, state: Arc<ProxyState>, shutdown: Arc<Shutdown>) {
+    let client_ip = match stream.peer_addr() {
+        Ok(address) => address.ip(),
+        Err(e) => {
+            report(format!("Could not read the client's address: {} >>> Closing connection", e));
+            return;
+        }
+    };
+
+    WORKER_RUNTIME.with(|runtime| {
+        let runtime = match runtime {
+            Ok(runtime) => runtime,
+            Err(e) => {
+                report(format!("Could not start the worker's event loop: {} >>> Closing connection", e));
+                return;
+            }
+        };
+        runtime.block_on(async {
+            //The stream is registered with the worker's event loop, which needs it non-blocking
+            match stream.set_nonblocking(true).and_then(|_| TcpStream::from_std(stream)) {
+                Ok(stream) => proxy_handler(stream, state, client_ip, shutdown).await,
+                Err(e) => report(format!("Could not wait on the client's connection: {} >>> Closing connection", e))
+            }
+        });
+    });
+}
@@ -0,0 +1,159 @@
+use std::net::IpAddr;
+use shared::pattern::wildcard_match;
+use crate::config::Config;
+use crate::ip_filter::Cidr;
+
+/// Security headers added by the proxy to every response sent to clients
+///
+/// # Arguments
+/// * `defaults` - Headers sent with every response.
+/// * `routes` - Overrides as (URI pattern, header name, value). An empty value removes the header.
+/// * `hsts` - Strict-Transport-Security value, only sent when clients use TLS.
+/// * `tls_enabled` - Whether every client reaches the proxy through TLS.
+/// * `terminators` - Networks of the TLS terminators trusted to tell, by ```X-Forwarded-Proto```, that a client used TLS.
+pub struct SecurityHeaders {
+    defaults: Vec<(String, String)>,
+    routes: Vec<(String, String, String)>,
+    hsts: Option<String>,
+    tls_enabled: bool,
+    terminators: Vec<Cidr>,
+}
+
+impl SecurityHeaders {
+    pub fn new(config: &Config) -> SecurityHeaders {
+        SecurityHeaders {
+            defaults: config.headers.clone(),
+            routes: config.route_headers.clone(),
+            hsts: if config.hsts.is_empty() { None } else { Some(config.hsts.clone()) },
+            tls_enabled: config.tls_enabled,
+            terminators: config.tls_terminators.clone(),
+        }
+    }
+
+    /// Whether a trusted TLS terminator tells that a client reached it through TLS
+    ///
+    /// # Arguments
+    /// * `client_ip: IpAddr` - IP the request came from, the terminator's when there is one.
+    /// * `forwarded_proto: Option<&str>` - ```X-Forwarded-Proto``` of the request.
+    ///
+    /// The header is ignored from any other network, as clients could send it themselves.
+    pub fn forwarded_tls(&self, client_ip: IpAddr, forwarded_proto: Option<&str>) -> bool {
+        self.terminators.iter().any(|network| network.contains(client_ip))
+            && forwarded_proto.is_some_and(|proto| proto.trim().eq_ignore_ascii_case("https"))
+    }
+
+    /// Headers that apply to a request URI, with route overrides already merged, the ones with an empty value are removed
+    ///
+    /// # Arguments
+    /// * `uri: &str` - Request's path.
+    /// * `forwarded_tls: bool` - Whether a trusted TLS terminator told that the client used TLS.
+    fn for_uri(&self, uri: &str, forwarded_tls: bool) -> Vec<(String, String)> {
+        let mut headers = self.defaults.clone();
+        if let Some(hsts) = self.hsts.as_ref().filter(|_| self.tls_enabled || forwarded_tls) {
+            headers.push(("Strict-Transport-Security".to_string(), hsts.clone()));
+        }
+
+        let path = uri.split('?').next().unwrap_or(uri);
+        for (_, name, value) in self.routes.iter().filter(|(pattern, _, _)| wildcard_match(pattern, path)) {
+            headers.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+            //An empty value stays in the list, so that the server's own header is removed as well
+            headers.push((name.clone(), value.clone()));
+        }
+
+        headers
+    }
+
+    /// Adds the security headers to a response head, replacing or removing the ones the server already sent
+    ///
+    /// # Arguments
+    /// * `head: &str` - Response status line and headers, ending with the blank line.
+    /// * `uri: &str` - Path of the request that is being answered.
+    /// * `forwarded_tls: bool` - Whether a trusted TLS terminator told that the client used TLS, see [`SecurityHeaders::forwarded_tls`].
+    pub fn inject(&self, head: &str, uri: &str, forwarded_tls: bool) -> String {
+        let headers = self.for_uri(uri, forwarded_tls);
+        let mut lines = head.trim_end_matches("\r\n").split("\r\n");
+        let mut output = format!("{}\r\n", lines.next().unwrap_or_default());
+
+        for line in lines {
+            let name = line.split_once(':').map(|(n, _)| n.trim()).unwrap_or(line);
+            if !headers.iter().any(|(n, _)| n.eq_ignore_ascii_case(name)) {
+                output.push_str(line);
+                output.push_str("\r\n");
+            }
+        }
+        for (name, value) in headers.into_iter().filter(|(_, value)| !value.is_empty()) {
+            output.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        output.push_str("\r\n");
+
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn security_headers(config: Config) -> SecurityHeaders {
+        SecurityHeaders::new(&Config {
+            headers: vec![("X-Frame-Options".to_string(), "DENY".to_string()), ("Content-Security-Policy".to_string(), "default-src 'self'".to_string())],
+            ..config
+        })
+    }
+
+    fn route(pattern: &str, name: &str, value: &str) -> (String, String, String) {
+        (pattern.to_string(), name.to_string(), value.to_string())
+    }
+
+    #[test]
+    fn replaces_the_headers_the_server_sent() {
+        let headers = security_headers(Config::default());
+        let head = headers.inject("HTTP/1.1 200 OK\r\nx-frame-options: SAMEORIGIN\r\nContent-Length: 2\r\n\r\n", "/", false);
+        assert_eq!(head, "HTTP/1.1 200 OK\r\nContent-Length: 2\r\nX-Frame-Options: DENY\r\nContent-Security-Policy: default-src 'self'\r\n\r\n");
+    }
+
+    #[test]
+    fn overrides_headers_on_matching_routes() {
+        let headers = security_headers(Config {
+            route_headers: vec![route("/public/*", "X-Frame-Options", "SAMEORIGIN"), route("/public/*", "Cache-Control", "no-store")],
+            ..Config::default()
+        });
+
+        let public = headers.for_uri("/public/notes.txt?download=1", false);
+        assert!(public.contains(&("X-Frame-Options".to_string(), "SAMEORIGIN".to_string())));
+        assert!(public.contains(&("Cache-Control".to_string(), "no-store".to_string())));
+        assert!(!public.iter().any(|(name, value)| name == "X-Frame-Options" && value == "DENY"));
+        assert_eq!(headers.for_uri("/private/notes.txt", false), headers.for_uri("/", false));
+    }
+
+    #[test]
+    fn an_empty_value_removes_the_header() {
+        let headers = security_headers(Config { route_headers: vec![route("/style.css", "content-security-policy", "")], ..Config::default() });
+
+        let head = headers.inject("HTTP/1.1 200 OK\r\nContent-Security-Policy: none\r\n\r\n", "/style.css", false);
+        assert_eq!(head, "HTTP/1.1 200 OK\r\nX-Frame-Options: DENY\r\n\r\n");
+        assert!(headers.for_uri("/index.html", false).iter().any(|(name, _)| name == "Content-Security-Policy"));
+    }
+
+    #[test]
+    fn sends_hsts_only_to_clients_that_used_tls() {
+        let has_hsts = |headers: &SecurityHeaders, forwarded_tls| headers.for_uri("/", forwarded_tls).iter().any(|(name, _)| name == "Strict-Transport-Security");
+
+        let plain = security_headers(Config::default());
+        assert!(!has_hsts(&plain, false));
+        assert!(has_hsts(&plain, true));
+        assert!(has_hsts(&security_headers(Config { tls_enabled: true, ..Config::default() }), false));
+        assert!(!has_hsts(&security_headers(Config { tls_enabled: true, hsts: String::new(), ..Config::default() }), true));
+    }
+
+    #[test]
+    fn trusts_forwarded_proto_only_from_terminators() {
+        let headers = security_headers(Config { tls_terminators: vec!["10.0.0.0/8".parse().unwrap()], ..Config::default() });
+        let terminator: IpAddr = "10.0.0.5".parse().unwrap();
+
+        assert!(headers.forwarded_tls(terminator, Some("HTTPS")));
+        assert!(!headers.forwarded_tls(terminator, Some("http")));
+        assert!(!headers.forwarded_tls(terminator, None));
+        assert!(!headers.forwarded_tls("192.168.0.5".parse().unwrap(), Some("https")));
+    }
+}
@@ -0,0 +1,131 @@
+use std::collections::{HashMap, HashSet};
+use std::io::prelude::*;
+use std::net::{SocketAddr, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use arc_swap::ArcSwap;
+use shared::shutdown::Shutdown;
+use crate::{http, report, server_request, ProxyState, Request};
+
+/// Health of the backends, as seen by the active health checks
+///
+/// Requests read it without a lock, only the checker thread and key changes write it.
+///
+/// # Arguments
+/// * `ejected` - Backends that failed `health_failures` checks in a row, which get no requests.
+#[derive(Default)]
+pub struct Health {
+    ejected: ArcSwap<HashSet<SocketAddr>>,
+}
+
+impl Health {
+    /// Whether a backend may get requests
+    ///
+    /// # Arguments
+    /// * `backend: &SocketAddr` - Address the backend listens at.
+    pub fn is_healthy(&self, backend: &SocketAddr) -> bool {
+        !self.ejected.load().contains(backend)
+    }
+
+    /// Stops sending requests to a backend
+    ///
+    /// # Arguments
+    /// * `backend: SocketAddr` - Address the backend listens at.
+    fn eject(&self, backend: SocketAddr) {
+        self.ejected.rcu(|ejected| {
+            let mut ejected = HashSet::clone(ejected);
+            ejected.insert(backend);
+            ejected
+        });
+    }
+
+    /// Sends requests to a backend again, like when it passes a check or registers a new key
+    ///
+    /// # Arguments
+    /// * `backend: &SocketAddr` - Address the backend listens at.
+    pub fn restore(&self, backend: &SocketAddr) {
+        self.ejected.rcu(|ejected| {
+            let mut ejected = HashSet::clone(ejected);
+            ejected.remove(backend);
+            ejected
+        });
+    }
+}
+
+/// Starts the thread that checks every registered backend each `health_interval` seconds
+///
+/// # Arguments
+/// * `state: Arc<ProxyState>` - Proxy's state, which holds the keys, the health and the settings.
+/// * `shutdown: Arc<Shutdown>` - Stops the checks.
+pub fn start(state: Arc<ProxyState>, shutdown: Arc<Shutdown>) {
+    thread::spawn(move || {
+        let interval = Duration::from_secs(state.config.health_interval);
+        let timeout = Duration::from_secs(state.config.health_timeout);
+        //Failed checks in a row of each backend
+        let mut failures: HashMap<SocketAddr, u32> = HashMap::new();
+
+        while !shutdown.is_requested() {
+            thread::sleep(interval);
+
+            let backends = state.secrets.backends();
+            failures.retain(|backend, _| backends.iter().any(|(registered, _)| registered == backend));
+
+            for (backend, key) in backends {
+                match check(backend, &key, timeout) {
+                    Ok(()) => {
+                        if failures.remove(&backend).is_some_and(|failed| failed >= state.config.health_failures) {
+                            report(format!("Server ({}) passed its health check >>> Sending requests again", backend));
+                            state.health.restore(&backend);
+                        }
+                    },
+                    Err(e) => {
+                        let failed = failures.entry(backend).or_insert(0);
+                        *failed += 1;
+                        if *failed == state.config.health_failures {
+                            report(format!("Server ({}) failed {} health checks ({}) >>> Ejecting it", backend, failed, e));
+                            state.health.eject(backend);
+                        }
+                    }
+                }
+            }
+        }
+    });
+}
+
+/// Sends a signed health check to a backend
+///
+/// # Arguments
+/// * `backend: SocketAddr` - Address the backend listens at.
+/// * `key: &str` - Backend's secret-key, which signs the check.
+/// * `timeout: Duration` - How long connecting and each step of the answer may take.
+///
+/// ## Returns
+/// Nothing if the backend answered 200
+/// What went wrong otherwise
+fn check(backend: SocketAddr, key: &str, timeout: Duration) -> Result<(), String> {
+    let request = Request {
+        signature: key.to_string(),
+        method: "GET".to_string(),
+        uri: "/healthz".to_string(),
+        version: "HTTP/1.1".to_string(),
+        host: backend.to_string(),
+        body: String::new(),
+        headers: Vec::new(),
+        upload: None,
+        keep_alive: false,
+        forwarded_tls: false
+    };
+    let request = server_request(&request).map_err(|e| e.to_string())?;
+
+    let mut stream = TcpStream::connect_timeout(&backend, timeout).map_err(|e| format!("could not connect: {}", e))?;
+    stream.set_write_timeout(Some(timeout)).map_err(|e| format!("could not set timeouts: {}", e))?;
+    stream.write_all(request.as_bytes()).map_err(|e| format!("could not send the check: {}", e))?;
+
+    match http::read_head(&mut stream, timeout, timeout) {
+        Ok((head, _)) if head.starts_with("HTTP/1.1 200") => Ok(()),
+        Ok((head, _)) if head.is_empty() => Err("no answer".to_string()),
+        Ok((head, _)) => Err(format!("answered {}", head.lines().next().unwrap_or_default())),
+        Err(e) => Err(e.to_string())
+    }
+}
@@ -0,0 +1,50 @@
+use std::io;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+pub use shared::http::{is_timeout, read_head, read_head_async, Error, Timeout};
+
+/// Maximum size of a body that is read whole instead of streamed, like a secret-key, in bytes
+pub const MAX_READ_BODY: u64 = 64 * 1024;
+
+/// Reads the rest of a request body, waiting on the event loop instead of blocking a thread
+///
+/// # Arguments
+/// * `stream: &mut S` - Non-blocking stream that holds connection with client.
+/// * `mut body: Vec<u8>` - Body bytes that were already read along with the head.
+/// * `size: u64` - Body size told by the Content-Length header.
+/// * `body_timeout: Duration` - How long the whole body may take to arrive.
+pub async fn read_body_async<S: AsyncRead + Unpin>(stream: &mut S, mut body: Vec<u8>, size: u64, body_timeout: Duration) -> Result<String, Error> {
+    let deadline = tokio::time::Instant::now() + body_timeout;
+    let mut buffer = [0; 4096];
+
+    while (body.len() as u64) < size {
+        let wanted = buffer.len().min((size - body.len() as u64) as usize);
+        match tokio::time::timeout_at(deadline, stream.read(&mut buffer[..wanted])).await {
+            Ok(Ok(0)) => break,
+            Ok(Ok(bytes_read)) => body.extend_from_slice(&buffer[..bytes_read]),
+            Ok(Err(e)) => return Err(Error::Io(e)),
+            Err(_) => return Err(Error::Timeout(Timeout::Body))
+        }
+    }
+    body.truncate(size as usize);
+
+    Ok(String::from_utf8_lossy(&body).to_string())
+}
+
+/// Reads the next bytes of a body that is passed on as it arrives, waiting on the event loop instead of blocking a thread
+///
+/// # Arguments
+/// * `stream: &mut S` - Non-blocking stream that holds connection with client.
+/// * `buffer: &mut [u8]` - Where the bytes are placed, no larger than what is left of the body.
+/// * `body_timeout: Duration` - How long to wait for the next bytes.
+///
+/// ## Returns
+/// The amount of bytes read, an error if the peer closed the connection before the body ended
+pub async fn read_some_async<S: AsyncRead + Unpin>(stream: &mut S, buffer: &mut [u8], body_timeout: Duration) -> Result<usize, Error> {
+    match tokio::time::timeout(body_timeout, stream.read(buffer)).await {
+        Ok(Ok(0)) => Err(Error::Io(io::ErrorKind::UnexpectedEof.into())),
+        Ok(Ok(bytes_read)) => Ok(bytes_read),
+        Ok(Err(e)) => Err(Error::Io(e)),
+        Err(_) => Err(Error::Timeout(Timeout::Body))
+    }
+}
@@ -0,0 +1,149 @@
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// IPv4 or IPv6 network written in CIDR notation, like ```192.168.0.0/16```
+///
+/// # Arguments
+/// * `network` - Network address.
+/// * `prefix` - Amount of leading bits that must match.
+#[derive(Clone, Copy)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u32,
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    /// A bare address is parsed as a network with a single host
+    fn from_str(text: &str) -> Result<Cidr, String> {
+        let (address, prefix) = match text.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (text, None)
+        };
+        let network: IpAddr = address.trim().parse().map_err(|_| format!("Invalid address ({})", address))?;
+        let max_prefix = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => p.trim().parse().map_err(|_| format!("Invalid prefix ({})", p))?,
+            None => max_prefix
+        };
+        if prefix > max_prefix {
+            return Err(format!("Prefix ({}) is too long", prefix));
+        }
+
+        Ok(Cidr { network, prefix })
+    }
+}
+
+impl Cidr {
+    /// Checks if an address is inside the network
+    ///
+    /// # Arguments
+    /// * `ip: IpAddr` - Address that will be checked.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        //IPv4 clients of a dual stack socket show up as ::ffff:a.b.c.d
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            },
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            },
+            _ => false
+        }
+    }
+}
+
+/// Decides which clients may talk to the proxy
+///
+/// A client is denied when it is inside any `deny` network, or when `allow`
+/// is not empty and the client is outside all of its networks.
+pub struct IpFilter {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+}
+
+impl IpFilter {
+    pub fn new(allow: Vec<Cidr>, deny: Vec<Cidr>) -> IpFilter {
+        IpFilter { allow, deny }
+    }
+
+    /// Checks if a client may be served
+    ///
+    /// # Arguments
+    /// * `ip: IpAddr` - Client's address.
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        if self.deny.iter().any(|cidr| cidr.contains(ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|cidr| cidr.contains(ip))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(text: &str) -> IpAddr {
+        text.parse().unwrap()
+    }
+
+    fn cidrs(texts: &[&str]) -> Vec<Cidr> {
+        texts.iter().map(|text| text.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn parses_networks() {
+        assert!("192.168.0.0/16".parse::<Cidr>().is_ok());
+        assert!("10.0.0.1".parse::<Cidr>().is_ok());
+        assert!("fd00::/8".parse::<Cidr>().is_ok());
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("fd00::/129".parse::<Cidr>().is_err());
+        assert!("10.0.0/8".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/x".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn matches_addresses_inside_the_network() {
+        let network: Cidr = "192.168.0.0/16".parse().unwrap();
+        assert!(network.contains(ip("192.168.10.20")));
+        assert!(!network.contains(ip("192.169.0.1")));
+        assert!(!network.contains(ip("fd00::1")));
+
+        let host: Cidr = "10.0.0.1".parse().unwrap();
+        assert!(host.contains(ip("10.0.0.1")));
+        assert!(!host.contains(ip("10.0.0.2")));
+
+        let everything: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(everything.contains(ip("8.8.8.8")));
+
+        let v6: Cidr = "fd00::/8".parse().unwrap();
+        assert!(v6.contains(ip("fd12:3456::1")));
+        assert!(!v6.contains(ip("fe80::1")));
+    }
+
+    #[test]
+    fn matches_mapped_ipv4_clients() {
+        let network: Cidr = "127.0.0.0/8".parse().unwrap();
+        assert!(network.contains(ip("::ffff:127.0.0.1")));
+    }
+
+    #[test]
+    fn deny_wins_over_allow() {
+        let filter = IpFilter::new(cidrs(&["10.0.0.0/8"]), cidrs(&["10.0.0.66"]));
+
+        assert!(filter.allows(ip("10.1.2.3")));
+        assert!(!filter.allows(ip("10.0.0.66")));
+        assert!(!filter.allows(ip("192.168.0.1")));
+    }
+
+    #[test]
+    fn empty_allow_lets_everyone_in() {
+        let filter = IpFilter::new(Vec::new(), cidrs(&["203.0.113.0/24"]));
+
+        assert!(filter.allows(ip("8.8.8.8")));
+        assert!(!filter.allows(ip("203.0.113.9")));
+    }
+}
@@ -0,0 +1,96 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{SocketAddr, TcpStream};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use crate::config::Config;
+
+/// Connections to the backends that stay open between requests, so a request does not wait for a new one
+///
+/// # Arguments
+/// * `idle` - Open connections that no request is using, by backend, along with when they were given back.
+/// * `max_idle` - Idle connections kept for each backend, the ones beyond it are closed.
+/// * `idle_timeout` - How long an idle connection is kept, shorter than the server keeps it.
+pub struct KeepAlive {
+    idle: Mutex<HashMap<SocketAddr, Vec<(TcpStream, Instant)>>>,
+    max_idle: usize,
+    idle_timeout: Duration,
+}
+
+impl KeepAlive {
+    pub fn new(config: &Config) -> KeepAlive {
+        KeepAlive {
+            idle: Mutex::new(HashMap::new()),
+            max_idle: config.keep_alive_idle,
+            idle_timeout: Duration::from_secs(config.keep_alive_timeout),
+        }
+    }
+
+    /// Locks the idle connections, which are always whole since each change is a single push or pop
+    fn idle(&self) -> MutexGuard<'_, HashMap<SocketAddr, Vec<(TcpStream, Instant)>>> {
+        self.idle.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Takes an idle connection to a backend that is still open
+    ///
+    /// # Arguments
+    /// * `backend: &SocketAddr` - Address the backend listens at.
+    ///
+    /// ## Returns
+    /// The most recently used connection, in blocking mode
+    /// None if there is no usable one, so a new connection must be opened
+    pub fn take(&self, backend: &SocketAddr) -> Option<TcpStream> {
+        loop {
+            //The lock is not held while a connection is checked
+            let (stream, since) = self.idle().get_mut(backend)?.pop()?;
+            if since.elapsed() < self.idle_timeout && is_open(&stream) {
+                return Some(stream);
+            }
+        }
+    }
+
+    /// Gives back a connection whose last answer was read whole, so the next request reuses it
+    ///
+    /// # Arguments
+    /// * `backend: SocketAddr` - Address the backend listens at.
+    /// * `stream: TcpStream` - Connection to the backend.
+    pub fn put(&self, backend: SocketAddr, stream: TcpStream) {
+        let mut idle = self.idle();
+        let streams = idle.entry(backend).or_default();
+        streams.retain(|(_, since)| since.elapsed() < self.idle_timeout);
+        if streams.len() < self.max_idle {
+            streams.push((stream, Instant::now()));
+        }
+    }
+
+    /// Closes every idle connection to a backend, like when it leaves the pool or restarts
+    ///
+    /// # Arguments
+    /// * `backend: &SocketAddr` - Address the backend listens at.
+    pub fn forget(&self, backend: &SocketAddr) {
+        self.idle().remove(backend);
+    }
+
+    /// Amount of idle connections kept for a backend
+    ///
+    /// # Arguments
+    /// * `backend: &SocketAddr` - Address the backend listens at.
+    pub fn count(&self, backend: &SocketAddr) -> usize {
+        self.idle().get(backend).map_or(0, |streams| streams.len())
+    }
+}
+
+/// Whether an idle connection can still carry a request
+///
+/// # Arguments
+/// * `stream: &TcpStream` - Idle connection to a backend.
+///
+/// A backend that closed the connection makes it readable with nothing to read.
+/// Anything else to read was never asked for, so the connection can not be trusted either.
+fn is_open(stream: &TcpStream) -> bool {
+    if stream.set_nonblocking(true).is_err() {
+        return false;
+    }
+    let waiting = matches!(stream.peek(&mut [0; 1]), Err(e) if e.kind() == io::ErrorKind::WouldBlock);
+    waiting && stream.set_nonblocking(false).is_ok()
+}
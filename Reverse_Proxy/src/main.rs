@@ -1,20 +1,95 @@
+use std::collections::HashMap;
 use std::fs;
 use std::io;
 use std::net::TcpListener;
-use std::net::TcpStream;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
 use std::io::prelude::*;
-use std::thread;
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
 use colored::*;
+use rand::Rng;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
 
-type SharedSecret = Arc<Mutex<Option<String>>>;
+mod balancer;
+mod cache;
+mod circuit;
+mod config;
+mod error;
+mod event_loop;
+mod headers;
+mod health;
+mod http;
+mod ip_filter;
+mod keep_alive;
+mod multipart;
+mod rate_limit;
+mod secrets;
+mod spool;
+use balancer::{BackendEntry, Balancer, Lease, Registration};
+use cache::{Cached, Lookup, ResponseCache};
+use config::{Config, IoMode};
+use error::ProxyError;
+use headers::SecurityHeaders;
+use health::Health;
+use ip_filter::IpFilter;
+use keep_alive::KeepAlive;
+use multipart::{Parser, Upload};
+use shared::pool::WorkerPool;
+use rate_limit::{Budget, RateLimiter};
+use secrets::Secrets;
+use shared::shutdown::Shutdown;
+use spool::Spool;
+
+/// Backend of the keys whose request does not name one, where the server listens by default
+const SERVER_ADDRESS: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 1445));
+
+/// Container that store everything a connection needs to be handled
+///
+/// # Arguments
+/// * `secrets` - Secret-keys came from the servers, one per backend.
+/// * `health` - Backends ejected by the health checks.
+/// * `balancer` - Picks the backend of each request.
+/// * `keep_alive` - Idle connections to the backends, reused by the next requests.
+/// * `cache` - Responses of the servers that answer repeated requests.
+/// * `config` - Proxy's settings.
+/// * `limiter` - Per-client rate limiter.
+/// * `ip_filter` - Allow and deny lists of client networks.
+/// * `security_headers` - Headers added to every response.
+/// * `pages` - Error pages, read when the proxy starts.
+/// * `timed_out` - Amount of connections closed because a peer was too slow.
+struct ProxyState {
+    secrets: Secrets,
+    health: Health,
+    balancer: Balancer,
+    keep_alive: KeepAlive,
+    cache: ResponseCache,
+    config: Config,
+    limiter: RateLimiter,
+    ip_filter: IpFilter,
+    security_headers: SecurityHeaders,
+    pages: HashMap<&'static str, String>,
+    timed_out: AtomicU64,
+}
+
+/// Counts and reports a connection that was closed because a peer was too slow
+///
+/// # Arguments
+/// * `state: &ProxyState` - Proxy's state, which holds the counter.
+/// * `peer: &str` - Who was too slow.
+/// * `cause: &str` - What was being waited for.
+fn report_timeout(state: &ProxyState, peer: &str, cause: &str) {
+    let total = state.timed_out.fetch_add(1, Ordering::Relaxed) + 1;
+    report(format!("{} timed out ({}) >>> Closing connection (timed out connections: {})", peer, cause, total));
+}
 
 /// Print a custom pattern message on concole
 /// 
 /// # Arguments
 /// 
 /// * `message: String` - Message that will be printed out.
-fn report(message: String) -> () {
+fn report(message: String) {
     println!("[{}] {} {}", "REVERSE PROXY".red(), "::".yellow(), message.truecolor(248, 150, 1));
 }
 /// Container that store request data
@@ -24,187 +99,1296 @@ fn report(message: String) -> () {
 /// * `sigature` - Proxy's Signature.
 /// * `method` - Request's method.
 /// * `uri` - Request's path.
+/// * `version` - Request's HTTP version, like ```HTTP/1.1```.
 /// * `host` - Request's host.
-/// * `body` - Request's body.
+/// * `body` - Request's body, empty for uploads, whose file goes to the server apart.
+/// * `headers` - Request's header lines as (name, value) pairs.
+/// * `upload` - Fields of an upload, which reach the server as headers.
+/// * `keep_alive` - Whether the client's connection stays open after the answer.
+/// * `forwarded_tls` - Whether a trusted TLS terminator told that the client used TLS.
 #[allow(dead_code)]
 struct Request {
     signature: String,
     method: String,
     uri: String,
+    version: String,
     host: String,
-    body: String
+    body: String,
+    headers: Vec<(String, String)>,
+    upload: Option<Upload>,
+    keep_alive: bool,
+    forwarded_tls: bool
+}
+
+impl Request {
+    /// Returns the value of a header, ignoring the case of its name
+    ///
+    /// # Arguments
+    /// * `name: &str` - Header's name.
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Whether the request uploads a file, whose body is streamed to the server instead of read whole
+    fn is_upload(&self) -> bool {
+        self.method == "POST" && self.uri == "/upload"
+    }
+
+    /// Whether the client asks to keep its connection open, which HTTP/1.1 does unless it says otherwise
+    fn keeps_alive(&self) -> bool {
+        let connection = self.header("Connection").unwrap_or_default();
+        let says = |option: &str| connection.split(',').any(|value| value.trim().eq_ignore_ascii_case(option));
+        if self.version == "HTTP/1.1" { !says("close") } else { says("keep-alive") }
+    }
+
+    /// Whether the request is a server managing its secret-key, only exempt from rate limits from ```registry_allow``` networks
+    fn is_key_request(&self) -> bool {
+        self.method == "POST" && ["/register-secret", "/deregister-secret", "/heartbeat"].contains(&self.uri.as_str())
+    }
+
+    /// Header lines that must reach the server untouched, already formatted
+    fn forwarded_headers(&self) -> String {
+        ["Authorization", "Cookie", "X-Forwarded-For", "If-None-Match"].iter()
+            .filter_map(|name| self.header(name).map(|value| format!("{}: {}\r\n", name, value)))
+            .collect()
+    }
+}
+
+/// Body of an upload, as it goes to the server
+///
+/// * `Spooled` - File kept on disk, as the CSRF token came after it and the whole body had to arrive first.
+/// * `Streamed` - File passed on to the server as it arrives, as its fields arrived before it.
+enum UploadBody {
+    Spooled(Spool),
+    Streamed(Streamed)
+}
+
+/// Rest of an upload body that is passed on to the server as it arrives from the client
+///
+/// # Arguments
+/// * `parser` - Parser of the body, taken once the body was read and checked whole.
+/// * `pending` - File bytes that arrived but were not sent yet.
+/// * `received` - Bytes of the body read from the client so far.
+/// * `size` - Body size told by the Content-Length header.
+struct Streamed {
+    parser: Option<Parser>,
+    pending: Vec<u8>,
+    received: u64,
+    size: u64
+}
+
+impl UploadBody {
+    /// Whether the body may be sent again, to another connection, after an attempt failed
+    fn is_replayable(&self) -> bool {
+        matches!(self, UploadBody::Spooled(_))
+    }
+
+    /// Whether the whole body was read from the client, so that its connection may carry another request
+    fn is_read(&self) -> bool {
+        matches!(self, UploadBody::Spooled(_) | UploadBody::Streamed(Streamed { parser: None, .. }))
+    }
 }
 
 /// Turn a request string into a struct
 /// # Arguments
 /// * `request: String` - Request that will be processed.
-fn parse(request: String) -> Request {
-    let main_header = request.lines().next().unwrap();
+///
+/// ## Returns
+/// The request, or a 400 error if it has no request line
+fn parse(request: String) -> Result<Request, ProxyError> {
+    let mut lines = request.lines();
+    let Some(main_header) = lines.next() else {
+        return Err(ProxyError::BadRequest("Request is empty".to_string()));
+    };
+
+    let mut headers = Vec::new();
+    for line in lines {
+        match line.split_once(": ") {
+            Some((name, value)) => headers.push((name.to_string(), value.to_string())),
+            None => break
+        }
+    }
+
     let mut parts = main_header.split_whitespace();
-    let method = parts.next().unwrap();
-    let path = parts.next().unwrap();
+    let (Some(method), Some(path), version) = (parts.next(), parts.next(), parts.next()) else {
+        return Err(ProxyError::BadRequest(format!("Malformed request line ({})", main_header)));
+    };
     let host = "0.0.0.0:2006";
-    let (_, body)  = request.split_once("\r\n\r\n").unwrap();
+    let body = request.split_once("\r\n\r\n").map(|(_, body)| body).unwrap_or_default();
 
-    Request {
+    Ok(Request {
         method: method.to_string(),
         signature: "N/A".to_string(),
         uri: path.to_string(),
+        version: version.unwrap_or("HTTP/1.0").to_string(),
         host: host.to_string(),
-        body: body.to_string()
+        body: body.to_string(),
+        headers,
+        upload: None,
+        keep_alive: false,
+        forwarded_tls: false
+    })
+}
+
+/// Reads the proxy's error pages, so that no request waits on the disk to be refused
+///
+/// ## Returns
+/// The pages that could be read, by path
+fn load_pages() -> HashMap<&'static str, String> {
+    error::PAGES.iter()
+        .filter_map(|&page| match fs::read_to_string(page) {
+            Ok(contents) => Some((page, contents)),
+            Err(e) => {
+                report(format!("Could not read ({}): {} >>> Sending a plain page instead", page, e));
+                None
+            }
+        })
+        .collect()
+}
+
+/// Builds a response that carries one of the proxy's error pages, after which the connection is closed
+///
+/// # Arguments
+/// * `status: &str` - Status code and reason, like ```503 SERVICE UNAVAIBLE```.
+/// * `page: Option<&str>` - Contents of the html page, None if it could not be read.
+/// * `extra_headers: &str` - Header lines to add, each one ending with ```\r\n```.
+fn error_page(status: &str, page: Option<&str>, extra_headers: &str) -> String {
+    //A missing page must not hide the error itself
+    let contents = page.map_or_else(|| format!("<h1>Error {}</h1>", status), str::to_string);
+    format!(
+        "HTTP/1.1 {}\r\n\
+        {}\
+        Connection: close\r\n\
+        Content-Length: {}\r\n\
+        Content-Type: text/html;charset=utf-8\r\n\
+        \r\n\
+        {}",
+        status,
+        extra_headers,
+        contents.len(),
+        contents
+    )
+}
+
+/// Adds the security headers to a response made by the proxy itself
+///
+/// # Arguments
+/// * `state: &ProxyState` - Proxy's state, which holds the security headers.
+/// * `uri: &str` - Path of the request that is being answered.
+/// * `response: &str` - Whole response, head and body.
+///
+/// These answers come before the request was read, so only `tls_enabled` tells if the client used TLS.
+fn finish_response(state: &ProxyState, uri: &str, response: &str) -> String {
+    let (head, body) = response.split_once("\r\n\r\n").unwrap_or((response, ""));
+    let head = state.security_headers.inject(&format!("{}\r\n\r\n", head), uri, false);
+
+    format!("{}{}", head, body)
+}
+
+/// Writes bytes to a stream, giving up when it takes too long
+///
+/// # Arguments
+/// * `stream: &mut TcpStream` - Stream that will be written.
+/// * `data: &[u8]` - Bytes that will be written.
+/// * `timeout: Duration` - How long the whole write may take.
+async fn write_all(stream: &mut TcpStream, data: &[u8], timeout: Duration) -> io::Result<()> {
+    match tokio::time::timeout(timeout, stream.write_all(data)).await {
+        Ok(result) => result,
+        Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "write timed out"))
     }
 }
 
-/// Handles proxy's connection
-/// 
+/// Reports an error and builds the page that answers it
+///
 /// # Arguments
-/// * `mut stream: TcpStream` - Stream that holds connection with client.
-/// * `secret_state: SharedSecret` - Variable that holds secret-key came from server.
-fn proxy_handler(mut stream: TcpStream, secret_state: SharedSecret) {
-    let mut buffer = [0; 4096];
-    stream.read(&mut buffer).unwrap();
-    let request_string = String::from_utf8_lossy(&buffer[..]);
+/// * `state: &ProxyState` - Proxy's state, which holds the security headers and the timed out connections counter.
+/// * `client: &str` - Who the client is, used in the report.
+/// * `error: &ProxyError` - Why the request was given up.
+///
+/// ## Returns
+/// The whole response, or None if the client must not be answered
+fn error_response(state: &ProxyState, client: &str, error: &ProxyError) -> Option<String> {
+    let response = error.response(&state.pages);
+    match error {
+        ProxyError::ClientTimeout(timeout) => report_timeout(state, client, &format!("{:?}", timeout).to_lowercase()),
+        ProxyError::BackendTimeout(cause) => report_timeout(state, "Server", cause),
+        _ if response.is_some() => report(format!("{} >>> Sending {} response", error, error.code())),
+        _ => report(format!("{} >>> Closing connection", error))
+    }
+
+    response.map(|response| finish_response(state, "", &response))
+}
+
+/// Reports an error and answers the client with its page
+///
+/// # Arguments
+/// * `stream: &mut TcpStream` - Stream that holds connection with client.
+/// * `state: &ProxyState` - Proxy's state, which holds the security headers and the timed out connections counter.
+/// * `client: &str` - Who the client is, used in the report.
+/// * `error: ProxyError` - Why the request was given up.
+/// * `timeout: Duration` - How long writing the page may take.
+async fn send_error(stream: &mut TcpStream, state: &ProxyState, client: &str, error: ProxyError, timeout: Duration) {
+    //A peer that never sent anything, or that is already gone, does not get an answer
+    if let Some(response) = error_response(state, client, &error)
+        && let Err(e) = write_all(stream, response.as_bytes(), timeout).await {
+        report(format!("Could not send the {} response: {}", error.code(), e));
+    }
+}
+
+/// What the proxy does with a request that passed every check
+///
+/// * `Respond` - Answers the client itself with a whole response.
+/// * `Forward` - Signs the request for a backend and sends it there.
+enum Action {
+    Respond(String),
+    Forward(Box<Request>),
+}
+
+/// Checks if a client may use the proxy at all, before anything is read from it
+///
+/// # Arguments
+/// * `state: &ProxyState` - Proxy's state, which holds the IP filter.
+/// * `client_ip: IpAddr` - Client's IP.
+fn check_client(state: &ProxyState, client_ip: IpAddr) -> Result<(), ProxyError> {
+    if !state.ip_filter.allows(client_ip) {
+        return Err(ProxyError::Forbidden(format!("Client ({}) is not allowed by the IP filter", client_ip)));
+    }
+
+    Ok(())
+}
+
+/// Turns a request head into a request and checks it before its body is read
+///
+/// # Arguments
+/// * `state: &ProxyState` - Proxy's state, which holds the rate limiter and the settings.
+/// * `client_ip: IpAddr` - Client's IP.
+/// * `request_head: String` - Request line and headers.
+///
+/// ## Returns
+/// The request and the size of its body
+fn admit(state: &ProxyState, client_ip: IpAddr, request_head: String) -> Result<(Request, u64), ProxyError> {
+    let mut request = parse(request_head)?;
+    request.forwarded_tls = state.security_headers.forwarded_tls(client_ip, request.header("X-Forwarded-Proto"));
+
+    //Servers may only manage their keys from the networks allowed into the pool, anyone else
+    //sending these requests spends their upload budget like any other POST
+    let from_backend = request.is_key_request() && check_registry(state, client_ip).is_ok();
+    if !from_backend {
+        //Deleting changes the files like an upload does, so it spends the same budget
+        let budget = if matches!(request.method.as_str(), "POST" | "DELETE") { Budget::Upload } else { Budget::Read };
+
+        if let Err(retry_after) = state.limiter.check(client_ip, budget) {
+            return Err(ProxyError::TooManyRequests {
+                context: format!("Client ({}) exceeded its rate limit", client_ip),
+                retry_after: retry_after.as_secs_f64().ceil() as u64
+            });
+        }
+    }
 
-    let mut request = parse(request_string.to_string());
+    //Bodies are checked before they are read, only an upload's is not held whole in memory
+    let size: u64 = request.header("Content-Length").and_then(|l| l.trim().parse().ok()).unwrap_or(0);
+    let max_size = if request.is_upload() { state.config.max_body_size } else { state.config.max_body_size.min(http::MAX_READ_BODY) };
+    if size > max_size {
+        return Err(ProxyError::PayloadTooLarge(format!("Client ({}) sent a body of {} bytes", client_ip, size)));
+    }
+
+    Ok((request, size))
+}
 
+/// Decides what to do with a whole request: answer it or sign it to the server
+///
+/// # Arguments
+/// * `state: &ProxyState` - Proxy's state, which holds the secret-key.
+/// * `mut request: Request` - Request, along with its body.
+/// * `client_ip: IpAddr` - Client's IP.
+fn dispatch(state: &ProxyState, mut request: Request, client_ip: IpAddr) -> Result<Action, ProxyError> {
     if request.method == "POST" && request.uri == "/register-secret" {
+        let backend = key_backend(&request)?;
         let body = request.body.trim().trim_end_matches('\0');
-        //Locks local thread to keep secret_key value
-        let mut signature_key = secret_state.lock().unwrap();
-        *signature_key = Some(body.to_string());
+        if body.is_empty() {
+            return Err(ProxyError::BadRequest("Server sent an empty key".to_string()));
+        }
+        check_registry(state, client_ip)?;
+        //A live server keeps its key, a server that restarted without removing it gets in once the checks signed with it eject it
+        if state.health.is_healthy(&backend) && !state.secrets.may_replace(&backend, body, request.header("Backend-Signature").map(str::trim)) {
+            return Err(ProxyError::Forbidden(format!("Client ({}) tried to replace the key of a live server ({})", client_ip, backend)));
+        }
+        let registration = registration(&request, backend)?;
+        let id = registration.id.clone();
+        state.balancer.register(registration)
+            .map_err(|reason| ProxyError::Forbidden(format!("Server ({}) could not join the pool: {}", backend, reason)))?;
+        state.secrets.register(backend, body);
+        //A server that registers again has just started, so it gets requests right away and its old connections are gone
+        state.health.restore(&backend);
+        state.keep_alive.forget(&backend);
 
-        report(format!("Received server's key >>> {}...", &body[0..5]));
-        report(format!("Sending back positive response"));
+        report(format!("Received server's key ({} at {}) >>> {}...", id, backend, body.get(0..5).unwrap_or(body)));
+        report("Sending back positive response".to_string());
 
-        let response = "HTTP/1.1 200 OK\r\n\r\n";
-        stream.write(response.as_bytes()).unwrap();
-        stream.flush().unwrap();
+        Ok(Action::Respond("HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n".to_string()))
+    } else if request.method == "POST" && request.uri == "/deregister-secret" {
+        let backend = key_backend(&request)?;
+        let body = request.body.trim().trim_end_matches('\0');
+        //Only the server, which knows the key, may take it away
+        if body.is_empty() || !state.secrets.deregister(&backend, body) {
+            return Err(ProxyError::Forbidden(format!("Client ({}) tried to remove a key it does not hold", client_ip)));
+        }
+        state.balancer.deregister(&backend);
+        state.health.restore(&backend);
+        state.keep_alive.forget(&backend);
+
+        report(format!("Server ({}) is shutting down >>> Removed its key, it gets no requests until it registers again", backend));
+
+        Ok(Action::Respond("HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n".to_string()))
+    } else if request.method == "POST" && request.uri == "/heartbeat" {
+        let backend = key_backend(&request)?;
+        let body = request.body.trim().trim_end_matches('\0');
+        //A proxy that restarted has forgotten the key, the server registers it again when it is refused
+        if body.is_empty() || !state.secrets.holds(&backend, body) {
+            return Err(ProxyError::Forbidden(format!("Server ({}) sent a heartbeat with a key that is not registered", backend)));
+        }
+
+        Ok(Action::Respond("HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n".to_string()))
+    } else if request.method == "GET" && (request.uri == "/healthz" || request.uri == "/readyz") {
+        Ok(Action::Respond(health_report(state, request.uri == "/readyz")))
+    } else if request.method == "GET" && request.uri == "/backends" {
+        check_registry(state, client_ip)?;
+        Ok(Action::Respond(backend_listing(state)))
+    } else if request.method == "POST" && request.uri == "/purge-cache" {
+        check_registry(state, client_ip)?;
+        //The body names the URI to purge, an empty body purges everything
+        let uri = request.body.trim().trim_end_matches('\0');
+        let purged = state.cache.purge(Some(uri).filter(|uri| !uri.is_empty()));
+        report(format!("Client ({}) purged the cache ({}) >>> Removed {} responses", client_ip, if uri.is_empty() { "everything" } else { uri }, purged));
+
+        let body = format!("{{\"purged\":{}}}", purged);
+        Ok(Action::Respond(format!("HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nCache-Control: no-store\r\nContent-Length: {}\r\n\r\n{}", body.len(), body)))
     } else if request.method == "GET" && request.uri == "/favicon.ico" {
-        report(format!("Client requested favicon.ico >>> Sending 204 response"));
-        let response = "HTTP/1.1 204 NO CONTENT\r\n\r\n";
-        stream.write(response.as_bytes()).unwrap();
-        stream.flush().unwrap();
+        report("Client requested favicon.ico >>> Sending 204 response".to_string());
+        Ok(Action::Respond("HTTP/1.1 204 NO CONTENT\r\n\r\n".to_string()))
 
     } else {
         report(format!("Received new request => \n\
                             Method: {}\nURI: {}\nHost: {}\nProvider: {}\n\nBody: {}\n",
-                            request.method, request.uri, request.host, stream.peer_addr().unwrap(),request.body));
-        //Secure that secret_state can be accessed by this local thread
-        let signature_key_guard = match secret_state.lock() {
-            Ok(guard) => guard,
-            Err(_) => {
-                let contents = fs::read_to_string("./pages/503.html").unwrap();
-                let response = format!(
-                    "HTTP/1.1 503 SERVICE UNAVAIBLE\r\n\
-                    Content-Length: {}\r\n\
-                    Content-Type: text/html;charset=utf-8\r\n\
-                    \r\n\
-                    {}",
-                    contents.len(),
-                    contents
-                );
-                stream.write(response.as_bytes()).unwrap();
-                stream.flush().unwrap();
-                panic!();
-            }
+                            request.method, request.uri, request.host, client_ip, request.body));
+        //The server trusts this header to know the client, so the client's own value is replaced
+        request.headers.retain(|(name, _)| !name.eq_ignore_ascii_case("X-Forwarded-For"));
+        request.headers.push(("X-Forwarded-For".to_string(), client_ip.to_string()));
+
+        Ok(Action::Forward(Box::new(request)))
+    }
+}
+
+/// Picks the backend of a request and signs the request with its key
+///
+/// # Arguments
+/// * `state: &ProxyState` - Proxy's state, which holds the pool, the keys and the health of the backends.
+/// * `request: &mut Request` - Request of the client, which gets the backend's signature.
+/// * `tried: &[SocketAddr]` - Backends that already failed the request, only picked again when no other one is left.
+///
+/// ## Returns
+/// The picked backend and the signed request, formatted for the server
+/// A 503 error if no backend may get the request
+fn sign_for_backend(state: &ProxyState, request: &mut Request, tried: &[SocketAddr]) -> Result<(Lease, String), ProxyError> {
+    //Only backends that registered a key and pass their health checks get requests
+    let usable = |backend: &SocketAddr| state.secrets.get(backend).is_some() && state.health.is_healthy(backend);
+    let lease = state.balancer.pick(&request.uri, |backend| usable(backend) && !tried.contains(backend))
+        .or_else(|| state.balancer.pick(&request.uri, usable))
+        .ok_or_else(|| if state.secrets.backends().is_empty() {
+            ProxyError::Unavailable("No server has registered its secret-key".to_string())
+        } else if state.balancer.backends().iter().any(|backend| usable(&backend.entry.address)) {
+            ProxyError::Unavailable("Every server has an open circuit".to_string())
+        } else {
+            ProxyError::Unavailable("Every server is ejected by the health checks".to_string())
+        })?;
+    let signature_key = state.secrets.get(&lease.address())
+        .ok_or_else(|| ProxyError::Unavailable(format!("Server ({}) removed its secret-key", lease.address())))?;
+
+    request.signature = signature_key.to_string();
+    let server_request = server_request(request)?;
+    Ok((lease, server_request))
+}
+
+/// Records in the backend's circuit how a forwarded request went
+///
+/// # Arguments
+/// * `lease: &mut Lease` - Backend the request was sent to.
+/// * `result: &Result<bool, ProxyError>` - How the request ended.
+fn settle(lease: &mut Lease, result: &Result<bool, ProxyError>) {
+    match result {
+        Ok(_) => lease.succeeded(),
+        Err(ProxyError::BadGateway(_) | ProxyError::GatewayTimeout(_) | ProxyError::BackendTimeout(_) | ProxyError::BackendFailed(_)) => lease.failed(),
+        //Anything else, like a client that left, says nothing about the backend
+        Err(_) => {}
+    }
+}
+
+/// Decides whether a request that a backend failed is sent again, and when
+///
+/// # Arguments
+/// * `state: &ProxyState` - Proxy's state, which holds the retry settings.
+/// * `request: &Request` - Request that failed.
+/// * `error: &ProxyError` - How it failed.
+/// * `attempt: u32` - Retries already made.
+///
+/// ## Returns
+/// How long to wait before sending it again
+/// None if the error goes to the client
+fn retry_after(state: &ProxyState, request: &Request, error: &ProxyError, attempt: u32) -> Option<Duration> {
+    //Only requests that change nothing are sent twice, and only while no answer has reached the client.
+    //GET is the only such method the server takes, anything else is refused before reaching a backend.
+    let idempotent = request.method == "GET";
+    let unanswered = matches!(error, ProxyError::BadGateway(_) | ProxyError::GatewayTimeout(_));
+    if !idempotent || !unanswered || attempt >= state.config.retries {
+        return None;
+    }
+
+    //A random wait up to the ceiling keeps clients that failed together from retrying together
+    let ceiling = state.config.retry_backoff.saturating_mul(1 << attempt.min(16));
+    Some(Duration::from_millis(rand::rng().random_range(0..=ceiling)))
+}
+
+/// Builds the answer of the proxy's own ```/healthz``` and ```/readyz``` endpoints
+///
+/// # Arguments
+/// * `state: &ProxyState` - Proxy's state, which holds the keys and the health of the backends.
+/// * `readiness: bool` - Whether the proxy must also be able to forward requests, like for ```/readyz```.
+///
+/// ## Returns
+/// A 200 response while the proxy is alive (and ready, for readiness), a 503 one otherwise
+fn health_report(state: &ProxyState, readiness: bool) -> String {
+    let backends = state.secrets.backends();
+    let healthy = backends.iter().filter(|(backend, _)| state.health.is_healthy(backend)).count();
+    let ready = !backends.is_empty() && healthy > 0;
+
+    let status = if readiness && !ready { "503 SERVICE UNAVAIBLE" } else { "200 OK" };
+    let body = format!("{{\"status\":\"{}\",\"registered_keys\":{},\"healthy_backends\":{}}}",
+                        if ready { "ready" } else { "not ready" }, backends.len(), healthy);
+
+    format!("HTTP/1.1 {}\r\nContent-Type: application/json\r\nCache-Control: no-store\r\nContent-Length: {}\r\n\r\n{}", status, body.len(), body)
+}
+
+/// Finds which backend a key is being registered or removed for
+///
+/// # Arguments
+/// * `request: &Request` - Key request, which may name its backend in the ```Backend-Address``` and ```Backend-Port``` headers.
+///
+/// ## Returns
+/// The backend's address, the default server when there are no headers
+/// A 400 error if the headers are not an address
+fn key_backend(request: &Request) -> Result<SocketAddr, ProxyError> {
+    match (request.header("Backend-Address"), request.header("Backend-Port")) {
+        (Some(address), Some(port)) => match (address.trim().parse::<IpAddr>(), port.trim().parse::<u16>()) {
+            (Ok(address), Ok(port)) => Ok(SocketAddr::new(address, port)),
+            _ => Err(ProxyError::BadRequest(format!("Key request named an invalid backend ({}, port {})", address, port)))
+        },
+        (None, None) => Ok(SERVER_ADDRESS),
+        _ => Err(ProxyError::BadRequest("Key request must name both Backend-Address and Backend-Port".to_string()))
+    }
+}
+
+/// Reads what a server tells about itself in the headers of ```/register-secret```
+///
+/// # Arguments
+/// * `request: &Request` - Registration request.
+/// * `backend: SocketAddr` - Address the server listens at.
+///
+/// ## Returns
+/// The registration, where missing headers take their defaults (the address as ID, weight 1 and every path)
+/// A 400 error if a header is invalid
+fn registration(request: &Request, backend: SocketAddr) -> Result<Registration, ProxyError> {
+    let invalid = |header: &str, value: &str| ProxyError::BadRequest(format!("Server ({}) sent an invalid {} ({})", backend, header, value));
+
+    //Both end up in the backends listing, so they are kept to plain characters
+    let id = request.header("Backend-Id").map(|id| id.trim()).filter(|id| !id.is_empty()).unwrap_or_default();
+    if !id.chars().all(|c| c.is_ascii_alphanumeric() || "-_.:".contains(c)) {
+        return Err(invalid("Backend-Id", id));
+    }
+    let weight = match request.header("Backend-Weight") {
+        Some(weight) => weight.trim().parse().ok().filter(|weight| *weight > 0).ok_or_else(|| invalid("Backend-Weight", weight))?,
+        None => 1
+    };
+    let prefixes: Vec<String> = request.header("Backend-Prefixes").unwrap_or_default()
+        .split(',')
+        .map(|prefix| prefix.trim())
+        .filter(|prefix| !prefix.is_empty())
+        .map(|prefix| prefix.to_string())
+        .collect();
+    if let Some(prefix) = prefixes.iter().find(|prefix| !prefix.starts_with('/') || prefix.chars().any(|c| !c.is_ascii_graphic() || c == '"' || c == '\\')) {
+        return Err(invalid("Backend-Prefixes", prefix));
+    }
+
+    Ok(Registration {
+        id: if id.is_empty() { backend.to_string() } else { id.to_string() },
+        entry: BackendEntry { address: backend, weight },
+        prefixes
+    })
+}
+
+/// Checks if a client may change or list the backend pool, or purge the cache
+///
+/// # Arguments
+/// * `state: &ProxyState` - Proxy's state, which holds the networks allowed to.
+/// * `client_ip: IpAddr` - Client's IP.
+fn check_registry(state: &ProxyState, client_ip: IpAddr) -> Result<(), ProxyError> {
+    if !state.config.registry_allow.iter().any(|network| network.contains(client_ip)) {
+        return Err(ProxyError::Forbidden(format!("Client ({}) is not allowed to manage the proxy", client_ip)));
+    }
+
+    Ok(())
+}
+
+/// Builds the answer of ```/backends```, the backends that are in the pool right now
+///
+/// # Arguments
+/// * `state: &ProxyState` - Proxy's state, which holds the pool, the keys and the health of the backends.
+fn backend_listing(state: &ProxyState) -> String {
+    let backends: Vec<String> = state.balancer.backends().iter().map(|backend| {
+        let address = backend.entry.address;
+        let prefixes: Vec<String> = backend.prefixes.iter().map(|prefix| format!("\"{}\"", prefix)).collect();
+        format!("{{\"id\":\"{}\",\"address\":\"{}\",\"port\":{},\"weight\":{},\"prefixes\":[{}],\"pinned\":{},\"registered\":{},\"healthy\":{},\"active\":{},\"circuit\":\"{}\",\"idle\":{}}}",
+                backend.id, address.ip(), address.port(), backend.entry.weight, prefixes.join(","), backend.pinned,
+                state.secrets.get(&address).is_some(), state.health.is_healthy(&address), backend.active(), backend.circuit(), state.keep_alive.count(&address))
+    }).collect();
+    let body = format!("[{}]", backends.join(","));
+
+    format!("HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nCache-Control: no-store\r\nContent-Length: {}\r\n\r\n{}", body.len(), body)
+}
+
+/// Handles proxy's connection, one request after another while the client keeps it open
+/// 
+/// # Arguments
+/// * `mut stream: TcpStream` - Stream that holds connection with client.
+/// * `state: Arc<ProxyState>` - Variable that holds secret-key came from server, proxy's settings and per-client protections.
+/// * `client_ip: IpAddr` - Client's IP.
+/// * `shutdown: Arc<Shutdown>` - Tells when to stop waiting for the next request.
+///
+/// Both the worker threads and the event loop hand their connections here.
+/// Any error that gives up a request is reported and answered with its page, which closes the connection.
+async fn proxy_handler(mut stream: TcpStream, state: Arc<ProxyState>, client_ip: IpAddr, shutdown: Arc<Shutdown>) {
+    let config = &state.config;
+    let client = format!("Client ({})", client_ip);
+    let write_timeout = Duration::from_secs(config.write_timeout);
+    if let Err(e) = check_client(&state, client_ip) {
+        send_error(&mut stream, &state, &client, e, write_timeout).await;
+        return;
+    }
+
+    let header_timeout = Duration::from_secs(config.header_timeout);
+    let mut idle_timeout = Duration::from_secs(config.idle_timeout);
+    let mut answered = false;
+    loop {
+        let head = tokio::select! {
+            head = http::read_head_async(&mut stream, idle_timeout, header_timeout) => head,
+            //Only a connection waiting for its next request is closed right away, the first one is still read
+            _ = shutdown.wait(), if answered => break
         };
-        //Access by reference the secret_key value from the lock_guard
-        let signature_key = match &*signature_key_guard {
-            Some(s) => s.clone(),
-            None => {
-                let contents = fs::read_to_string("./pages/503.html").unwrap();
-                let response = format!(
-                    "HTTP/1.1 503 SERVICE UNAVAIBLE\r\n\
-                    Content-Length: {}\r\n\
-                    Content-Type: text/html; charset=utf-8\r\n\
-                    \r\n\
-                    {}",
-                    contents.len(),
-                    contents
-                );
-                stream.write(response.as_bytes()).unwrap();
-                stream.flush().unwrap();
-                return;
+        let result = match head {
+            //A client that kept its connection open may close it, or leave it idle, instead of sending another request
+            Ok((request_head, _)) if request_head.is_empty() && answered => break,
+            Err(http::Error::Timeout(http::Timeout::Idle)) if answered => break,
+            Ok((request_head, body_start)) => handle_request(&mut stream, &state, client_ip, request_head, body_start, &shutdown).await,
+            Err(e) => Err(e.into())
+        };
+        match result {
+            Ok(true) => {
+                answered = true;
+                idle_timeout = Duration::from_secs(config.client_keep_alive_timeout);
+            },
+            Ok(false) => break,
+            Err(e) => {
+                send_error(&mut stream, &state, &client, e, write_timeout).await;
+                break;
             }
+        }
+    }
+
+    let _ = stream.shutdown().await;
+}
+
+/// Checks and answers or forwards a request of a client, once its head is read
+///
+/// # Arguments
+/// * `stream: &mut TcpStream` - Stream that holds connection with client.
+/// * `state: &ProxyState` - Proxy's state.
+/// * `client_ip: IpAddr` - Client's IP.
+/// * `request_head: String` - Request line and headers.
+/// * `body_start: Vec<u8>` - Body bytes that were read along with the head.
+/// * `shutdown: &Shutdown` - Tells whether the connection may still be kept open.
+///
+/// ## Returns
+/// Whether the connection stays open for the next request of the client
+async fn handle_request(stream: &mut TcpStream, state: &ProxyState, client_ip: IpAddr, request_head: String, body_start: Vec<u8>, shutdown: &Shutdown) -> Result<bool, ProxyError> {
+    let config = &state.config;
+    let (mut request, size) = admit(state, client_ip, request_head)?;
+    //Bytes past the body would be taken as the start of the next request, so such a connection ends with the answer
+    let whole = body_start.len() as u64 <= size;
+    request.keep_alive = whole && request.keeps_alive() && config.client_keep_alive_timeout > 0 && !shutdown.is_requested();
+
+    let body_timeout = Duration::from_secs(config.body_timeout);
+    //An upload's file is never held whole, only the fields around it are
+    let mut file = None;
+    if request.is_upload() {
+        let (upload, body) = read_upload(stream, body_start, &request, size, body_timeout).await?;
+        request.upload = Some(upload);
+        file = Some(body);
+    } else {
+        request.body = http::read_body_async(stream, body_start, size, body_timeout).await?;
+    }
+
+    let (uri, keep_alive, forwarded_tls) = (request.uri.clone(), request.keep_alive, request.forwarded_tls);
+    match dispatch(state, request, client_ip)? {
+        Action::Respond(response) => {
+            let (head, body) = response.split_once("\r\n\r\n").unwrap_or((&response, ""));
+            //Only an answer whose end the client can tell leaves the connection open
+            let keep_alive = keep_alive && answer_framing(head).0.is_some();
+            let head = state.security_headers.inject(&client_head(head, keep_alive), &uri, forwarded_tls);
+            write_all(stream, format!("{}{}", head, body).as_bytes(), Duration::from_secs(config.write_timeout)).await?;
+            Ok(keep_alive)
+        },
+        Action::Forward(mut request) => forward(&mut request, file.as_mut(), stream, state).await
+    }
+}
+
+/// Reads the body of an upload until its file starts, or whole when the file must wait on disk
+///
+/// The file is passed on to the server as it arrives when the CSRF token came before it, like in the
+/// shipped form. Otherwise the token is only known once the whole body arrived, and the server needs
+/// it in the head that comes before the file, so the file is written to disk meanwhile.
+///
+/// # Arguments
+/// * `stream: &mut TcpStream` - Stream that holds connection with client.
+/// * `mut body: Vec<u8>` - Body bytes that were already read along with the head.
+/// * `request: &Request` - Upload request, whose Content-Type holds the boundary.
+/// * `size: u64` - Body size told by the Content-Length header.
+/// * `body_timeout: Duration` - How long to wait for each part of the body.
+///
+/// ## Returns
+/// The fields of the upload and the body that is left to send
+/// A 400 error if the body is not an upload the server can take
+/// A 503 error if the file could not be written to disk
+async fn read_upload(stream: &mut TcpStream, mut body: Vec<u8>, request: &Request, size: u64, body_timeout: Duration) -> Result<(Upload, UploadBody), ProxyError> {
+    let boundary = multipart::boundary(request.header("Content-Type").unwrap_or_default())
+        .ok_or_else(|| ProxyError::BadRequest("Upload is not a multipart/form-data body".to_string()))?;
+    let mut parser = Parser::new(&boundary);
+
+    body.truncate(size.min(body.len() as u64) as usize);
+    let mut received = body.len() as u64;
+    let mut file = parser.feed(&body).map_err(ProxyError::BadRequest)?;
+    let mut buffer = [0; 8192];
+    loop {
+        match parser.started() {
+            Some(upload) if upload.csrf_token.is_some() => {
+                return Ok((upload, UploadBody::Streamed(Streamed { parser: Some(parser), pending: file, received, size })));
+            },
+            Some(_) => break,
+            None if received >= size => break,
+            None => {}
+        }
+        let wanted = buffer.len().min((size - received) as usize);
+        let bytes_read = http::read_some_async(stream, &mut buffer[..wanted], body_timeout).await?;
+        received += bytes_read as u64;
+        file = parser.feed(&buffer[..bytes_read]).map_err(ProxyError::BadRequest)?;
+    }
+
+    let spool_error = |e: io::Error| ProxyError::Unavailable(format!("Could not keep the upload on disk: {}", e));
+    let mut spool = Spool::create().await.map_err(spool_error)?;
+    spool.write(&file).await.map_err(spool_error)?;
+    while received < size {
+        let wanted = buffer.len().min((size - received) as usize);
+        let bytes_read = http::read_some_async(stream, &mut buffer[..wanted], body_timeout).await?;
+        received += bytes_read as u64;
+        let file = parser.feed(&buffer[..bytes_read]).map_err(ProxyError::BadRequest)?;
+        spool.write(&file).await.map_err(spool_error)?;
+    }
+
+    Ok((parser.finish().map_err(ProxyError::BadRequest)?, UploadBody::Spooled(spool)))
+}
+
+/// Sends a request to a backend, and again after a backoff, to another backend when there is one, while it may be retried
+///
+/// # Arguments
+/// * `request: &mut Request` - Request of the client, signed again for each backend.
+/// * `mut file: Option<&mut UploadBody>` - Body of an upload, left to send.
+/// * `stream: &mut TcpStream` - Stream that holds connection with client.
+/// * `state: &ProxyState` - Proxy's state.
+///
+/// ## Returns
+/// Whether the connection stays open, once the answer reached the client
+/// The error of the last attempt otherwise
+async fn forward(request: &mut Request, mut file: Option<&mut UploadBody>, stream: &mut TcpStream, state: &ProxyState) -> Result<bool, ProxyError> {
+    let stale = match check_cache(state, request) {
+        Some((cached, true)) => {
+            let response = cached_response(state, request, &cached, request.header("If-None-Match"), request.keep_alive);
+            write_all(stream, &response, Duration::from_secs(state.config.write_timeout)).await?;
+            return Ok(request.keep_alive);
+        },
+        stale => stale.map(|(cached, _)| cached)
+    };
+    let mut tried = Vec::new();
+    let mut last_error = None;
+
+    loop {
+        //A retry that finds no backend left answers with the failure that caused it
+        let (mut lease, server_request) = match sign_for_backend(state, request, &tried) {
+            Ok(picked) => picked,
+            Err(e) => return Err(last_error.unwrap_or(e))
         };
-        //Unlock thread, realeasing secret_key common value from this thread
-        drop(signature_key_guard);
+        let backend = lease.address();
+        let result = proxy_forward(backend, request, &server_request, stale.as_deref(), file.as_deref_mut(), stream, state).await;
+        settle(&mut lease, &result);
+        drop(lease);
 
-        request.signature = signature_key;
+        let e = match result {
+            Ok(kept) => {
+                invalidate_cache(state, request);
+                return Ok(kept);
+            },
+            Err(e) => e
+        };
+        let Some(delay) = retry_after(state, request, &e, tried.len() as u32) else {
+            return Err(e);
+        };
+        report(format!("Server ({}) failed the request ({}) >>> Retrying in {}ms", backend, e, delay.as_millis()));
+        tried.push(backend);
+        last_error = Some(e);
+        tokio::time::sleep(delay).await;
+    }
+}
 
-        proxy_forward(request, stream);
+/// Looks for a stored response that answers a request, before the request goes to a server
+///
+/// # Arguments
+/// * `state: &ProxyState` - Proxy's state, which holds the cache.
+/// * `request: &mut Request` - Request of the client, which gets the ETag of a stale response to confirm.
+///
+/// ## Returns
+/// The stored response, along with whether it answers the request without asking the server
+/// None if the request goes to the server as it is
+fn check_cache(state: &ProxyState, request: &mut Request) -> Option<(Arc<Cached>, bool)> {
+    match state.cache.lookup(request) {
+        Lookup::Fresh(cached) => {
+            report(format!("Found ({}) in the cache >>> Answering without the server", request.uri));
+            Some((cached, true))
+        },
+        //A client that asks about its own copy gets the server's answer instead
+        Lookup::Stale(cached) if request.header("If-None-Match").is_none() => {
+            let etag = cached.etag.clone().unwrap_or_default();
+            request.headers.push(("If-None-Match".to_string(), etag));
+            Some((cached, false))
+        },
+        _ => None
     }
 }
 
-/// Passes Forward a request of a client to the server
-/// 
+/// Builds the answer of a request from a stored response
+///
 /// # Arguments
-/// * `request: Request` - Countainer that holds request data.
-/// * `mut stream: TcpStream` - Stream that holds connection with client.
-fn proxy_forward(request: Request, mut stream: TcpStream) {
-    let mut server_stream = TcpStream::connect("127.0.0.1:1445").unwrap();
-    let server_request;
+/// * `state: &ProxyState` - Proxy's state, which holds the security headers.
+/// * `request: &Request` - Client's request, whose path and TLS pick the security headers.
+/// * `cached: &Cached` - Stored response.
+/// * `client_tags: Option<&str>` - ```If-None-Match``` of the client, the versions it already holds.
+/// * `keep_alive: bool` - Whether the client's connection stays open after the answer.
+///
+/// ## Returns
+/// A 304 answer if the client already holds the stored version, the stored response otherwise
+fn cached_response(state: &ProxyState, request: &Request, cached: &Cached, client_tags: Option<&str>, keep_alive: bool) -> Vec<u8> {
+    let holds = cached.etag.as_deref()
+        .is_some_and(|etag| client_tags.is_some_and(|tags| tags.split(',').any(|tag| tag.trim() == etag || tag.trim() == "*")));
+    let mut lines = cached.head.trim_end_matches("\r\n").split("\r\n");
+    let status = lines.next().unwrap_or_default();
+    let mut head = format!("{}\r\n", if holds { "HTTP/1.1 304 NOT MODIFIED" } else { status });
+    for line in lines {
+        //A 304 has no body to describe, and the stored connection header was meant for another client
+        let name = line.split_once(':').map(|(name, _)| name.trim()).unwrap_or(line);
+        if (holds && (name.eq_ignore_ascii_case("Content-Length") || name.eq_ignore_ascii_case("Content-Type"))) || name.eq_ignore_ascii_case("Connection") {
+            continue;
+        }
+        head.push_str(line);
+        head.push_str("\r\n");
+    }
+    head.push_str(&format!("Age: {}\r\nX-Cache: HIT\r\n", cached.age()));
+    let head = client_head(&head, keep_alive);
+
+    let mut response = state.security_headers.inject(&head, &request.uri, request.forwarded_tls).into_bytes();
+    if !holds {
+        response.extend_from_slice(&cached.body);
+    }
+    response
+}
+
+/// Removes from the cache what a request that changed the files went through may have changed
+///
+/// # Arguments
+/// * `state: &ProxyState` - Proxy's state, which holds the cache.
+/// * `request: &Request` - Request that reached the server.
+fn invalidate_cache(state: &ProxyState, request: &Request) {
+    if request.method == "GET" {
+        return;
+    }
+    //An upload or a delete only changes its own file and the listing, anything else may have changed any page
+    let purged = match &request.upload {
+        //The server keeps only the last part of the name, as the file is stored without folders
+        Some(upload) => state.cache.purge_file(upload.file_name.rsplit(['/', '\\']).next().unwrap_or_default()),
+        None if request.method == "DELETE" => state.cache.purge_file(request.uri.split('?').next().unwrap_or_default().trim_start_matches('/')),
+        None => state.cache.purge(None)
+    };
+    if purged > 0 {
+        report(format!("Request ({} {}) may have changed the files >>> Removed {} responses from the cache", request.method, request.uri, purged));
+    }
+}
+
+/// Formats the head of the signed request that is sent to the server, an upload's file follows it
+///
+/// # Arguments
+/// * `request: &Request` - Countainer that holds request data, already signed.
+///
+/// ## Returns
+/// The request head, or a 400 error if the server would not understand it
+fn server_request(request: &Request) -> Result<String, ProxyError> {
+    //The server does not read the body of a GET, which would be taken as the next request of the reused connection
     if request.method == "GET" {
-        server_request = format!(
-            "X-Proxy-Signature: {}\r\n{} {} HTTP/1.1\r\nHost: {}\r\n\r\n{}",
+        Ok(format!(
+            "X-Proxy-Signature: {}\r\n{} {} HTTP/1.1\r\nHost: {}\r\nConnection: keep-alive\r\n{}\r\n",
             request.signature,
             request.method,
             request.uri,
             request.host,
-            request.body
-        );
+            request.forwarded_headers()
+        ))
 
-    } else if request.method == "POST" && request.uri == "/upload" {
+    } else if request.is_upload() {
+        let Some(upload) = &request.upload else {
+            return Err(ProxyError::BadRequest("Upload without any file".to_string()));
+        };
+        //The token becomes a header line, so it must not carry line breaks
+        let csrf_token = upload.csrf_token.as_deref()
+            .filter(|token| token.chars().all(|c| c.is_ascii_graphic()))
+            .unwrap_or("N/A");
 
-        let line = request.body.lines().nth(1).unwrap().split("; ").nth(2).unwrap();
+        //A file passed on as it arrives has no known size, only the client's body bounds it
+        let framing = match upload.length {
+            Some(length) => format!("Content-Length: {}", length),
+            None => format!("Transfer-Encoding: chunked\r\nUpload-Max-Length: {}", request.header("Content-Length").unwrap_or("0").trim())
+        };
 
-        let file_name = line.split_once("=").unwrap().1.replace('"', "");
+        Ok(format!(
+            "X-Proxy-Signature: {}\r\n{} {} HTTP/1.1\r\nHost: {}\r\nConnection: keep-alive\r\n{}X-CSRF-Token: {}\r\nFile-Name: {}\r\n{}\r\n\r\n",
+            request.signature,
+            request.method, 
+            request.uri,
+            request.host,
+            request.forwarded_headers(),
+            csrf_token,
+            upload.file_name,
+            framing
+        ))
 
-        let file_content = request.body.split_once("\r\n\r\n").unwrap().1.split_once("\r\n").unwrap().0.trim_end_matches('\0').trim();
+    } else if request.method == "DELETE" {
+        //The token comes from the client's header, as there is no form, and must not carry line breaks either
+        let csrf_token = request.header("X-CSRF-Token")
+            .filter(|token| token.chars().all(|c| c.is_ascii_graphic()))
+            .unwrap_or("N/A");
 
-        server_request = format!(
-            "X-Proxy-Signature: {}\r\n{} {} HTTP/1.1\r\nHost: {}\r\nFile-Name: {}\r\nContent-Length: {}\r\n\r\n{}",
+        Ok(format!(
+            "X-Proxy-Signature: {}\r\n{} {} HTTP/1.1\r\nHost: {}\r\nConnection: keep-alive\r\n{}X-CSRF-Token: {}\r\nContent-Length: 0\r\n\r\n",
             request.signature,
-            request.method, 
+            request.method,
             request.uri,
             request.host,
-            file_name,
-            file_content.len(),
-            file_content
-        );
+            request.forwarded_headers(),
+            csrf_token
+        ))
 
     } else {
-        report(format!(
-            "Strange Request >>> Method: {} | Path: {} | Body: {}", 
+        Err(ProxyError::BadRequest(format!(
+            "Strange Request (Method: {} | Path: {} | Body: {})",
             request.method, request.uri, request.body
-        ));
-        return;
+        )))
     }
-    server_stream.write(server_request.as_bytes()).unwrap();
-    server_stream.flush().unwrap();
+}
 
-    report(format!("Request ({}) successfuly forwarded", request.method));
+/// Passes Forward a request of a client to the server
+/// 
+/// # Arguments
+/// * `backend: SocketAddr` - Address of the server the request was signed for.
+/// * `request: &Request` - Request of the client, used to pick the security headers and to cache the answer.
+/// * `server_request: &str` - Signed request, already formatted.
+/// * `stale: Option<&Cached>` - Stored response whose ETag the request carries, answered if the server confirms it.
+/// * `mut file: Option<&mut UploadBody>` - Body of an upload, left to send.
+/// * `stream: &mut TcpStream` - Stream that holds connection with client.
+/// * `state: &ProxyState` - Proxy's state, which holds the security headers, the idle connections and the cache.
+///
+/// An idle connection to the server is reused when there is one, and kept for the next request when the answer was read whole.
+/// An upload passed on as it arrives always gets a new connection, as it could not be sent again if a reused one was gone.
+/// Both bodies pass through a fixed buffer, so neither is ever held whole in memory.
+///
+/// ## Returns
+/// Whether the client's connection stays open, once the server's answer reached the client
+/// A 502 error if the server could not be reached or did not answer
+/// A 504 error if the server took longer than `backend_timeout` to accept or to start answering
+async fn proxy_forward(backend: SocketAddr, request: &Request, server_request: &str, stale: Option<&Cached>, mut file: Option<&mut UploadBody>, stream: &mut TcpStream, state: &ProxyState) -> Result<bool, ProxyError> {
+    let config = &state.config;
+    //Taken before the request leaves, so an answer that crosses a purge is not stored
+    let generation = state.cache.generation();
+    let write_timeout = Duration::from_secs(config.write_timeout);
+    let backend_timeout = Duration::from_secs(config.backend_timeout);
+    //Idle connections are kept as blocking sockets, and must be made non-blocking to be waited on
+    let mut reused = Some(backend).filter(|_| file.as_deref().is_none_or(UploadBody::is_replayable))
+        .and_then(|backend| state.keep_alive.take(&backend))
+        .filter(|server_stream| server_stream.set_nonblocking(true).is_ok())
+        .and_then(|server_stream| TcpStream::from_std(server_stream).ok());
+    let (mut server_stream, (response_head, mut body_start)) = loop {
+        let is_reused = reused.is_some();
+        let mut server_stream = match reused.take() {
+            Some(server_stream) => server_stream,
+            None => match tokio::time::timeout(backend_timeout, TcpStream::connect(backend)).await {
+                Ok(connected) => connected.map_err(|e| backend_error(backend, "connect", e))?,
+                Err(_) => return Err(ProxyError::GatewayTimeout(format!("{}, connect", backend)))
+            }
+        };
+        match exchange(&mut server_stream, backend, server_request, file.as_deref_mut(), stream, state).await {
+            Ok(answer) => break (server_stream, answer),
+            //The server closes idle connections, so a reused one may be gone before the request was read
+            Err(ProxyError::BadGateway(cause)) if is_reused => report(format!("{} on a reused connection >>> Opening a new one", cause)),
+            Err(e) => return Err(e)
+        }
+    };
 
-    io::copy(&mut server_stream, &mut stream).unwrap();
-    report(format!("Received answer from Server >>> Passing forward to Client"));
-    stream.flush().unwrap();
-    server_stream.shutdown(std::net::Shutdown::Both).unwrap();
+    let (length, server_keep_alive) = answer_framing(&response_head);
+    //Bytes past the body belong to no request, so such a connection is not reused
+    let reusable = server_keep_alive && length.is_some_and(|length| body_start.len() as u64 <= length);
+    if let Some(length) = length {
+        body_start.truncate(length as usize);
+    }
+    let mut remaining = length.map(|length| length - body_start.len() as u64);
+    //The client's connection only carries another request if the answer has a known end, and
+    //if the server did not answer before the whole upload was read from the client
+    let keep_alive = request.keep_alive && length.is_some() && file.as_deref().is_none_or(UploadBody::is_read);
+
+    if let Some(stale) = stale.filter(|_| response_head.split_whitespace().nth(1) == Some("304")) {
+        let cached = state.cache.refresh(request, stale, &response_head, generation);
+        report(format!("Server confirmed ({}) has not changed >>> Answering from the cache", request.uri));
+        write_all(stream, &cached_response(state, request, &cached, None, keep_alive), write_timeout).await?;
+    } else {
+        //Security headers are added to the server's response head, the body passes untouched
+        let fresh_for = state.cache.storable(request, &response_head, length);
+        let client_response_head = client_head(&response_head, keep_alive);
+        write_all(stream, state.security_headers.inject(&client_response_head, &request.uri, request.forwarded_tls).as_bytes(), write_timeout).await?;
+        write_all(stream, &body_start, write_timeout).await?;
+        let mut captured = fresh_for.map(|_| body_start.clone());
+
+        //Each read from the server may take up to body_timeout
+        let body_timeout = Duration::from_secs(config.body_timeout);
+        let mut buffer = [0; 8192];
+        while remaining != Some(0) {
+            let limit = remaining.map_or(buffer.len(), |remaining| remaining.min(buffer.len() as u64) as usize);
+            //The answer has already started, so a failure from here on can only close the connection
+            let bytes_read = match tokio::time::timeout(body_timeout, server_stream.read(&mut buffer[..limit])).await {
+                Ok(Ok(0)) if remaining.is_some() => return Err(ProxyError::BackendFailed(format!("Server ({}) closed the connection before the end of its answer", backend))),
+                Ok(Ok(0)) => break,
+                Ok(Ok(bytes_read)) => bytes_read,
+                Ok(Err(e)) => return Err(ProxyError::BackendFailed(format!("Could not read the answer of the server ({}): {}", backend, e))),
+                Err(_) => return Err(ProxyError::BackendTimeout("response body".to_string()))
+            };
+            write_all(stream, &buffer[..bytes_read], write_timeout).await?;
+            if let Some(body) = captured.as_mut() {
+                body.extend_from_slice(&buffer[..bytes_read]);
+            }
+            remaining = remaining.map(|remaining| remaining - bytes_read as u64);
+        }
+        report("Received answer from Server >>> Passing forward to Client".to_string());
+
+        if let (Some(fresh_for), Some(body)) = (fresh_for, captured)
+            && remaining == Some(0) {
+            state.cache.store(request, client_response_head, body, fresh_for, generation);
+        }
+    }
+
+    if reusable && remaining == Some(0) {
+        if let Ok(server_stream) = server_stream.into_std() {
+            state.keep_alive.put(backend, server_stream);
+        }
+    } else {
+        let _ = server_stream.shutdown().await;
+    }
+
+    Ok(keep_alive)
+}
+
+/// Sends a signed request on a connection to the server and reads the head of its answer
+///
+/// # Arguments
+/// * `server_stream: &mut TcpStream` - Connection to the server, new or reused.
+/// * `backend: SocketAddr` - Address of the server.
+/// * `server_request: &str` - Signed request head, already formatted.
+/// * `upload: Option<&mut UploadBody>` - Body of an upload, sent after the head.
+/// * `client: &mut TcpStream` - Connection to the client, which the rest of a streamed upload comes from.
+/// * `state: &ProxyState` - Proxy's state, which holds the timeouts.
+///
+/// ## Returns
+/// The response head and the bytes of the body that were read along with it
+async fn exchange(server_stream: &mut TcpStream, backend: SocketAddr, server_request: &str, upload: Option<&mut UploadBody>, client: &mut TcpStream, state: &ProxyState) -> Result<(String, Vec<u8>), ProxyError> {
+    let backend_timeout = Duration::from_secs(state.config.backend_timeout);
+    let sent = match upload {
+        Some(UploadBody::Spooled(file)) => send_upload(server_stream, backend, server_request, file, state).await,
+        Some(UploadBody::Streamed(body)) => stream_upload(server_stream, backend, server_request, body, client, state).await,
+        None => write_all(server_stream, server_request.as_bytes(), Duration::from_secs(state.config.write_timeout)).await
+            .map_err(|e| backend_error(backend, "send the request", e))
+    };
+
+    //A server that refuses an upload answers before reading all of it, and stops reading
+    if let Err(ProxyError::BadGateway(cause)) = sent {
+        return match http::read_head_async(server_stream, backend_timeout, backend_timeout).await {
+            Ok((head, body_start)) if !head.is_empty() => {
+                report("Server answered before the whole request was sent >>> Passing its answer forward".to_string());
+                Ok((head, body_start))
+            },
+            _ => Err(ProxyError::BadGateway(cause))
+        };
+    }
+    sent?;
+
+    report("Request successfuly forwarded".to_string());
+
+    server_answer(backend, http::read_head_async(server_stream, backend_timeout, backend_timeout).await)
+}
+
+/// Sends the head of an upload to the server, followed by its file
+///
+/// # Arguments
+/// * `server_stream: &mut TcpStream` - Connection to the server.
+/// * `backend: SocketAddr` - Address of the server.
+/// * `server_request: &str` - Signed request head, already formatted.
+/// * `file: &mut Spool` - File of the upload, read from its start.
+/// * `state: &ProxyState` - Proxy's state, which holds the timeouts.
+///
+/// ## Returns
+/// Nothing if the whole request reached the server
+/// A 503 error if the file could not be read back from disk
+async fn send_upload(server_stream: &mut TcpStream, backend: SocketAddr, server_request: &str, file: &mut Spool, state: &ProxyState) -> Result<(), ProxyError> {
+    let write_timeout = Duration::from_secs(state.config.write_timeout);
+    let spool_error = |e: io::Error| ProxyError::Unavailable(format!("Could not read the upload back from disk: {}", e));
+    file.rewind().await.map_err(spool_error)?;
+    write_all(server_stream, server_request.as_bytes(), write_timeout).await.map_err(|e| backend_error(backend, "send the request", e))?;
+
+    let mut buffer = [0; 8192];
+    loop {
+        let bytes_read = file.read(&mut buffer).await.map_err(spool_error)?;
+        if bytes_read == 0 {
+            return Ok(());
+        }
+        write_all(server_stream, &buffer[..bytes_read], write_timeout).await.map_err(|e| backend_error(backend, "send the upload", e))?;
+    }
+}
+
+/// Sends the head of an upload to the server, followed by its file as it arrives from the client
+///
+/// The file is sent in chunks, and only the last one tells the server that it ended, once the whole body
+/// arrived and was checked. A client that fails before that leaves the server with a cut body, which it
+/// throws away when the connection is closed.
+///
+/// # Arguments
+/// * `server_stream: &mut TcpStream` - Connection to the server.
+/// * `backend: SocketAddr` - Address of the server.
+/// * `server_request: &str` - Signed request head, already formatted.
+/// * `body: &mut Streamed` - Rest of the upload body, read from the client.
+/// * `client: &mut TcpStream` - Connection to the client.
+/// * `state: &ProxyState` - Proxy's state, which holds the timeouts.
+///
+/// ## Returns
+/// Nothing if the whole request reached the server
+/// A 400 error if the rest of the body is not an upload the server can take
+async fn stream_upload(server_stream: &mut TcpStream, backend: SocketAddr, server_request: &str, body: &mut Streamed, client: &mut TcpStream, state: &ProxyState) -> Result<(), ProxyError> {
+    let write_timeout = Duration::from_secs(state.config.write_timeout);
+    let body_timeout = Duration::from_secs(state.config.body_timeout);
+    let Some(parser) = body.parser.as_mut() else {
+        return Err(ProxyError::BadRequest("Upload body was already sent".to_string()));
+    };
+    write_all(server_stream, server_request.as_bytes(), write_timeout).await.map_err(|e| backend_error(backend, "send the request", e))?;
+
+    let mut buffer = [0; 8192];
+    loop {
+        if !body.pending.is_empty() {
+            let mut chunk = format!("{:x}\r\n", body.pending.len()).into_bytes();
+            chunk.append(&mut body.pending);
+            chunk.extend_from_slice(b"\r\n");
+            write_all(server_stream, &chunk, write_timeout).await.map_err(|e| backend_error(backend, "send the upload", e))?;
+        }
+        if body.received >= body.size {
+            break;
+        }
+        let wanted = buffer.len().min((body.size - body.received) as usize);
+        let bytes_read = http::read_some_async(client, &mut buffer[..wanted], body_timeout).await?;
+        body.received += bytes_read as u64;
+        body.pending = parser.feed(&buffer[..bytes_read]).map_err(ProxyError::BadRequest)?;
+    }
+
+    //Only a body that ended with its closing boundary gets the last chunk, anything else is cut
+    if let Some(parser) = body.parser.take() {
+        parser.finish().map_err(ProxyError::BadRequest)?;
+    }
+    write_all(server_stream, b"0\r\n\r\n", write_timeout).await.map_err(|e| backend_error(backend, "send the upload", e))
+}
+
+/// Reads how the server delimits its answer
+///
+/// # Arguments
+/// * `head: &str` - Response head of the server.
+///
+/// ## Returns
+/// The size of the body, None when it lasts until the server closes the connection
+/// Whether the server keeps the connection open after the answer
+fn answer_framing(head: &str) -> (Option<u64>, bool) {
+    //These answers never have a body, whatever their headers say
+    let bodiless = head.split_whitespace().nth(1).is_some_and(|status| status.starts_with('1') || status == "204" || status == "304");
+    let mut length = None;
+    let mut keep_alive = false;
+    for (name, value) in head.lines().skip(1).filter_map(|line| line.split_once(':')) {
+        if name.trim().eq_ignore_ascii_case("Content-Length") {
+            length = value.trim().parse().ok();
+        } else if name.trim().eq_ignore_ascii_case("Connection") {
+            keep_alive = value.trim().eq_ignore_ascii_case("keep-alive");
+        }
+    }
+
+    (if bodiless { Some(0) } else { length }, keep_alive)
+}
+
+/// Turns a response head into the one sent to the client, which tells whether its connection stays open
+///
+/// # Arguments
+/// * `head: &str` - Response head of the server, or of the proxy itself.
+/// * `keep_alive: bool` - Whether the client's connection stays open after the answer.
+fn client_head(head: &str, keep_alive: bool) -> String {
+    let mut lines = head.trim_end_matches("\r\n").split("\r\n");
+    let mut output = format!("{}\r\n", lines.next().unwrap_or_default());
+    //Keeping the connection to the server open says nothing about the one with the client
+    for line in lines.filter(|line| !line.split_once(':').is_some_and(|(name, _)| name.trim().eq_ignore_ascii_case("Connection"))) {
+        output.push_str(line);
+        output.push_str("\r\n");
+    }
+    output.push_str(if keep_alive { "Connection: keep-alive\r\n\r\n" } else { "Connection: close\r\n\r\n" });
+
+    output
+}
+
+/// Turns a failure while reaching the server, before its answer has started, into the proxy's errors
+///
+/// # Arguments
+/// * `backend: SocketAddr` - Address of the server.
+/// * `action: &str` - What the proxy was doing, like ```connect```.
+/// * `e: io::Error` - What went wrong.
+fn backend_error(backend: SocketAddr, action: &str, e: io::Error) -> ProxyError {
+    if http::is_timeout(&e) {
+        ProxyError::GatewayTimeout(format!("{}, {}", backend, action))
+    } else {
+        ProxyError::BadGateway(format!("Could not {} to the server ({}): {}", action, backend, e))
+    }
+}
+
+/// Turns the result of reading the server's response head into the proxy's errors
+///
+/// # Arguments
+/// * `backend: SocketAddr` - Address of the server.
+/// * `head: Result<(String, Vec<u8>), http::Error>` - What was read from the server.
+fn server_answer(backend: SocketAddr, head: Result<(String, Vec<u8>), http::Error>) -> Result<(String, Vec<u8>), ProxyError> {
+    match head {
+        Ok((head, _)) if head.is_empty() => Err(ProxyError::BadGateway(format!("Server ({}) closed the connection without answering", backend))),
+        Ok(head) => Ok(head),
+        Err(http::Error::Timeout(http::Timeout::Idle)) => Err(ProxyError::GatewayTimeout(format!("{}, waiting for the answer", backend))),
+        Err(http::Error::Timeout(_)) => Err(ProxyError::GatewayTimeout(format!("{}, answer head", backend))),
+        Err(http::Error::Io(e)) => Err(ProxyError::BadGateway(format!("Could not read the answer of the server ({}): {}", backend, e))),
+        Err(e @ (http::Error::Malformed(_) | http::Error::TooLarge(_) | http::Error::HeadTooLarge(_))) => Err(ProxyError::BadGateway(format!("Server ({}) sent a broken answer: {}", backend, e)))
+    }
 }
 
 fn main() {
-    let listener = TcpListener::bind("0.0.0.0:2006").unwrap();
+    let listener = match TcpListener::bind("0.0.0.0:2006") {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("[{}] {} {} >> {}", "REVERSE PROXY".red(), "::".yellow(), "Could not listen at 0.0.0.0:2006".red(), e);
+            std::process::exit(1);
+        }
+    };
+
+    let config = Config::load("./proxy.conf");
+    spool::clean_temp();
+    let limiter = RateLimiter::new(&config);
+    let ip_filter = IpFilter::new(config.allow.clone(), config.deny.clone());
+    let security_headers = SecurityHeaders::new(&config);
+
+    let balancer = Balancer::new(&config);
+    let keep_alive = KeepAlive::new(&config);
+    let cache = ResponseCache::new(&config);
+    let state = Arc::new(ProxyState { secrets: Secrets::default(), health: Health::default(), balancer, keep_alive, cache, config, limiter, ip_filter, security_headers, pages: load_pages(), timed_out: AtomicU64::new(0) });
+
+    let shutdown = match Shutdown::install(SocketAddr::from(([127, 0, 0, 1], 2006)), report) {
+        Ok(shutdown) => shutdown,
+        Err(e) => {
+            eprintln!("[{}] {} {} >> {}", "REVERSE PROXY".red(), "::".yellow(), "Could not handle shutdown signals".red(), e);
+            std::process::exit(1);
+        }
+    };
+    let deadline = Duration::from_secs(state.config.shutdown_timeout);
 
-    report(format!("Initialized at {}", listener.local_addr().unwrap()));
+    health::start(Arc::clone(&state), Arc::clone(&shutdown));
+
+    let (still_open, unfinished) = if state.config.io_mode == IoMode::Async {
+        report(format!("Initialized at 0.0.0.0:2006 >>> event loop with {} threads", state.config.workers));
+        match event_loop::run(listener, Arc::clone(&state), Arc::clone(&shutdown)) {
+            Ok(still_open) => (still_open, "connections"),
+            Err(e) => {
+                eprintln!("[{}] {} {} >> {}", "REVERSE PROXY".red(), "::".yellow(), "Event loop has stopped".red(), e);
+                std::process::exit(1);
+            }
+        }
+    } else {
+        //Creates new pointer to the proxy's state, shared by every worker
+        let state_clone = Arc::clone(&state);
+        let shutdown_clone = Arc::clone(&shutdown);
+        let pool = WorkerPool::new(state.config.workers, state.config.queue_size, move |stream| {
+            event_loop::serve(stream, Arc::clone(&state_clone), Arc::clone(&shutdown_clone));
+        }, report);
+
+        report(format!("Initialized at 0.0.0.0:2006 >>> {} workers", pool.size()));
+
+        for stream in listener.incoming() {
+            //The signal handler connects once to wake this loop up
+            if shutdown.is_requested() {
+                break;
+            }
+            //A connection that failed before being accepted does not stop the proxy
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    report(format!("Could not accept a connection: {}", e));
+                    continue;
+                }
+            };
+            //Connections beyond the queue are refused right away instead of spawning more threads
+            if let Err(mut stream) = pool.submit(stream) {
+                let client = stream.peer_addr().map(|a| a.ip().to_string()).unwrap_or("N/A".to_string());
+                let _ = stream.set_write_timeout(Some(Duration::from_secs(1)));
+                let error = ProxyError::Unavailable(format!("Client ({}) was refused, every worker is busy and the queue is full", client));
+                if let Some(response) = error_response(&state, &format!("Client ({})", client), &error) {
+                    let _ = stream.write_all(response.as_bytes());
+                }
+            }
+        }
 
-    //Initializes the smart pointer that will hold the secret_key
-    let secret_state: SharedSecret = Arc::new(Mutex::new(None));
+        //New connections are refused from now on, the queued ones get until the deadline
+        drop(listener);
+        report("Shutting down >>> Waiting for open connections to finish".to_string());
+        //Counts the workers still busy, each one holding a connection
+        (pool.shutdown(deadline), "busy workers")
+    };
 
-    for stream in listener.incoming() {
-        let stream = stream.unwrap();
-        //Creates new pointer to secret_state
-        let secret_state_clone = Arc::clone(&secret_state);
-        thread::spawn(move || {
-            proxy_handler(stream, secret_state_clone);
-        });
+    if still_open > 0 {
+        report(format!("{} {} did not finish in {}s >>> Closing them", still_open, unfinished, deadline.as_secs()));
     }
+    report("Proxy has stopped".to_string());
+    let _ = io::stdout().flush();
 }
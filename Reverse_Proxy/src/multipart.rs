@@ -0,0 +1,353 @@
+/// Maximum size of the headers of a part, or of a field that is not the file, in bytes
+const MAX_PART: usize = 16 * 1024;
+
+/// Fields of an upload, which reach the server as headers
+///
+/// # Arguments
+/// * `file_name` - Name of the uploaded file.
+/// * `csrf_token` - CSRF token of the form, wherever its field is in the body.
+/// * `length` - Size of the file content, in bytes, unknown while the file is passed on as it arrives.
+pub struct Upload {
+    pub file_name: String,
+    pub csrf_token: Option<String>,
+    pub length: Option<u64>,
+}
+
+/// Reads a parameter out of a header value, like ```name``` in ```form-data; name="x"```
+///
+/// # Arguments
+/// * `header: &str` - Header value.
+/// * `parameter: &str` - Parameter's name.
+fn header_parameter(header: &str, parameter: &str) -> Option<String> {
+    header.split(';')
+        .filter_map(|p| p.trim().split_once('='))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case(parameter))
+        .map(|(_, value)| value.trim().trim_matches('"').to_string())
+}
+
+/// Finds where a sequence of bytes first appears
+///
+/// # Arguments
+/// * `haystack: &[u8]` - Bytes that are searched.
+/// * `needle: &[u8]` - Bytes that are looked for.
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+/// Reads the boundary between the parts of a ```multipart/form-data``` body
+///
+/// # Arguments
+/// * `content_type: &str` - Request's Content-Type header.
+pub fn boundary(content_type: &str) -> Option<String> {
+    header_parameter(content_type, "boundary").filter(|boundary| !boundary.is_empty())
+}
+
+/// Where a [`Parser`] is in the body of an upload
+///
+/// * `Preamble` - Before the first boundary, which is ignored.
+/// * `Boundary` - Right after a boundary, which either closes the body or starts a part.
+/// * `Headers` - In the headers of a part.
+/// * `Field` - In the content of a field that is not the file, kept when it is the CSRF token.
+/// * `File` - In the content of the file.
+/// * `Epilogue` - After the closing boundary, which is ignored.
+#[derive(Clone, Copy)]
+enum State {
+    Preamble,
+    Boundary,
+    Headers,
+    Field(bool),
+    File,
+    Epilogue,
+}
+
+/// Reads the body of an upload as it arrives, whatever the order of its parts, and passes its file on
+///
+/// Only the CSRF token is kept, the file is passed on and the other fields are skipped, so the body
+/// is never held whole. The last bytes read may be the start of a boundary split between two reads,
+/// so they are held until the next ones arrive.
+///
+/// # Arguments
+/// * `delimiter` - Boundary between the parts, along with the line break before it.
+/// * `state` - Where the parser is in the body.
+/// * `held` - Bytes that arrived but were not parsed yet.
+/// * `field` - Content of the field being read, when it is the CSRF token.
+/// * `part_size` - Bytes of the headers or of the field being read so far.
+/// * `file_name` - Name of the file, once its part started.
+/// * `csrf_token` - CSRF token of the form, once its field ended.
+/// * `length` - Bytes of the file passed on so far.
+pub struct Parser {
+    delimiter: Vec<u8>,
+    state: State,
+    held: Vec<u8>,
+    field: Vec<u8>,
+    part_size: usize,
+    file_name: Option<String>,
+    csrf_token: Option<String>,
+    length: u64,
+}
+
+impl Parser {
+    /// # Arguments
+    /// * `boundary: &str` - Boundary between the parts, from [`boundary`].
+    pub fn new(boundary: &str) -> Parser {
+        Parser {
+            delimiter: format!("\r\n--{}", boundary).into_bytes(),
+            state: State::Preamble,
+            //The first boundary may start the body, without a line break before it
+            held: b"\r\n".to_vec(),
+            field: Vec::new(),
+            part_size: 0,
+            file_name: None,
+            csrf_token: None,
+            length: 0,
+        }
+    }
+
+    /// Takes the next bytes of the body
+    ///
+    /// # Arguments
+    /// * `bytes: &[u8]` - Bytes that arrived.
+    ///
+    /// ## Returns
+    /// The bytes of the file among them, which may be passed on
+    /// A String with the reason if the body is not an upload the server can take
+    pub fn feed(&mut self, bytes: &[u8]) -> Result<Vec<u8>, String> {
+        self.held.extend_from_slice(bytes);
+        let mut file = Vec::new();
+        let mut at = 0;
+
+        loop {
+            let rest = &self.held[at..];
+            match self.state {
+                State::Preamble | State::Field(_) | State::File => {
+                    let (end, found) = match find(rest, &self.delimiter) {
+                        Some(end) => (end, true),
+                        None => (rest.len().saturating_sub(self.delimiter.len() - 1), false)
+                    };
+                    let content = &rest[..end];
+                    match self.state {
+                        State::File => {
+                            file.extend_from_slice(content);
+                            self.length += end as u64;
+                        },
+                        State::Field(keep) => {
+                            self.part_size += end;
+                            if self.part_size > MAX_PART {
+                                return Err(format!("Upload has a field of more than {} bytes", MAX_PART));
+                            }
+                            if keep {
+                                self.field.extend_from_slice(content);
+                            }
+                        },
+                        _ => {}
+                    }
+                    at += end;
+                    if !found {
+                        break;
+                    }
+
+                    at += self.delimiter.len();
+                    if let State::Field(true) = self.state {
+                        self.csrf_token = Some(String::from_utf8_lossy(&self.field).trim().to_string());
+                    }
+                    self.state = State::Boundary;
+                },
+                State::Boundary => {
+                    if rest.starts_with(b"--") {
+                        self.state = State::Epilogue;
+                        continue;
+                    }
+                    //Spaces may follow a boundary before its line break
+                    let padding = rest.iter().take_while(|&&byte| byte == b' ' || byte == b'\t').count();
+                    match rest.get(padding..padding + 2) {
+                        Some(b"\r\n") => {
+                            at += padding + 2;
+                            self.state = State::Headers;
+                        },
+                        Some(_) => return Err("Upload has a malformed boundary line".to_string()),
+                        None if rest.len() > MAX_PART => return Err("Upload has a malformed boundary line".to_string()),
+                        None => break
+                    }
+                },
+                State::Headers => {
+                    //A part without headers has its blank line right away
+                    let end = if rest.starts_with(b"\r\n") { Some(0) } else { find(rest, b"\r\n\r\n").map(|end| end + 2) };
+                    let Some(end) = end else {
+                        if rest.len() > MAX_PART {
+                            return Err(format!("Upload has a part with more than {} bytes of headers", MAX_PART));
+                        }
+                        break;
+                    };
+                    let headers = String::from_utf8_lossy(&rest[..end]);
+                    let disposition = headers.lines()
+                        .filter_map(|line| line.split_once(':'))
+                        .find(|(name, _)| name.trim().eq_ignore_ascii_case("Content-Disposition"))
+                        .map(|(_, value)| value.to_string())
+                        .unwrap_or_default();
+                    at += end + 2;
+
+                    if let Some(file_name) = header_parameter(&disposition, "filename") {
+                        if self.file_name.is_some() {
+                            return Err("Upload has more than one file".to_string());
+                        }
+                        self.file_name = Some(file_name);
+                        self.state = State::File;
+                    } else {
+                        self.field.clear();
+                        self.part_size = 0;
+                        self.state = State::Field(header_parameter(&disposition, "name").as_deref() == Some("csrf_token"));
+                    }
+                },
+                State::Epilogue => {
+                    at = self.held.len();
+                    break;
+                }
+            }
+        }
+        self.held.drain(..at);
+
+        Ok(file)
+    }
+
+    /// Reads the fields of the upload as soon as its file starts, before the rest of the body arrives
+    ///
+    /// ## Returns
+    /// The fields read so far, without the length of the file
+    /// None while the file has not started
+    pub fn started(&self) -> Option<Upload> {
+        self.file_name.as_ref().map(|file_name| Upload { file_name: file_name.clone(), csrf_token: self.csrf_token.clone(), length: None })
+    }
+
+    /// Checks how the body ended, once all of it arrived
+    ///
+    /// ## Returns
+    /// The fields that reach the server as headers
+    /// A String with the reason if the body is not an upload the server can take
+    pub fn finish(self) -> Result<Upload, String> {
+        let Some(file_name) = self.file_name else {
+            return Err("Upload without any file".to_string());
+        };
+        if !matches!(self.state, State::Epilogue) {
+            return Err("Upload body does not end with its closing boundary".to_string());
+        }
+
+        Ok(Upload { file_name, csrf_token: self.csrf_token, length: Some(self.length) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BOUNDARY: &str = "XyZb0und";
+
+    /// Feeds a body to a parser a few bytes at a time, like reads from a socket
+    fn parse(body: &[u8], chunk: usize) -> Result<(Upload, Vec<u8>), String> {
+        let mut parser = Parser::new(BOUNDARY);
+        let mut file = Vec::new();
+        for bytes in body.chunks(chunk) {
+            file.extend(parser.feed(bytes)?);
+        }
+        Ok((parser.finish()?, file))
+    }
+
+    fn field(name: &str, value: &str) -> String {
+        format!("--{}\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n", BOUNDARY, name, value)
+    }
+
+    fn file(name: &str, content: &str) -> String {
+        format!("--{}\r\nContent-Disposition: form-data; name=\"file_name\"; filename=\"{}\"\r\nContent-Type: text/plain\r\n\r\n{}\r\n", BOUNDARY, name, content)
+    }
+
+    fn closing() -> String {
+        format!("--{}--\r\n", BOUNDARY)
+    }
+
+    #[test]
+    fn reads_the_token_before_or_after_the_file() {
+        let before = format!("{}{}{}", field("csrf_token", "abc"), file("a.txt", "hello"), closing());
+        let after = format!("{}{}{}", file("a.txt", "hello"), field("csrf_token", "abc"), closing());
+        for body in [before, after] {
+            for chunk in [1, 3, 7, body.len()] {
+                let (upload, content) = parse(body.as_bytes(), chunk).unwrap();
+                assert_eq!(upload.file_name, "a.txt");
+                assert_eq!(upload.csrf_token.as_deref(), Some("abc"));
+                assert_eq!(upload.length, Some(5));
+                assert_eq!(content, b"hello");
+            }
+        }
+    }
+
+    #[test]
+    fn skips_other_fields_the_preamble_and_the_epilogue() {
+        let body = format!("preamble\r\n{}{}{}{}\r\nepilogue", field("note", "x"), file("b.txt", "data"), field("other", "y"), closing());
+        let (upload, content) = parse(body.as_bytes(), 2).unwrap();
+        assert_eq!(upload.csrf_token, None);
+        assert_eq!(content, b"data");
+    }
+
+    #[test]
+    fn accepts_a_closing_boundary_without_line_break_or_with_padding() {
+        for end in ["", "\r\n", "  \r\n\r\n"] {
+            let body = format!("{}--{}--{}", file("c.txt", "data"), BOUNDARY, end);
+            assert_eq!(parse(body.as_bytes(), 4).unwrap().1, b"data");
+        }
+        let padded = format!("--{} \t\r\nContent-Disposition: form-data; name=\"f\"; filename=\"d.txt\"\r\n\r\ndata\r\n{}", BOUNDARY, closing());
+        assert_eq!(parse(padded.as_bytes(), 5).unwrap().1, b"data");
+    }
+
+    #[test]
+    fn keeps_bytes_that_only_look_like_a_boundary() {
+        let content = format!("a\r\n--{}x\r\n--Xy", &BOUNDARY[..4]);
+        let body = format!("{}{}", file("e.bin", &content), closing());
+        for chunk in [1, 2, 5] {
+            assert_eq!(parse(body.as_bytes(), chunk).unwrap().1, content.as_bytes());
+        }
+    }
+
+    #[test]
+    fn tells_the_fields_as_soon_as_the_file_starts() {
+        let mut parser = Parser::new(BOUNDARY);
+        parser.feed(field("csrf_token", "abc").as_bytes()).unwrap();
+        assert!(parser.started().is_none());
+
+        let body = file("a.txt", "hello");
+        assert_eq!(parser.feed(&body.as_bytes()[..body.len() - 3]).unwrap(), b"");
+        let upload = parser.started().unwrap();
+        assert_eq!(upload.file_name, "a.txt");
+        assert_eq!(upload.csrf_token.as_deref(), Some("abc"));
+        assert_eq!(upload.length, None);
+    }
+
+    #[test]
+    fn refuses_a_body_without_closing_boundary() {
+        let open = format!("{}--{}\r\n", file("f.txt", "data"), BOUNDARY);
+        let cut = format!("--{}\r\nContent-Disposition: form-data; name=\"f\"; filename=\"f.txt\"\r\n\r\ndata", BOUNDARY);
+        for body in [open, cut] {
+            assert_eq!(parse(body.as_bytes(), 3).err().as_deref(), Some("Upload body does not end with its closing boundary"));
+        }
+    }
+
+    #[test]
+    fn refuses_bodies_without_one_single_file() {
+        let none = format!("{}{}", field("csrf_token", "abc"), closing());
+        assert_eq!(parse(none.as_bytes(), 8).err().as_deref(), Some("Upload without any file"));
+        let two = format!("{}{}{}", file("g.txt", "1"), file("h.txt", "2"), closing());
+        assert_eq!(parse(two.as_bytes(), 8).err().as_deref(), Some("Upload has more than one file"));
+    }
+
+    #[test]
+    fn refuses_oversized_fields_and_malformed_boundaries() {
+        let big = format!("{}{}{}", field("note", &"x".repeat(MAX_PART + 1)), file("i.txt", "1"), closing());
+        assert!(parse(big.as_bytes(), 1024).is_err());
+        let malformed = format!("--{}junk\r\n", BOUNDARY);
+        assert_eq!(parse(malformed.as_bytes(), 4).err().as_deref(), Some("Upload has a malformed boundary line"));
+    }
+
+    #[test]
+    fn reads_the_boundary_out_of_the_content_type() {
+        assert_eq!(boundary("multipart/form-data; boundary=\"abc\"").as_deref(), Some("abc"));
+        assert_eq!(boundary("multipart/form-data; boundary="), None);
+        assert_eq!(boundary("text/plain"), None);
+    }
+}
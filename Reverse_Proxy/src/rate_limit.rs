@@ -0,0 +1,235 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use crate::config::Config;
+
+/// Kind of request, each one has its own budget per client
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Budget {
+    Read,
+    Upload,
+}
+
+/// Token bucket of a single client and budget
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// Buckets of every client, along with the checks made since they were last swept
+struct Buckets {
+    map: HashMap<(IpAddr, Budget), Bucket>,
+    checks: u32,
+}
+
+/// Token bucket rate limiter keyed by client IP
+///
+/// Every client has a bucket per budget that refills at `rate` tokens per second
+/// up to `burst` tokens. Each request spends one token.
+///
+/// # Arguments
+/// * `buckets` - Buckets of the clients.
+/// * `read` - Rate and burst of the read budget.
+/// * `upload` - Rate and burst of the upload budget.
+/// * `max_buckets` - Most buckets kept at once, [`MAX_BUCKETS`] outside of tests.
+pub struct RateLimiter {
+    buckets: Mutex<Buckets>,
+    read: (f64, f64),
+    upload: (f64, f64),
+    max_buckets: usize,
+}
+
+/// Most buckets kept at once, the ones refilled the longest ago are forgotten past it
+const MAX_BUCKETS: usize = 10_000;
+
+/// Checks between two sweeps of the buckets that are full again
+const SWEEP_EVERY: u32 = 1_000;
+
+impl RateLimiter {
+    pub fn new(config: &Config) -> RateLimiter {
+        RateLimiter {
+            buckets: Mutex::new(Buckets { map: HashMap::new(), checks: 0 }),
+            read: (config.read_rate, config.read_burst),
+            upload: (config.upload_rate, config.upload_burst),
+            max_buckets: MAX_BUCKETS,
+        }
+    }
+
+    /// Forgets clients whose buckets would already be full again, as a new bucket starts full anyway
+    ///
+    /// # Arguments
+    /// * `map: &mut HashMap<(IpAddr, Budget), Bucket>` - Buckets of the clients.
+    /// * `now: Instant` - Time of the check that sweeps them.
+    fn sweep(&self, map: &mut HashMap<(IpAddr, Budget), Bucket>, now: Instant) {
+        map.retain(|(_, budget), bucket| {
+            let (rate, burst) = if *budget == Budget::Read { self.read } else { self.upload };
+            bucket.tokens + now.duration_since(bucket.last_refill).as_secs_f64() * rate < burst
+        });
+    }
+
+    /// Makes room for a new bucket once the cap is reached
+    ///
+    /// Full buckets go first. If that is not enough, the buckets refilled the longest ago are forgotten
+    /// until a tenth of the cap is free, so a flood of new clients pays for this once every many checks.
+    ///
+    /// # Arguments
+    /// * `map: &mut HashMap<(IpAddr, Budget), Bucket>` - Buckets of the clients.
+    /// * `now: Instant` - Time of the check that needs the room.
+    fn make_room(&self, map: &mut HashMap<(IpAddr, Budget), Bucket>, now: Instant) {
+        self.sweep(map, now);
+        let keep = self.max_buckets - self.max_buckets / 10;
+        if map.len() <= keep {
+            return;
+        }
+
+        let mut refills: Vec<Instant> = map.values().map(|bucket| bucket.last_refill).collect();
+        let excess = map.len() - keep;
+        let (_, newest_evicted, _) = refills.select_nth_unstable(excess - 1);
+        let newest_evicted = *newest_evicted;
+        let mut evicted = 0;
+        map.retain(|_, bucket| {
+            //Buckets refilled at the same instant as the last evicted one are only evicted while needed
+            let evict = bucket.last_refill < newest_evicted || (bucket.last_refill == newest_evicted && evicted < excess);
+            evicted += evict as usize;
+            !evict
+        });
+    }
+
+    /// Spends a token of a client's budget
+    ///
+    /// # Arguments
+    /// * `ip: IpAddr` - Client's IP.
+    /// * `budget: Budget` - Budget that the request uses.
+    ///
+    /// ## Returns
+    /// Nothing if the request is allowed
+    /// How long the client must wait before retrying if it is not
+    pub fn check(&self, ip: IpAddr, budget: Budget) -> Result<(), Duration> {
+        let (rate, burst) = match budget {
+            Budget::Read => self.read,
+            Budget::Upload => self.upload,
+        };
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let Buckets { map, checks } = &mut *buckets;
+
+        *checks += 1;
+        if *checks >= SWEEP_EVERY {
+            *checks = 0;
+            self.sweep(map, now);
+        }
+        if map.len() >= self.max_buckets && !map.contains_key(&(ip, budget)) {
+            self.make_room(map, now);
+        }
+
+        let bucket = map.entry((ip, budget)).or_insert(Bucket { tokens: burst, last_refill: now });
+        let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(burst);
+        bucket.last_refill = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else if rate > 0.0 {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / rate))
+        } else {
+            Err(Duration::from_secs(60))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(read: (f64, f64), upload: (f64, f64)) -> RateLimiter {
+        RateLimiter::new(&Config {
+            read_rate: read.0,
+            read_burst: read.1,
+            upload_rate: upload.0,
+            upload_burst: upload.1,
+            ..Config::default()
+        })
+    }
+
+    #[test]
+    fn allows_a_burst_then_refuses() {
+        let limiter = limiter((1.0, 3.0), (1.0, 1.0));
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+
+        for _ in 0..3 {
+            assert_eq!(limiter.check(ip, Budget::Read), Ok(()));
+        }
+        let wait = limiter.check(ip, Budget::Read).unwrap_err();
+        assert!(wait > Duration::ZERO && wait <= Duration::from_secs(1));
+    }
+
+    #[test]
+    fn keeps_budgets_and_clients_apart() {
+        let limiter = limiter((1.0, 1.0), (1.0, 1.0));
+        let first: IpAddr = "10.0.0.1".parse().unwrap();
+        let second: IpAddr = "10.0.0.2".parse().unwrap();
+
+        assert_eq!(limiter.check(first, Budget::Read), Ok(()));
+        assert!(limiter.check(first, Budget::Read).is_err());
+        assert_eq!(limiter.check(first, Budget::Upload), Ok(()));
+        assert_eq!(limiter.check(second, Budget::Read), Ok(()));
+    }
+
+    #[test]
+    fn refills_over_time() {
+        let limiter = limiter((50.0, 1.0), (1.0, 1.0));
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+
+        assert_eq!(limiter.check(ip, Budget::Read), Ok(()));
+        assert!(limiter.check(ip, Budget::Read).is_err());
+        std::thread::sleep(Duration::from_millis(40));
+        assert_eq!(limiter.check(ip, Budget::Read), Ok(()));
+    }
+
+    #[test]
+    fn sweeps_full_buckets_every_few_checks() {
+        let limiter = limiter((1000.0, 1.0), (0.0, 1.0));
+        for i in 0..100u8 {
+            let _ = limiter.check(IpAddr::from([10, 0, 0, i]), Budget::Read);
+        }
+        let _ = limiter.check("10.0.1.1".parse().unwrap(), Budget::Upload);
+        std::thread::sleep(Duration::from_millis(10));
+
+        //The read buckets are full again by the sweep, the upload one never refills
+        let ip: IpAddr = "10.0.1.2".parse().unwrap();
+        for _ in 0..SWEEP_EVERY {
+            let _ = limiter.check(ip, Budget::Read);
+        }
+        let buckets = limiter.buckets.lock().unwrap();
+        assert!(buckets.map.len() <= 2);
+        assert!(buckets.map.contains_key(&("10.0.1.1".parse().unwrap(), Budget::Upload)));
+    }
+
+    #[test]
+    fn never_keeps_more_buckets_than_the_cap() {
+        let mut limiter = limiter((0.0, 1.0), (0.0, 1.0));
+        limiter.max_buckets = 100;
+        let first: IpAddr = "10.0.0.1".parse().unwrap();
+        assert_eq!(limiter.check(first, Budget::Read), Ok(()));
+
+        for i in 0..1000u16 {
+            let _ = limiter.check(IpAddr::from([10, 1, (i >> 8) as u8, i as u8]), Budget::Read);
+            assert!(limiter.buckets.lock().unwrap().map.len() <= 100);
+        }
+        //The oldest client was forgotten, the latest ones are still limited
+        let latest = IpAddr::from([10, 1, 3, 231]);
+        assert!(limiter.check(latest, Budget::Read).is_err());
+        assert!(!limiter.buckets.lock().unwrap().map.contains_key(&(first, Budget::Read)));
+    }
+
+    #[test]
+    fn a_zero_rate_never_refills() {
+        let limiter = limiter((0.0, 1.0), (1.0, 1.0));
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+
+        assert_eq!(limiter.check(ip, Budget::Read), Ok(()));
+        assert_eq!(limiter.check(ip, Budget::Read), Err(Duration::from_secs(60)));
+    }
+}
@@ -0,0 +1,119 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use arc_swap::ArcSwap;
+
+/// Secret-keys registered by the servers, one per backend address
+///
+/// Requests read a snapshot of every key without taking a lock, so they never wait on each other.
+/// Changes build a new map and swap it in at once, so a panicking thread can not leave it half-written.
+///
+/// # Arguments
+/// * `keys` - Secret-key of each backend, by the address it listens at.
+#[derive(Default)]
+pub struct Secrets {
+    keys: ArcSwap<HashMap<SocketAddr, Arc<str>>>,
+}
+
+impl Secrets {
+    /// Returns the secret-key of a backend
+    ///
+    /// # Arguments
+    /// * `backend: &SocketAddr` - Address the backend listens at.
+    pub fn get(&self, backend: &SocketAddr) -> Option<Arc<str>> {
+        self.keys.load().get(backend).cloned()
+    }
+
+    /// Every backend that registered a key, along with it
+    pub fn backends(&self) -> Vec<(SocketAddr, Arc<str>)> {
+        self.keys.load().iter().map(|(backend, key)| (*backend, Arc::clone(key))).collect()
+    }
+
+    /// Whether a key is the one registered for a backend
+    ///
+    /// # Arguments
+    /// * `backend: &SocketAddr` - Address the backend listens at.
+    /// * `key: &str` - Key sent by the backend.
+    pub fn holds(&self, backend: &SocketAddr, key: &str) -> bool {
+        self.get(backend).is_some_and(|registered| &*registered == key)
+    }
+
+    /// Whether a registration may replace the key of a backend
+    ///
+    /// # Arguments
+    /// * `backend: &SocketAddr` - Address the backend listens at.
+    /// * `key: &str` - Key being registered.
+    /// * `signature: Option<&str>` - Key the registration was signed with, when it replaces the key with a new one.
+    ///
+    /// ## Returns
+    /// Whether the backend holds no key, or the registration was signed with the one it holds
+    pub fn may_replace(&self, backend: &SocketAddr, key: &str, signature: Option<&str>) -> bool {
+        self.get(backend).is_none_or(|registered| *registered == *signature.unwrap_or(key))
+    }
+
+    /// Registers the secret-key of a backend, replacing the one it had
+    ///
+    /// # Arguments
+    /// * `backend: SocketAddr` - Address the backend listens at.
+    /// * `key: &str` - Backend's secret-key.
+    pub fn register(&self, backend: SocketAddr, key: &str) {
+        let key: Arc<str> = Arc::from(key);
+        self.keys.rcu(|keys| {
+            let mut keys = HashMap::clone(keys);
+            keys.insert(backend, Arc::clone(&key));
+            keys
+        });
+    }
+
+    /// Removes the secret-key of a backend, as long as the given key is the registered one
+    ///
+    /// # Arguments
+    /// * `backend: &SocketAddr` - Address the backend listens at.
+    /// * `key: &str` - Backend's secret-key.
+    ///
+    /// ## Returns
+    /// Whether the key was removed
+    pub fn deregister(&self, backend: &SocketAddr, key: &str) -> bool {
+        let mut removed = false;
+        self.keys.rcu(|keys| {
+            let mut keys = HashMap::clone(keys);
+            removed = keys.get(backend).is_some_and(|registered| &**registered == key);
+            if removed {
+                keys.remove(backend);
+            }
+            keys
+        });
+        removed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_holder_of_a_key_may_replace_it() {
+        let secrets = Secrets::default();
+        let backend: SocketAddr = "127.0.0.1:1445".parse().unwrap();
+        assert!(secrets.may_replace(&backend, "first", None));
+        secrets.register(backend, "first");
+
+        assert!(!secrets.may_replace(&backend, "second", None));
+        assert!(!secrets.may_replace(&backend, "second", Some("wrong")));
+        assert!(secrets.may_replace(&backend, "second", Some("first")));
+        //Registering the same key again proves it is known
+        assert!(secrets.may_replace(&backend, "first", None));
+    }
+
+    #[test]
+    fn only_the_holder_of_a_key_may_remove_it() {
+        let secrets = Secrets::default();
+        let backend: SocketAddr = "127.0.0.1:1445".parse().unwrap();
+        secrets.register(backend, "first");
+
+        assert!(!secrets.deregister(&backend, "second"));
+        assert!(secrets.holds(&backend, "first"));
+        assert!(secrets.deregister(&backend, "first"));
+        assert_eq!(secrets.get(&backend), None);
+    }
+}
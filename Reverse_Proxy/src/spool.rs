@@ -0,0 +1,159 @@
+use std::fs;
+use std::io;
+use std::io::SeekFrom;
+use std::path::PathBuf;
+use rand::Rng;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+/// Folder where the files of uploads wait until their whole body arrived
+const TEMP_DIR: &str = "./tmp";
+
+/// Removes upload files left behind in the temporary folder by a crash
+pub fn clean_temp() {
+    if let Ok(files) = fs::read_dir(TEMP_DIR) {
+        for file in files.flatten() {
+            let _ = fs::remove_file(file.path());
+        }
+    }
+}
+
+/// File of an upload, kept on disk instead of in memory until it is sent to the server
+///
+/// Only an upload whose CSRF token comes after its file needs one: the token is only known once the
+/// whole body arrived, and it must be in the head that the server gets before the file.
+///
+/// # Arguments
+/// * `path` - Where the file is kept, removed when the spool is dropped.
+/// * `file` - Open file, written as the upload arrives and read when it is sent.
+pub struct Spool {
+    path: PathBuf,
+    file: tokio::fs::File,
+}
+
+impl Spool {
+    /// Creates an empty file in the temporary folder
+    pub async fn create() -> io::Result<Spool> {
+        tokio::fs::create_dir_all(TEMP_DIR).await?;
+        let path = PathBuf::from(format!("{}/{:016x}.part", TEMP_DIR, rand::rng().random::<u64>()));
+        let file = tokio::fs::OpenOptions::new().read(true).write(true).create_new(true).open(&path).await?;
+
+        Ok(Spool { path, file })
+    }
+
+    /// Adds bytes to the end of the file
+    ///
+    /// # Arguments
+    /// * `bytes: &[u8]` - Bytes of the file that arrived.
+    pub async fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.file.write_all(bytes).await
+    }
+
+    /// Goes back to the start of the file, before it is sent to a server
+    pub async fn rewind(&mut self) -> io::Result<()> {
+        self.file.flush().await?;
+        self.file.seek(SeekFrom::Start(0)).await.map(|_| ())
+    }
+
+    /// Reads the next bytes of the file
+    ///
+    /// # Arguments
+    /// * `buffer: &mut [u8]` - Where the bytes are placed.
+    ///
+    /// ## Returns
+    /// The amount of bytes read, 0 at the end of the file
+    pub async fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buffer).await
+    }
+}
+
+impl Drop for Spool {
+    fn drop(&mut self) {
+        //On an event loop the file is removed by a blocking thread, so no connection waits on the disk
+        let path = std::mem::take(&mut self.path);
+        match tokio::runtime::Handle::try_current() {
+            Ok(handle) => drop(handle.spawn_blocking(move || fs::remove_file(path))),
+            Err(_) => drop(fs::remove_file(path))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+    use std::sync::Mutex;
+    use std::time::{Duration, Instant};
+
+    /// Tests share the temporary folder, which [`clean_temp`] empties
+    static TEMP: Mutex<()> = Mutex::new(());
+
+    fn runtime() -> tokio::runtime::Runtime {
+        tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap()
+    }
+
+    /// Waits a moment for a file that a blocking thread removes
+    fn removed(path: &Path) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while path.exists() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        !path.exists()
+    }
+
+    #[test]
+    fn reads_back_what_was_written() {
+        let _temp = TEMP.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let spool = runtime().block_on(async {
+            let mut spool = Spool::create().await.unwrap();
+            spool.write(b"hello ").await.unwrap();
+            spool.write(&[b'x'; 10000]).await.unwrap();
+            spool.rewind().await.unwrap();
+
+            let mut content = Vec::new();
+            let mut buffer = [0; 4096];
+            loop {
+                let bytes_read = spool.read(&mut buffer).await.unwrap();
+                if bytes_read == 0 {
+                    break;
+                }
+                content.extend_from_slice(&buffer[..bytes_read]);
+            }
+            assert_eq!(&content[..6], b"hello ");
+            assert_eq!(content.len(), 10006);
+            spool
+        });
+
+        //Out of an event loop the file is gone as soon as the spool is
+        let path = spool.path.clone();
+        assert!(path.exists());
+        drop(spool);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn removes_the_file_when_dropped_on_an_event_loop() {
+        let _temp = TEMP.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let runtime = runtime();
+        let path = runtime.block_on(async {
+            let spool = Spool::create().await.unwrap();
+            spool.path.clone()
+        });
+        assert!(removed(&path));
+    }
+
+    #[test]
+    fn never_reuses_a_file_and_cleans_what_a_crash_left() {
+        let _temp = TEMP.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let runtime = runtime();
+        let (first, second) = runtime.block_on(async { (Spool::create().await.unwrap(), Spool::create().await.unwrap()) });
+        assert_ne!(first.path, second.path);
+
+        //A crash drops nothing, so its files stay until the next start
+        let paths = [first.path.clone(), second.path.clone()];
+        std::mem::forget(first);
+        std::mem::forget(second);
+        assert!(paths.iter().all(|path| path.exists()));
+        clean_temp();
+        assert!(paths.iter().all(|path| !path.exists()));
+    }
+}
@@ -0,0 +1,367 @@
+use std::collections::HashMap;
+use std::fs;
+use std::sync::Mutex;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use hmac::{Hmac, Mac};
+use pbkdf2::pbkdf2_hmac;
+use sha2::Sha256;
+use subtle::ConstantTimeEq;
+use shared::pattern::wildcard_match;
+use crate::report;
+
+/// PBKDF2 iterations of the password hashes made by ```--hash-password```
+const PBKDF2_ITERATIONS: u32 = 200_000;
+
+/// Most credentials remembered as verified, all of them are forgotten once it is reached
+const MAX_VERIFIED: usize = 1024;
+
+/// Operations that can be granted on files inside ```./data```
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Operation {
+    List,
+    Read,
+    Upload,
+    Delete,
+}
+
+impl Operation {
+    /// Turn a policy file word into an Operation
+    ///
+    /// # Arguments
+    /// * `word: &str` - Operation name as written in the policy file.
+    fn from_word(word: &str) -> Option<Operation> {
+        match word.trim() {
+            "list" => Some(Operation::List),
+            "read" => Some(Operation::Read),
+            "upload" => Some(Operation::Upload),
+            "delete" => Some(Operation::Delete),
+            _ => None
+        }
+    }
+}
+
+/// Client identity resolved from a request
+///
+/// # Arguments
+/// * `name` - User's name, ```anonymous``` when no valid credentials were sent.
+/// * `groups` - Groups the user belongs to.
+pub struct User {
+    pub name: String,
+    pub groups: Vec<String>,
+}
+
+impl User {
+    pub fn anonymous() -> User {
+        User { name: "anonymous".to_string(), groups: Vec::new() }
+    }
+
+    pub fn is_anonymous(&self) -> bool {
+        self.name == "anonymous"
+    }
+}
+
+/// Salted PBKDF2-HMAC-SHA256 hash of a password
+///
+/// # Arguments
+/// * `iterations` - Rounds of HMAC, which make every guess slow.
+/// * `salt` - Random bytes of the account, so equal passwords do not share a hash.
+/// * `hash` - Derived key.
+struct PasswordHash {
+    iterations: u32,
+    salt: Vec<u8>,
+    hash: Vec<u8>,
+}
+
+impl PasswordHash {
+    /// Reads a hash in the format ```pbkdf2-sha256$iterations$salt$hash```, with salt and hash in hex
+    ///
+    /// # Arguments
+    /// * `text: &str` - Hash as written in the users file.
+    fn parse(text: &str) -> Option<PasswordHash> {
+        let mut parts = text.trim().split('$');
+        if parts.next()? != "pbkdf2-sha256" {
+            return None;
+        }
+        let iterations = parts.next()?.parse().ok().filter(|&iterations| iterations > 0)?;
+        let salt = hex::decode(parts.next()?).ok()?;
+        let hash = hex::decode(parts.next()?).ok().filter(|hash| hash.len() == 32)?;
+
+        parts.next().is_none().then_some(PasswordHash { iterations, salt, hash })
+    }
+
+    /// Whether a password matches the hash, compared in constant time
+    ///
+    /// # Arguments
+    /// * `password: &str` - Password sent by the client.
+    fn verify(&self, password: &str) -> bool {
+        let mut derived = [0u8; 32];
+        pbkdf2_hmac::<Sha256>(password.as_bytes(), &self.salt, self.iterations, &mut derived);
+        derived.ct_eq(self.hash.as_slice()).into()
+    }
+}
+
+/// Hashes a password with a new random salt, in the format read from ```./users.txt```
+///
+/// # Arguments
+/// * `password: &str` - Password of the account.
+pub fn hash_password(password: &str) -> String {
+    let salt: [u8; 16] = rand::random();
+    let mut hash = [0u8; 32];
+    pbkdf2_hmac::<Sha256>(password.as_bytes(), &salt, PBKDF2_ITERATIONS, &mut hash);
+
+    format!("pbkdf2-sha256${}${}${}", PBKDF2_ITERATIONS, hex::encode(salt), hex::encode(hash))
+}
+
+/// Registered user, as stored in ```./users.txt```
+struct Account {
+    name: String,
+    password_hash: PasswordHash,
+    groups: Vec<String>,
+}
+
+/// Holds every account read from ```./users.txt```
+///
+/// Each line has the format ```name:pbkdf2-sha256$iterations$salt$hash:group1,group2```,
+/// where the hash is made by running the server with ```--hash-password```.
+///
+/// # Arguments
+/// * `accounts` - Accounts that were read.
+/// * `decoy` - Hash checked for unknown names, so they take as long to refuse as a wrong password.
+/// * `verified_key` - Random key of the HMAC that identifies credentials already verified.
+/// * `verified` - Account of each credentials already verified, so a client is not slowed down on every request.
+pub struct Users {
+    accounts: Vec<Account>,
+    decoy: PasswordHash,
+    verified_key: [u8; 32],
+    verified: Mutex<HashMap<Vec<u8>, usize>>,
+}
+
+impl Users {
+    /// Read accounts from a file. A missing file means no accounts at all.
+    ///
+    /// # Arguments
+    /// * `path: &str` - Path of the users file.
+    pub fn load(path: &str) -> Users {
+        let contents = fs::read_to_string(path).unwrap_or_default();
+        let mut accounts = Vec::new();
+
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut fields = line.splitn(3, ':');
+            let (Some(name), Some(password_hash)) = (fields.next(), fields.next()) else {
+                continue;
+            };
+            let Some(password_hash) = PasswordHash::parse(password_hash) else {
+                report(format!("Account ({}) in ({}) has no valid password hash >>> Skipping it", name.trim(), path));
+                continue;
+            };
+            let groups = fields.next().unwrap_or("")
+                .split(',')
+                .map(|g| g.trim().to_string())
+                .filter(|g| !g.is_empty())
+                .collect();
+
+            accounts.push(Account {
+                name: name.trim().to_string(),
+                password_hash,
+                groups
+            });
+        }
+
+        let decoy = PasswordHash { iterations: PBKDF2_ITERATIONS, salt: vec![0; 16], hash: vec![0; 32] };
+        Users { accounts, decoy, verified_key: rand::random(), verified: Mutex::new(HashMap::new()) }
+    }
+
+    /// Resolve the user of a request from its ```Authorization``` header value.
+    /// Anything that is not valid Basic credentials resolves to the anonymous user.
+    ///
+    /// # Arguments
+    /// * `authorization: Option<&str>` - Value of the Authorization header, if any.
+    pub fn authenticate(&self, authorization: Option<&str>) -> User {
+        let Some(encoded) = authorization.and_then(|a| a.trim().strip_prefix("Basic ")) else {
+            return User::anonymous();
+        };
+        let Ok(decoded) = STANDARD.decode(encoded.trim()) else {
+            return User::anonymous();
+        };
+        let credentials = String::from_utf8_lossy(&decoded);
+        let Some((name, password)) = credentials.split_once(':') else {
+            return User::anonymous();
+        };
+
+        //Only the keyed HMAC of credentials that were right is kept, never the password itself
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.verified_key).expect("HMAC takes keys of any size");
+        mac.update(credentials.as_bytes());
+        let credentials_mac = mac.finalize().into_bytes().to_vec();
+        let mut verified = self.verified.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some(&index) = verified.get(&credentials_mac) {
+            let account = &self.accounts[index];
+            return User { name: account.name.clone(), groups: account.groups.clone() };
+        }
+        drop(verified);
+
+        match self.accounts.iter().position(|a| a.name == name) {
+            Some(index) if self.accounts[index].password_hash.verify(password) => {
+                verified = self.verified.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+                if verified.len() >= MAX_VERIFIED {
+                    verified.clear();
+                }
+                verified.insert(credentials_mac, index);
+
+                let account = &self.accounts[index];
+                User { name: account.name.clone(), groups: account.groups.clone() }
+            },
+            Some(_) => User::anonymous(),
+            None => {
+                self.decoy.verify(password);
+                User::anonymous()
+            }
+        }
+    }
+}
+
+/// Who a policy rule applies to
+enum Subject {
+    Everyone,
+    Group(String),
+    User(String),
+}
+
+/// A single line of the policy file
+struct Rule {
+    subject: Subject,
+    operations: Vec<Operation>,
+    pattern: String,
+}
+
+/// Access control list read from ```./policy.txt```
+///
+/// Each line has the format ```subject operations pattern```, where:
+/// * `subject` - ```*``` for everyone, ```@group``` for a group or a user name.
+/// * `operations` - Comma separated list of ```list```, ```read```, ```upload``` and ```delete```.
+/// * `pattern` - Path under ```./data``` where ```*``` matches any sequence of characters.
+///
+/// Access is only granted when a rule allows it.
+pub struct Policy {
+    rules: Vec<Rule>,
+}
+
+impl Policy {
+    /// Read a policy from a file. A missing file grants nothing.
+    ///
+    /// # Arguments
+    /// * `path: &str` - Path of the policy file.
+    pub fn load(path: &str) -> Policy {
+        let contents = fs::read_to_string(path).unwrap_or_default();
+        let mut rules = Vec::new();
+
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut fields = line.split_whitespace();
+            let (Some(subject), Some(operations), Some(pattern)) = (fields.next(), fields.next(), fields.next()) else {
+                continue;
+            };
+
+            let subject = match subject {
+                "*" => Subject::Everyone,
+                s if s.starts_with('@') => Subject::Group(s[1..].to_string()),
+                s => Subject::User(s.to_string())
+            };
+            let operations = operations.split(',').filter_map(Operation::from_word).collect();
+
+            rules.push(Rule { subject, operations, pattern: pattern.to_string() });
+        }
+
+        Policy { rules }
+    }
+
+    /// Checks if an user may perform an operation over a path
+    ///
+    /// # Arguments
+    /// * `user: &User` - User that wants to perform the operation.
+    /// * `operation: Operation` - Operation that will be performed.
+    /// * `path: &str` - Path relative to ```./data```.
+    pub fn allows(&self, user: &User, operation: Operation, path: &str) -> bool {
+        //Paths escaping ./data can never be matched by a pattern
+        if path.starts_with('/') || path.split(['/', '\\']).any(|part| part == "..") {
+            return false;
+        }
+
+        self.rules.iter().any(|rule| {
+            let applies = match &rule.subject {
+                Subject::Everyone => true,
+                Subject::Group(g) => user.groups.contains(g),
+                Subject::User(u) => &user.name == u
+            };
+            applies && rule.operations.contains(&operation) && wildcard_match(&rule.pattern, path)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes a policy to a temporary file and loads it
+    fn policy(name: &str, contents: &str) -> Policy {
+        let path = std::env::temp_dir().join(format!("acl-test-{}-{}.txt", std::process::id(), name));
+        fs::write(&path, contents).unwrap();
+        let policy = Policy::load(path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+        policy
+    }
+
+    fn user(name: &str, groups: &[&str]) -> User {
+        User { name: name.to_string(), groups: groups.iter().map(|g| g.to_string()).collect() }
+    }
+
+    #[test]
+    fn policy_grants_by_subject() {
+        let policy = policy("subject", "\
+# comment
+*       list            *
+@staff  read,upload     reports/*
+alice   read,delete     private/alice/*
+");
+        let anonymous = User::anonymous();
+        let staff = user("bob", &["staff"]);
+        let alice = user("alice", &[]);
+
+        assert!(policy.allows(&anonymous, Operation::List, "anything.txt"));
+        assert!(!policy.allows(&anonymous, Operation::Read, "reports/q1.txt"));
+        assert!(policy.allows(&staff, Operation::Read, "reports/q1.txt"));
+        assert!(policy.allows(&staff, Operation::Upload, "reports/q1.txt"));
+        assert!(!policy.allows(&staff, Operation::Read, "private/alice/diary.txt"));
+        assert!(policy.allows(&alice, Operation::Read, "private/alice/diary.txt"));
+        assert!(policy.allows(&alice, Operation::Delete, "private/alice/diary.txt"));
+        assert!(!policy.allows(&staff, Operation::Delete, "reports/q1.txt"));
+        assert!(!policy.allows(&alice, Operation::Upload, "private/alice/diary.txt"));
+    }
+
+    #[test]
+    fn policy_refuses_escaping_paths() {
+        let policy = policy("escape", "* list,read,upload *\n");
+        let anonymous = User::anonymous();
+
+        assert!(policy.allows(&anonymous, Operation::Read, "notes.txt"));
+        assert!(!policy.allows(&anonymous, Operation::Read, "../secret.txt"));
+        assert!(!policy.allows(&anonymous, Operation::Read, "a/../../secret.txt"));
+        assert!(!policy.allows(&anonymous, Operation::Read, "a\\..\\secret.txt"));
+        assert!(!policy.allows(&anonymous, Operation::Read, "/etc/passwd"));
+    }
+
+    #[test]
+    fn policy_ignores_malformed_lines() {
+        let policy = policy("malformed", "* read\n* write *\n");
+
+        assert!(!policy.allows(&User::anonymous(), Operation::Read, "notes.txt"));
+        assert!(!Policy::load("./missing-policy.txt").allows(&User::anonymous(), Operation::List, "notes.txt"));
+    }
+}
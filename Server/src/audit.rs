@@ -0,0 +1,625 @@
+use std::fs::{self, File};
+use std::io;
+use std::io::prelude::*;
+use std::sync::Mutex;
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender};
+use std::thread::{self, JoinHandle};
+use std::time::{SystemTime, UNIX_EPOCH};
+use hmac::{Hmac, Mac};
+use sha2::{Sha256, Digest};
+use crate::report;
+
+/// Hash that the first entry of a log is chained to
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Most entries that may wait for the writer thread, recording waits for room past it
+const QUEUE_SIZE: usize = 4096;
+
+/// A file operation that will be written to the audit log
+///
+/// # Arguments
+/// * `client` - Client's IP.
+/// * `user` - User that made the request.
+/// * `operation` - Operation, like ```list```, ```read``` or ```upload```.
+/// * `path` - File or URI the operation was made on.
+/// * `content` - Size and hash of the content that was read or written.
+/// * `signature_valid` - Whether the request had a valid proxy signature.
+/// * `outcome` - How the request ended, like ```ok```, ```denied``` or ```refused```.
+pub struct Entry<'a> {
+    pub client: &'a str,
+    pub user: &'a str,
+    pub operation: &'a str,
+    pub path: &'a str,
+    pub content: &'a ContentHash,
+    pub signature_valid: bool,
+    pub outcome: &'a str,
+}
+
+/// Size and SHA-256 of the content of an operation, which may be hashed as it goes by
+///
+/// # Arguments
+/// * `hasher` - SHA-256 of the bytes so far.
+/// * `size` - Amount of bytes so far.
+#[derive(Default)]
+pub struct ContentHash {
+    hasher: Sha256,
+    size: u64,
+}
+
+impl ContentHash {
+    /// Hash of content that is whole in memory
+    ///
+    /// # Arguments
+    /// * `content: &[u8]` - Content that was read or written, empty when there is none.
+    pub fn of(content: &[u8]) -> ContentHash {
+        let mut hash = ContentHash::default();
+        hash.update(content);
+        hash
+    }
+
+    /// Adds the next bytes of the content
+    ///
+    /// # Arguments
+    /// * `bytes: &[u8]` - Bytes that went by.
+    pub fn update(&mut self, bytes: &[u8]) {
+        self.hasher.update(bytes);
+        self.size += bytes.len() as u64;
+    }
+
+    /// Amount of bytes of the content
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// SHA-256 of the content as hex, ```-``` when there is no content
+    fn hex(&self) -> String {
+        if self.size == 0 { "-".to_string() } else { hex::encode(self.hasher.clone().finalize()) }
+    }
+}
+
+/// Reader that hashes the content read through it, for content that is never whole in memory
+///
+/// # Arguments
+/// * `inner` - Reader of the content.
+/// * `hash` - Hash of what was read so far.
+pub struct Hashed<R> {
+    inner: R,
+    hash: ContentHash,
+}
+
+impl<R: Read> Hashed<R> {
+    /// # Arguments
+    /// * `inner: R` - Reader of the content.
+    pub fn new(inner: R) -> Hashed<R> {
+        Hashed { inner, hash: ContentHash::default() }
+    }
+
+    /// Hash of what was read so far
+    pub fn hash(&self) -> &ContentHash {
+        &self.hash
+    }
+}
+
+impl<R: Read> Read for Hashed<R> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let bytes_read = self.inner.read(buffer)?;
+        self.hash.update(&buffer[..bytes_read]);
+        Ok(bytes_read)
+    }
+}
+
+/// Next position of the chain
+struct Chain {
+    sequence: u64,
+    last_hash: String,
+}
+
+/// What the writer thread of a log is asked to do
+///
+/// * `Line` - Appends an entry, already chained.
+/// * `Flush` - Answers once every entry sent before it was written.
+enum Message {
+    Line(String),
+    Flush(Sender<()>),
+}
+
+/// Append-only, hash-chained audit log
+///
+/// Each line holds tab separated fields: sequence, timestamp (ms), client, user, operation, path,
+/// size, content SHA-256, signature, outcome, previous entry hash and the entry hash. The entry
+/// hash is the HMAC-SHA256 of every other field, keyed with ```<log>.key```, so changing, removing
+/// or reordering lines breaks the chain and only who holds the key can build a new one.
+/// The sequence and hash of the last entry are also kept in ```<log>.head```, so entries removed
+/// from the end are found too.
+///
+/// Entries are chained by the request that records them, but written by a thread of their own,
+/// so no request waits on the disk.
+///
+/// # Arguments
+/// * `key` - Key of the entries' HMAC.
+/// * `chain` - Next position of the chain.
+/// * `sender` - Queue of the writer thread, only taken away when the log is dropped.
+/// * `writer` - Handle of the writer thread.
+pub struct AuditLog {
+    key: Vec<u8>,
+    chain: Mutex<Chain>,
+    sender: Option<SyncSender<Message>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+/// Makes a value safe to be stored as a single field of a line
+///
+/// # Arguments
+/// * `value: &str` - Value that will be stored.
+fn escape_field(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('\t', "\\t").replace('\n', "\\n").replace('\r', "\\r")
+}
+
+/// HMAC-SHA256 of an entry's fields, as hex
+///
+/// # Arguments
+/// * `key: &[u8]` - Key of the log.
+/// * `fields: &str` - Every field of the entry but its hash.
+fn entry_hash(key: &[u8], fields: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(fields.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// Path of the file that holds the key of a log
+fn key_path(path: &str) -> String {
+    format!("{}.key", path)
+}
+
+/// Path of the file that holds the last sequence and hash of a log
+fn head_path(path: &str) -> String {
+    format!("{}.head", path)
+}
+
+/// Sequence and hash of an entry, if it is whole and its hash was made with the key of the log
+///
+/// # Arguments
+/// * `key: &[u8]` - Key of the log.
+/// * `line: &str` - Line of the entry.
+fn chained_entry(key: &[u8], line: &str) -> Option<(u64, String)> {
+    let (fields, hash) = line.rsplit_once('\t')?;
+    let values: Vec<&str> = fields.split('\t').collect();
+    if values.len() != 11 || entry_hash(key, fields) != hash {
+        return None;
+    }
+    Some((values[0].parse().ok()?, hash.to_string()))
+}
+
+/// Sequence and hash kept in the head of a log
+///
+/// # Arguments
+/// * `head: &str` - Contents of the head file.
+fn parse_head(head: &str) -> Option<(u64, String)> {
+    let (sequence, hash) = head.trim().split_once('\t')?;
+    if hash.len() != GENESIS_HASH.len() || !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    Some((sequence.parse().ok()?, hash.to_string()))
+}
+
+/// Appends a batch of entries to a log, then points its head at the last one
+///
+/// # Arguments
+/// * `path: &str` - Path of the log file.
+/// * `file: &mut Option<File>` - Log file, opened again after a failed write.
+/// * `lines: &[String]` - Entries, in the order of the chain.
+fn append(path: &str, file: &mut Option<File>, lines: &[String]) -> io::Result<()> {
+    let mut log = match file.take() {
+        Some(log) => log,
+        None => fs::OpenOptions::new().create(true).append(true).open(path)?
+    };
+    //One write for the whole batch, so a burst of requests costs what a single one does
+    let batch: String = lines.iter().map(|line| format!("{}\n", line)).collect();
+    log.write_all(batch.as_bytes())?;
+    log.flush()?;
+    *file = Some(log);
+
+    if let Some(last) = lines.last() {
+        let (sequence, hash) = (last.split('\t').next().unwrap_or_default(), last.rsplit('\t').next().unwrap_or_default());
+        if let Err(e) = fs::write(head_path(path), format!("{}\t{}\n", sequence, hash)) {
+            report(format!("Could not update the head of the audit log: {}", e));
+        }
+    }
+    Ok(())
+}
+
+/// Loop of the writer thread, which writes every entry waiting in the queue at once
+///
+/// Entries that could not be written are kept and written again along with the next ones,
+/// as the chain of the entries after them goes through them.
+///
+/// # Arguments
+/// * `path: String` - Path of the log file.
+/// * `receiver: Receiver<Message>` - Queue filled by [`AuditLog::record`].
+fn write_entries(path: String, receiver: Receiver<Message>) {
+    let mut file = None;
+    let mut pending = Vec::new();
+
+    while let Ok(message) = receiver.recv() {
+        let mut flushes = Vec::new();
+        let mut next = Some(message);
+        while let Some(message) = next {
+            match message {
+                Message::Line(line) => pending.push(line),
+                Message::Flush(done) => flushes.push(done)
+            }
+            next = receiver.try_recv().ok();
+        }
+
+        if !pending.is_empty() {
+            match append(&path, &mut file, &pending) {
+                Ok(()) => pending.clear(),
+                Err(e) => report(format!("Could not write to the audit log: {} >>> Retrying {} entries with the next ones", e, pending.len()))
+            }
+        }
+        for done in flushes {
+            let _ = done.send(());
+        }
+    }
+}
+
+/// Reads the key of a log, making a new random one the first time
+///
+/// # Arguments
+/// * `path: &str` - Path of the log file.
+/// * `create: bool` - Whether a missing key is made, instead of being an error.
+fn load_key(path: &str, create: bool) -> Result<Vec<u8>, String> {
+    let key_path = key_path(path);
+    match fs::read_to_string(&key_path) {
+        Ok(key) => hex::decode(key.trim()).map_err(|e| format!("Key ({}) is not valid hex: {}", key_path, e)),
+        Err(e) if create && e.kind() == std::io::ErrorKind::NotFound => {
+            let key: [u8; 32] = rand::random();
+            let mut options = fs::OpenOptions::new();
+            options.write(true).create_new(true);
+            //Only the server's user may read the key
+            #[cfg(unix)]
+            std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+            options.open(&key_path)
+                .and_then(|mut file| writeln!(file, "{}", hex::encode(key)))
+                .map_err(|e| format!("Could not create key ({}): {}", key_path, e))?;
+            report(format!("Audit log key created at ({}) >>> Keep a copy away from the log", key_path));
+            Ok(key.to_vec())
+        },
+        Err(e) => Err(format!("Could not read key ({}): {}", key_path, e))
+    }
+}
+
+impl AuditLog {
+    /// Opens a log, continuing the chain of the entries it already has, and starts its writer thread
+    ///
+    /// A last entry that is cut short or was modified can not be chained to, so the chain goes on
+    /// from the head of the log instead, and the broken entry stays there for --verify-audit to report.
+    ///
+    /// # Arguments
+    /// * `path: &str` - Path of the log file.
+    ///
+    /// ## Returns
+    /// A String with the reason if the key of the log can not be read or made,
+    /// or if the last entry is broken and the head can not be read either
+    pub fn open(path: &str) -> Result<AuditLog, String> {
+        let key = load_key(path, true)?;
+        let contents = fs::read_to_string(path).unwrap_or_default();
+        let head = fs::read_to_string(head_path(path)).unwrap_or_default();
+
+        let (sequence, last_hash) = match contents.lines().last() {
+            None => (0, GENESIS_HASH.to_string()),
+            Some(line) => match (chained_entry(&key, line), parse_head(&head)) {
+                (Some(last), head) => {
+                    //Recording goes on even so, the break stays there for --verify-audit to report
+                    if head.as_ref() != Some(&last) {
+                        report(format!("Audit log ({}) does not end where its head says >>> Entries may have been removed", path));
+                    }
+                    last
+                },
+                (None, Some(head)) => {
+                    report(format!("Last entry of the audit log ({}) is broken >>> Chaining to entry {} of its head", path, head.0));
+                    head
+                },
+                (None, None) => return Err(format!("Last entry of ({}) is broken and its head can not be read, so the chain can not go on", path))
+            }
+        };
+
+        //An entry cut short by a crash must not swallow the first new one
+        if !contents.is_empty() && !contents.ends_with('\n') {
+            fs::OpenOptions::new().append(true).open(path)
+                .and_then(|mut log| log.write_all(b"\n"))
+                .map_err(|e| format!("Could not open ({}): {}", path, e))?;
+        }
+
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_SIZE);
+        let log_path = path.to_string();
+        let writer = thread::spawn(move || write_entries(log_path, receiver));
+        let chain = Chain { sequence: sequence + 1, last_hash };
+
+        Ok(AuditLog { key, chain: Mutex::new(chain), sender: Some(sender), writer: Some(writer) })
+    }
+
+    /// Chains an entry and hands it to the writer thread
+    ///
+    /// # Arguments
+    /// * `entry: Entry` - Operation that will be recorded.
+    pub fn record(&self, entry: Entry) {
+        let mut chain = self.chain.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis()).unwrap_or(0);
+
+        let fields = format!(
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            chain.sequence,
+            timestamp,
+            escape_field(entry.client),
+            escape_field(entry.user),
+            escape_field(entry.operation),
+            escape_field(entry.path),
+            entry.content.size(),
+            entry.content.hex(),
+            if entry.signature_valid { "valid" } else { "invalid" },
+            escape_field(entry.outcome),
+            chain.last_hash
+        );
+        let hash = entry_hash(&self.key, &fields);
+
+        //Sent while the chain is locked, so the writer gets the entries in the order they were chained
+        if let Some(sender) = &self.sender
+            && sender.send(Message::Line(format!("{}\t{}", fields, hash))).is_ok() {
+            chain.sequence += 1;
+            chain.last_hash = hash;
+        }
+    }
+
+    /// Waits until every entry recorded so far was written
+    pub fn flush(&self) {
+        let (done, written) = mpsc::channel();
+        if let Some(sender) = &self.sender
+            && sender.send(Message::Flush(done)).is_ok() {
+            let _ = written.recv();
+        }
+    }
+}
+
+impl Drop for AuditLog {
+    //The writer thread stops once its queue is closed and every entry in it was written
+    fn drop(&mut self) {
+        drop(self.sender.take());
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+/// Checks the hash chain of a log file, along with its key and head
+///
+/// # Arguments
+/// * `path: &str` - Path of the log file.
+///
+/// ## Returns
+/// The amount of entries if the chain is intact
+/// A String describing the first broken entry if it is not
+pub fn verify(path: &str) -> Result<u64, String> {
+    let key = load_key(path, false)?;
+    let contents = fs::read_to_string(path).map_err(|e| format!("Could not read {}: {}", path, e))?;
+    let mut last_hash = GENESIS_HASH.to_string();
+    let mut sequence = 0;
+
+    for (number, line) in contents.lines().enumerate() {
+        let number = number + 1;
+        let Some((fields, hash)) = line.rsplit_once('\t') else {
+            return Err(format!("Line {} is malformed", number));
+        };
+        let values: Vec<&str> = fields.split('\t').collect();
+        if values.len() != 11 {
+            return Err(format!("Line {} has {} fields instead of 12", number, values.len() + 1));
+        }
+        if values[0].parse::<u64>().ok() != Some(sequence + 1) {
+            return Err(format!("Line {} breaks the sequence, an entry was removed or reordered", number));
+        }
+        if values[10] != last_hash {
+            return Err(format!("Line {} is not chained to the previous entry", number));
+        }
+        if entry_hash(&key, fields) != hash {
+            return Err(format!("Line {} was modified", number));
+        }
+
+        sequence += 1;
+        last_hash = hash.to_string();
+    }
+
+    //The chain can not tell that its last entries are gone, the head kept apart can
+    let head = fs::read_to_string(head_path(path)).map_err(|e| format!("Could not read the head of the log: {}", e))?;
+    if head.trim() != format!("{}\t{}", sequence, last_hash) {
+        return Err(format!("Log ends at entry {}, but its head is at ({}), entries were removed from the end", sequence, head.trim()));
+    }
+
+    Ok(sequence)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Path of a new log in the temporary folder, without any file of a previous run
+    fn log_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("audit-test-{}-{}.log", std::process::id(), name));
+        let path = path.to_str().unwrap().to_string();
+        remove_log(&path);
+        path
+    }
+
+    fn remove_log(path: &str) {
+        for file in [path.to_string(), key_path(path), head_path(path)] {
+            let _ = fs::remove_file(file);
+        }
+    }
+
+    fn record(log: &AuditLog, path: &str, content: &[u8]) {
+        log.record(Entry {
+            client: "127.0.0.1",
+            user: "alice",
+            operation: "upload",
+            path,
+            content: &ContentHash::of(content),
+            signature_valid: true,
+            outcome: "ok",
+        });
+    }
+
+    /// Rewrites the lines of a log, keeping its key and head
+    fn rewrite(path: &str, change: impl FnOnce(&mut Vec<String>)) {
+        let mut lines: Vec<String> = fs::read_to_string(path).unwrap().lines().map(String::from).collect();
+        change(&mut lines);
+        fs::write(path, lines.iter().map(|line| format!("{}\n", line)).collect::<String>()).unwrap();
+    }
+
+    #[test]
+    fn escapes_separators() {
+        assert_eq!(escape_field("a\tb\nc\rd\\e"), "a\\tb\\nc\\rd\\\\e");
+    }
+
+    #[test]
+    fn hashes_content_as_it_is_read() {
+        let mut reader = Hashed::new(&b"hello world"[..]);
+        let mut content = Vec::new();
+        reader.read_to_end(&mut content).unwrap();
+
+        assert_eq!(reader.hash().size(), 11);
+        assert_eq!(reader.hash().hex(), ContentHash::of(b"hello world").hex());
+        assert_eq!(ContentHash::of(b"").hex(), "-");
+    }
+
+    #[test]
+    fn verifies_an_intact_chain() {
+        let path = log_path("intact");
+        let log = AuditLog::open(&path).unwrap();
+        record(&log, "a.txt", b"first");
+        record(&log, "b\tc.txt", b"");
+        drop(log);
+
+        //A reopened log continues the same chain
+        let log = AuditLog::open(&path).unwrap();
+        record(&log, "d.txt", b"third");
+        log.flush();
+
+        assert_eq!(verify(&path), Ok(3));
+        remove_log(&path);
+    }
+
+    #[test]
+    fn finds_modified_entries() {
+        let path = log_path("modified");
+        let log = AuditLog::open(&path).unwrap();
+        record(&log, "a.txt", b"first");
+        record(&log, "b.txt", b"second");
+        log.flush();
+        rewrite(&path, |lines| lines[0] = lines[0].replace("alice", "mallory"));
+
+        assert_eq!(verify(&path), Err("Line 1 was modified".to_string()));
+        remove_log(&path);
+    }
+
+    #[test]
+    fn finds_removed_and_reordered_entries() {
+        let path = log_path("removed");
+        let log = AuditLog::open(&path).unwrap();
+        for name in ["a.txt", "b.txt", "c.txt"] {
+            record(&log, name, b"data");
+        }
+        log.flush();
+        rewrite(&path, |lines| { lines.remove(1); });
+
+        assert!(verify(&path).unwrap_err().contains("breaks the sequence"));
+
+        rewrite(&path, |lines| lines.swap(0, 1));
+        assert!(verify(&path).unwrap_err().contains("breaks the sequence"));
+        remove_log(&path);
+    }
+
+    #[test]
+    fn finds_entries_removed_from_the_end() {
+        let path = log_path("truncated");
+        let log = AuditLog::open(&path).unwrap();
+        record(&log, "a.txt", b"first");
+        record(&log, "b.txt", b"second");
+        log.flush();
+        rewrite(&path, |lines| { lines.pop(); });
+
+        assert!(verify(&path).unwrap_err().contains("entries were removed from the end"));
+        remove_log(&path);
+    }
+
+    #[test]
+    fn needs_the_key_of_the_log() {
+        let path = log_path("key");
+        let log = AuditLog::open(&path).unwrap();
+        record(&log, "a.txt", b"first");
+        log.flush();
+        fs::write(key_path(&path), format!("{}\n", hex::encode([7u8; 32]))).unwrap();
+
+        assert_eq!(verify(&path), Err("Line 1 was modified".to_string()));
+
+        fs::remove_file(key_path(&path)).unwrap();
+        assert!(verify(&path).is_err());
+        remove_log(&path);
+    }
+
+    #[test]
+    fn writes_entries_of_many_threads_in_chain_order() {
+        let path = log_path("threads");
+        let log = AuditLog::open(&path).unwrap();
+        std::thread::scope(|scope| {
+            for thread in 0..8 {
+                let log = &log;
+                scope.spawn(move || {
+                    for entry in 0..50 {
+                        record(log, &format!("{}-{}.txt", thread, entry), b"data");
+                    }
+                });
+            }
+        });
+        drop(log);
+
+        assert_eq!(verify(&path), Ok(400));
+        remove_log(&path);
+    }
+
+    #[test]
+    fn chains_to_the_head_when_the_last_entry_is_broken() {
+        let path = log_path("broken");
+        let log = AuditLog::open(&path).unwrap();
+        record(&log, "a.txt", b"first");
+        record(&log, "b.txt", b"second");
+        drop(log);
+        let second = fs::read_to_string(&path).unwrap().lines().last().unwrap().to_string();
+        //An entry cut short by a crash, which never reached the head
+        fs::OpenOptions::new().append(true).open(&path).unwrap().write_all(b"3\t17000").unwrap();
+
+        let log = AuditLog::open(&path).unwrap();
+        record(&log, "c.txt", b"third");
+        drop(log);
+
+        let contents = fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines.len(), 4);
+        let fields: Vec<&str> = lines[3].split('\t').collect();
+        assert_eq!((fields[0], fields[10]), ("3", second.rsplit('\t').next().unwrap()));
+        //The broken entry is still reported
+        assert_eq!(verify(&path), Err("Line 3 has 2 fields instead of 12".to_string()));
+        remove_log(&path);
+    }
+
+    #[test]
+    fn refuses_to_open_a_broken_log_without_its_head() {
+        let path = log_path("headless");
+        let log = AuditLog::open(&path).unwrap();
+        record(&log, "a.txt", b"first");
+        drop(log);
+        rewrite(&path, |lines| lines[0] = lines[0].replace("alice", "mallory"));
+        fs::remove_file(head_path(&path)).unwrap();
+
+        assert!(AuditLog::open(&path).is_err());
+        remove_log(&path);
+    }
+}
@@ -0,0 +1,207 @@
+use std::fs;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use crate::report;
+
+/// Container that store server's settings, read from ```./server.conf```
+///
+/// # Arguments
+/// * `address` - Address the server listens at, which is also the backend it registers its key for.
+/// * `backend_id` - Name of the server in the proxy's backend pool, the address when empty.
+/// * `weight` - Share of the requests the server gets when the proxy balances by weight.
+/// * `prefixes` - Paths the server serves through the proxy, every path when empty.
+/// * `max_upload_size` - Maximum size of a single uploaded file, in bytes.
+/// * `data_quota` - Maximum size of the whole ```./data``` folder, in bytes.
+/// * `user_quota` - Maximum size of the files uploaded by each user, in bytes. Disabled when 0.
+/// * `allowed_extensions` - Extensions, without the dot, that uploaded files may have.
+/// * `allowed_types` - Detected content types that uploaded files may have.
+/// * `idle_timeout` - Seconds the proxy may take to send the first byte of a request.
+/// * `header_timeout` - Seconds a request head may take to arrive, and the proxy may take to answer the registration.
+/// * `body_timeout` - Seconds a request body may take to arrive.
+/// * `write_timeout` - Seconds a write to the proxy may block.
+/// * `keep_alive_timeout` - Seconds a connection the proxy reuses may wait for its next request. Disabled when 0.
+/// * `workers` - Amount of threads that handle connections.
+/// * `queue_size` - Amount of accepted connections that may wait for a free worker.
+/// * `io_mode` - Whether connections are handled by the worker threads or by an event loop.
+/// * `max_connections` - Amount of connections the event loop holds at once.
+/// * `shutdown_timeout` - Seconds active connections may take to finish once a shutdown is asked for.
+/// * `heartbeat_interval` - Seconds between checks that the proxy still holds the secret-key.
+pub struct Config {
+    pub address: SocketAddr,
+    pub backend_id: String,
+    pub weight: usize,
+    pub prefixes: Vec<String>,
+    pub max_upload_size: u64,
+    pub data_quota: u64,
+    pub user_quota: u64,
+    pub allowed_extensions: Vec<String>,
+    pub allowed_types: Vec<String>,
+    pub idle_timeout: u64,
+    pub header_timeout: u64,
+    pub body_timeout: u64,
+    pub write_timeout: u64,
+    pub keep_alive_timeout: u64,
+    pub workers: usize,
+    pub queue_size: usize,
+    pub io_mode: IoMode,
+    pub max_connections: usize,
+    pub shutdown_timeout: u64,
+    pub heartbeat_interval: u64,
+}
+
+/// How connections are handled
+///
+/// * `Threads` - Each connection takes a worker thread from the pool until it ends.
+/// * `Async` - A few threads run an event loop that waits on every connection at once.
+#[derive(Clone, Copy, PartialEq)]
+pub enum IoMode {
+    Threads,
+    Async,
+}
+
+impl FromStr for IoMode {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<IoMode, String> {
+        match text.to_lowercase().as_str() {
+            "threads" => Ok(IoMode::Threads),
+            "async" => Ok(IoMode::Async),
+            _ => Err(format!("Unknown io_mode ({})", text))
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            address: SocketAddr::from(([127, 0, 0, 1], 1445)),
+            backend_id: String::new(),
+            weight: 1,
+            prefixes: Vec::new(),
+            max_upload_size: 1024 * 1024,
+            data_quota: 64 * 1024 * 1024,
+            user_quota: 0,
+            allowed_extensions: vec!["txt".to_string()],
+            allowed_types: vec!["text/plain".to_string()],
+            idle_timeout: 15,
+            header_timeout: 10,
+            body_timeout: 30,
+            write_timeout: 30,
+            keep_alive_timeout: 5,
+            workers: 16,
+            queue_size: 64,
+            io_mode: IoMode::Threads,
+            max_connections: 10000,
+            shutdown_timeout: 30,
+            heartbeat_interval: 5,
+        }
+    }
+}
+
+/// Parses a config value into its field, keeping the default one when it is invalid
+///
+/// # Arguments
+/// * `key: &str` - Config key, used to report errors.
+/// * `value: &str` - Value written in the config file.
+/// * `field: &mut T` - Field that will hold the value.
+fn set<T: FromStr>(key: &str, value: &str, field: &mut T) {
+    match value.parse() {
+        Ok(v) => *field = v,
+        Err(_) => report(format!("Invalid value ({}) for config key ({}) >>> Keeping default", value, key))
+    }
+}
+
+/// Parses a comma separated config value into a list field, skipping invalid items
+///
+/// # Arguments
+/// * `key: &str` - Config key, used to report errors.
+/// * `value: &str` - Value written in the config file.
+/// * `field: &mut Vec<T>` - Field that will hold the list.
+fn set_list<T: FromStr>(key: &str, value: &str, field: &mut Vec<T>) {
+    *field = value.split(',')
+        .map(|item| item.trim())
+        .filter(|item| !item.is_empty())
+        .filter_map(|item| match item.parse() {
+            Ok(v) => Some(v),
+            Err(_) => {
+                report(format!("Invalid item ({}) for config key ({}) >>> Ignoring", item, key));
+                None
+            }
+        })
+        .collect();
+}
+
+impl Config {
+    /// Read settings from a ```key = value``` file. Missing keys keep their default values.
+    ///
+    /// # Arguments
+    /// * `path: &str` - Path of the config file.
+    pub fn load(path: &str) -> Config {
+        let mut config = Config::default();
+        let Ok(contents) = fs::read_to_string(path) else {
+            report(format!("Config file ({}) not found >>> Using defaults", path));
+            return config;
+        };
+
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                report(format!("Malformed config line ({}) >>> Ignoring", line));
+                continue;
+            };
+            let (key, value) = (key.trim(), value.trim());
+
+            match key {
+                "address" => set(key, value, &mut config.address),
+                "backend_id" => config.backend_id = value.to_string(),
+                "weight" => set(key, value, &mut config.weight),
+                "prefixes" => set_list(key, value, &mut config.prefixes),
+                "max_upload_size" => set(key, value, &mut config.max_upload_size),
+                "data_quota" => set(key, value, &mut config.data_quota),
+                "user_quota" => set(key, value, &mut config.user_quota),
+                "allowed_extensions" => set_list(key, &value.to_lowercase(), &mut config.allowed_extensions),
+                "allowed_types" => set_list(key, &value.to_lowercase(), &mut config.allowed_types),
+                "idle_timeout" => set(key, value, &mut config.idle_timeout),
+                "header_timeout" => set(key, value, &mut config.header_timeout),
+                "body_timeout" => set(key, value, &mut config.body_timeout),
+                "write_timeout" => set(key, value, &mut config.write_timeout),
+                "workers" => set(key, value, &mut config.workers),
+                "queue_size" => set(key, value, &mut config.queue_size),
+                "keep_alive_timeout" => set(key, value, &mut config.keep_alive_timeout),
+                "io_mode" => set(key, value, &mut config.io_mode),
+                "max_connections" => set(key, value, &mut config.max_connections),
+                "shutdown_timeout" => set(key, value, &mut config.shutdown_timeout),
+                "heartbeat_interval" => set(key, value, &mut config.heartbeat_interval),
+                _ => report(format!("Unknown config key ({}) >>> Ignoring", key))
+            }
+        }
+
+        //Sockets do not accept a zero timeout
+        for timeout in [&mut config.idle_timeout, &mut config.header_timeout, &mut config.body_timeout, &mut config.write_timeout] {
+            if *timeout == 0 {
+                report("Timeouts must be at least 1 second >>> Using 1 second".to_string());
+                *timeout = 1;
+            }
+        }
+
+        if config.weight == 0 {
+            report("Weight must be at least 1 >>> Using 1".to_string());
+            config.weight = 1;
+        }
+
+        if config.heartbeat_interval == 0 {
+            report("Heartbeat interval must be at least 1 second >>> Using 1 second".to_string());
+            config.heartbeat_interval = 1;
+        }
+
+        if config.workers == 0 {
+            report("There must be at least 1 worker >>> Using 1 worker".to_string());
+            config.workers = 1;
+        }
+
+        config
+    }
+}
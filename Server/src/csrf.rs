@@ -0,0 +1,119 @@
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use sha2::Sha256;
+use subtle::ConstantTimeEq;
+
+/// Name of the cookie (and of the form field) that carries the token
+pub const COOKIE_NAME: &str = "csrf_token";
+
+/// HMAC-SHA256 of a token's nonce, keyed with the server's secret-key
+///
+/// # Arguments
+/// * `secret: &str` - Server's secret-key.
+/// * `nonce: &str` - Random part of the token.
+fn mac(secret: &str, nonce: &str) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(b"csrf:");
+    mac.update(nonce.as_bytes());
+    mac
+}
+
+/// Generates a new token with the format ```nonce.signature```
+///
+/// # Arguments
+/// * `secret: &str` - Server's secret-key.
+pub fn generate(secret: &str) -> String {
+    let nonce = format!("{:032x}", rand::rng().random::<u128>());
+    let signature = hex::encode(mac(secret, &nonce).finalize().into_bytes());
+    format!("{}.{}", nonce, signature)
+}
+
+/// Checks if a token was generated by this server, comparing its signature in constant time
+///
+/// # Arguments
+/// * `secret: &str` - Server's secret-key.
+/// * `token: &str` - Token that will be checked.
+pub fn is_valid(secret: &str, token: &str) -> bool {
+    match token.split_once('.') {
+        Some((nonce, signature)) => match hex::decode(signature) {
+            Ok(signature) => !nonce.is_empty() && mac(secret, nonce).verify_slice(&signature).is_ok(),
+            Err(_) => false
+        },
+        None => false
+    }
+}
+
+/// Reads the token out of a Cookie header
+///
+/// # Arguments
+/// * `cookies: Option<&str>` - Value of the Cookie header, if any.
+pub fn from_cookies(cookies: Option<&str>) -> Option<&str> {
+    cookies?.split(';')
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(name, _)| *name == COOKIE_NAME)
+        .map(|(_, value)| value)
+}
+
+/// Checks a state-changing request, whose cookie token and form token must be the same valid token
+///
+/// # Arguments
+/// * `secret: &str` - Server's secret-key.
+/// * `cookies: Option<&str>` - Value of the Cookie header, if any.
+/// * `form_token: Option<&str>` - Token sent in the form, forwarded by the proxy.
+pub fn verify(secret: &str, cookies: Option<&str>, form_token: Option<&str>) -> bool {
+    match (from_cookies(cookies), form_token) {
+        (Some(cookie_token), Some(form_token)) => {
+            let same: bool = cookie_token.as_bytes().ct_eq(form_token.trim().as_bytes()).into();
+            same && is_valid(secret, cookie_token)
+        },
+        _ => false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "test-secret";
+
+    #[test]
+    fn generated_tokens_are_valid_and_unique() {
+        let token = generate(SECRET);
+
+        assert!(is_valid(SECRET, &token));
+        assert_ne!(token, generate(SECRET));
+    }
+
+    #[test]
+    fn refuses_forged_tokens() {
+        let token = generate(SECRET);
+        let (nonce, signature) = token.split_once('.').unwrap();
+
+        assert!(!is_valid("other-secret", &token));
+        assert!(!is_valid(SECRET, &format!("{}0.{}", nonce, signature)));
+        assert!(!is_valid(SECRET, &format!(".{}", signature)));
+        assert!(!is_valid(SECRET, &format!("{}.zz", nonce)));
+        assert!(!is_valid(SECRET, nonce));
+        assert!(!is_valid(SECRET, ""));
+    }
+
+    #[test]
+    fn reads_token_from_cookies() {
+        assert_eq!(from_cookies(Some("a=1; csrf_token=abc.def; b=2")), Some("abc.def"));
+        assert_eq!(from_cookies(Some("csrf_token_old=x; b=2")), None);
+        assert_eq!(from_cookies(None), None);
+    }
+
+    #[test]
+    fn verifies_matching_cookie_and_form_tokens() {
+        let token = generate(SECRET);
+        let cookies = format!("session=1; csrf_token={}", token);
+
+        assert!(verify(SECRET, Some(&cookies), Some(&token)));
+        assert!(verify(SECRET, Some(&cookies), Some(&format!("{}\r\n", token))));
+        assert!(!verify(SECRET, Some(&cookies), Some(&generate(SECRET))));
+        assert!(!verify(SECRET, Some(&cookies), None));
+        assert!(!verify(SECRET, None, Some(&token)));
+        assert!(!verify("other-secret", Some(&cookies), Some(&token)));
+    }
+}
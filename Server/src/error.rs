@@ -0,0 +1,160 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use crate::acl::User;
+use crate::http;
+
+/// Everything that makes the server give up a request
+///
+/// Each variant carries the context that is reported along with it:
+/// * `BadRequest` - The request could not be understood (400).
+/// * `Unauthorized` - An anonymous user was denied, so credentials are asked for (401).
+/// * `Forbidden` - An authenticated user or an unsigned request was denied (403).
+/// * `InvalidCsrf` - A state-changing request had no valid CSRF token (403).
+/// * `NotFound` - The requested file does not exist (404).
+/// * `Timeout` - The client was too slow (408, nothing is sent if it never sent a byte).
+/// * `PayloadTooLarge` - An upload exceeded a size limit or quota (413).
+/// * `HeaderTooLarge` - The request head is longer than the server reads (431).
+/// * `UnsupportedMediaType` - An upload has a type that is not accepted, its `reason` is shown in the page (415).
+/// * `Internal` - Something failed on the server's side, like a file that could not be read (500).
+/// * `Unavailable` - Every worker is busy and the queue is full (503).
+/// * `Connection` - The connection with the client failed, so no answer can be sent.
+#[derive(Debug)]
+pub enum ServerError {
+    BadRequest(String),
+    Unauthorized(String),
+    Forbidden(String),
+    InvalidCsrf(String),
+    NotFound(String),
+    Timeout(http::Timeout),
+    PayloadTooLarge(String),
+    HeaderTooLarge(String),
+    UnsupportedMediaType { context: String, reason: String },
+    Internal(String),
+    Unavailable(String),
+    Connection(io::Error),
+}
+
+impl ServerError {
+    /// Error sent when an user is not allowed to do something.
+    /// Anonymous users are asked for credentials, everyone else is forbidden.
+    ///
+    /// # Arguments
+    /// * `user: &User` - User that has been denied.
+    /// * `context: String` - What the user was not allowed to do.
+    pub fn denied(user: &User, context: String) -> ServerError {
+        if user.is_anonymous() {
+            ServerError::Unauthorized(context)
+        } else {
+            ServerError::Forbidden(context)
+        }
+    }
+
+    /// Status line of the response, None when no response can or should be sent
+    fn status(&self) -> Option<&'static str> {
+        match self {
+            ServerError::BadRequest(_) => Some("400 BAD REQUEST"),
+            ServerError::Unauthorized(_) => Some("401 UNAUTHORIZED"),
+            ServerError::Forbidden(_) | ServerError::InvalidCsrf(_) => Some("403 FORBIDDEN"),
+            ServerError::NotFound(_) => Some("404 NOT FOUND"),
+            ServerError::Timeout(http::Timeout::Idle) => None,
+            ServerError::Timeout(_) => Some("408 REQUEST TIMEOUT"),
+            ServerError::PayloadTooLarge(_) => Some("413 PAYLOAD TOO LARGE"),
+            ServerError::HeaderTooLarge(_) => Some("431 REQUEST HEADER FIELDS TOO LARGE"),
+            ServerError::UnsupportedMediaType { .. } => Some("415 UNSUPPORTED MEDIA TYPE"),
+            ServerError::Internal(_) => Some("500 INTERNAL SERVER ERROR"),
+            ServerError::Unavailable(_) => Some("503 SERVICE UNAVAILABLE"),
+            ServerError::Connection(_) => None
+        }
+    }
+
+    /// Path of the html page sent along with the error
+    fn page(&self) -> &'static str {
+        match self {
+            ServerError::BadRequest(_) => "./pages/400.html",
+            ServerError::InvalidCsrf(_) => "./pages/403-csrf.html",
+            ServerError::NotFound(_) => "./pages/404.html",
+            ServerError::Timeout(_) => "./pages/408.html",
+            ServerError::PayloadTooLarge(_) => "./pages/413.html",
+            ServerError::HeaderTooLarge(_) => "./pages/431.html",
+            ServerError::UnsupportedMediaType { .. } => "./pages/415.html",
+            ServerError::Internal(_) => "./pages/500.html",
+            ServerError::Unavailable(_) => "./pages/503.html",
+            _ => "./pages/403.html"
+        }
+    }
+
+    /// Builds the response that tells the client about the error
+    ///
+    /// ## Returns
+    /// The whole response, head and body
+    /// None if the client must not be answered
+    pub fn response(&self) -> Option<String> {
+        let status = self.status()?;
+
+        //A missing page must not hide the error itself
+        let template = fs::read_to_string(self.page()).unwrap_or_else(|e| {
+            crate::report(format!("Could not read ({}): {} >>> Sending a plain page", self.page(), e));
+            format!("<h1>{}</h1>", status)
+        });
+        let contents = match self {
+            ServerError::UnsupportedMediaType { reason, .. } => crate::fill_template(&template, "{{MOTIVO_DA_RECUSA}}", reason),
+            _ => template
+        };
+        let extra_headers = match self {
+            ServerError::Unauthorized(_) => "WWW-Authenticate: Basic realm=\"FileSearcher\"\r\n",
+            ServerError::BadRequest(_) | ServerError::Timeout(_) | ServerError::PayloadTooLarge(_) | ServerError::HeaderTooLarge(_) | ServerError::Unavailable(_) => "Connection: close\r\n",
+            _ => ""
+        };
+
+        Some(format!(
+            "HTTP/1.1 {}\r\n{}Content-Length: {}\r\nContent-Type: text/html;charset=utf-8\r\n\r\n{}",
+            status,
+            extra_headers,
+            contents.len(),
+            contents
+        ))
+    }
+
+    /// Status code sent to the client, like ```404```, or ```none``` when there is no response
+    pub fn code(&self) -> &'static str {
+        self.status().and_then(|status| status.split_whitespace().next()).unwrap_or("none")
+    }
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ServerError::BadRequest(context)
+            | ServerError::Unauthorized(context)
+            | ServerError::Forbidden(context)
+            | ServerError::InvalidCsrf(context)
+            | ServerError::NotFound(context)
+            | ServerError::PayloadTooLarge(context)
+            | ServerError::HeaderTooLarge(context)
+            | ServerError::UnsupportedMediaType { context, .. }
+            | ServerError::Internal(context)
+            | ServerError::Unavailable(context) => write!(f, "{}", context),
+            ServerError::Timeout(timeout) => write!(f, "Connection timed out ({:?})", timeout),
+            ServerError::Connection(e) => write!(f, "Connection with client failed: {}", e)
+        }
+    }
+}
+
+impl From<io::Error> for ServerError {
+    fn from(e: io::Error) -> Self {
+        ServerError::Connection(e)
+    }
+}
+
+impl From<http::Error> for ServerError {
+    fn from(e: http::Error) -> Self {
+        match e {
+            http::Error::Timeout(timeout) => ServerError::Timeout(timeout),
+            http::Error::Io(e) => ServerError::Connection(e),
+            http::Error::Malformed(context) => ServerError::BadRequest(context),
+            http::Error::TooLarge(context) => ServerError::PayloadTooLarge(context),
+            http::Error::HeadTooLarge(context) => ServerError::HeaderTooLarge(context)
+        }
+    }
+}
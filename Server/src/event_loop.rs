@@ -0,0 +1,231 @@
+use std::io;
+use std::net::TcpListener;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use crate::error::ServerError;
+use shared::shutdown::Shutdown;
+use crate::{check_request, error_response, http, keep_alive_response, keeps_alive, receive_upload, report, route, stop_accepting, ServerState};
+
+/// Place taken by an open connection, given back when it is dropped
+///
+/// # Arguments
+/// * `0` - Counter of open connections.
+struct Slot(Arc<AtomicUsize>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Runs the server on an event loop, where a few threads wait on every connection at once
+///
+/// # Arguments
+/// * `listener: TcpListener` - Listener already bound to the server's address.
+/// * `state: Arc<ServerState>` - Server data, shared by every connection.
+/// * `shutdown: Arc<Shutdown>` - Tells when to stop accepting connections.
+/// * `heartbeat: thread::JoinHandle<()>` - Heartbeat thread, joined before the key is removed from the proxy.
+///
+/// Waiting for the proxy happens on the event loop. Checking and routing read and write files,
+/// so they run on a bounded set of blocking threads, the same code used by the worker threads.
+/// An upload's body is read on the event loop and passed to its blocking thread as it arrives.
+///
+/// ## Returns
+/// The amount of connections that were still open when the shutdown deadline passed
+pub fn run(listener: TcpListener, state: Arc<ServerState>, shutdown: Arc<Shutdown>, heartbeat: thread::JoinHandle<()>) -> io::Result<usize> {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(state.config.workers)
+        .max_blocking_threads(state.config.workers)
+        .enable_io()
+        .enable_time()
+        .build()?;
+
+    let deadline = Duration::from_secs(state.config.shutdown_timeout);
+    let open = runtime.block_on(accept_loop(listener, state, shutdown, heartbeat, deadline))?;
+    //Connections still open after the deadline are dropped along with the runtime
+    runtime.shutdown_timeout(Duration::from_secs(1));
+
+    Ok(open)
+}
+
+/// Accepts connections and gives each one a task, until a shutdown is asked for
+///
+/// # Arguments
+/// * `listener: TcpListener` - Listener already bound to the server's address.
+/// * `state: Arc<ServerState>` - Server data, shared by every connection.
+/// * `shutdown: Arc<Shutdown>` - Tells when to stop accepting connections.
+/// * `heartbeat: thread::JoinHandle<()>` - Heartbeat thread, joined before the key is removed from the proxy.
+/// * `deadline: Duration` - How long open connections may take to finish after a shutdown.
+///
+/// ## Returns
+/// The amount of connections that were still open when the deadline passed
+async fn accept_loop(listener: TcpListener, state: Arc<ServerState>, shutdown: Arc<Shutdown>, heartbeat: thread::JoinHandle<()>, deadline: Duration) -> io::Result<usize> {
+    listener.set_nonblocking(true)?;
+    let listener = tokio::net::TcpListener::from_std(listener)?;
+    let open = Arc::new(AtomicUsize::new(0));
+
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = shutdown.wait() => break
+        };
+        if shutdown.is_requested() {
+            break;
+        }
+
+        //A connection that failed before being accepted does not stop the server
+        let stream = match accepted {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                report(format!("Could not accept a connection: {}", e));
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let state = Arc::clone(&state);
+
+        //Connections beyond the limit are refused right away instead of piling up
+        if open.fetch_add(1, Ordering::Relaxed) >= state.config.max_connections {
+            open.fetch_sub(1, Ordering::Relaxed);
+            tokio::spawn(async move {
+                let error = ServerError::Unavailable("The connection limit was reached".to_string());
+                send_error(stream, &state, error, Duration::from_secs(1)).await;
+            });
+            continue;
+        }
+        let slot = Slot(Arc::clone(&open));
+
+        tokio::spawn(async move {
+            let _slot = slot;
+            handle_connection(stream, state).await;
+        });
+    }
+
+    //New connections are refused from now on, the open ones get until the deadline
+    drop(listener);
+    let stopping_state = Arc::clone(&state);
+    let _ = tokio::task::spawn_blocking(move || stop_accepting(&stopping_state, heartbeat)).await;
+
+    let end = Instant::now() + deadline;
+    while open.load(Ordering::Relaxed) > 0 && Instant::now() < end {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+
+    Ok(open.load(Ordering::Relaxed))
+}
+
+/// Writes bytes to a stream, giving up when it takes too long
+///
+/// # Arguments
+/// * `stream: &mut TcpStream` - Stream that will be written.
+/// * `data: &[u8]` - Bytes that will be written.
+/// * `timeout: Duration` - How long the whole write may take.
+async fn write_all(stream: &mut TcpStream, data: &[u8], timeout: Duration) -> io::Result<()> {
+    match tokio::time::timeout(timeout, stream.write_all(data)).await {
+        Ok(result) => result,
+        Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "write timed out"))
+    }
+}
+
+/// Reports an error and answers the client with its page
+///
+/// # Arguments
+/// * `mut stream: TcpStream` - Stream that holds the connection.
+/// * `state: &ServerState` - Server data, which holds the timed out connections counter.
+/// * `error: ServerError` - Why the request was given up.
+/// * `timeout: Duration` - How long writing the page may take.
+async fn send_error(mut stream: TcpStream, state: &ServerState, error: ServerError, timeout: Duration) {
+    if let Some(response) = error_response(state, &error)
+        && let Err(e) = write_all(&mut stream, response.as_bytes(), timeout).await {
+        report(format!("Could not send the {} response: {}", error.code(), e));
+    }
+}
+
+/// Handles the connection of a stream on the event loop
+///
+/// # Arguments
+/// * `mut stream: TcpStream` - Stream that holds the connection.
+/// * `state: Arc<ServerState>` - Server data.
+async fn handle_connection(mut stream: TcpStream, state: Arc<ServerState>) {
+    //The proxy reuses its connections, so one connection may carry many requests
+    let mut reused = false;
+    loop {
+        match handle_request(&mut stream, &state, reused).await {
+            Ok(true) => reused = true,
+            Ok(false) => break,
+            Err(e) => {
+                let timeout = Duration::from_secs(state.config.write_timeout);
+                send_error(stream, &state, e, timeout).await;
+                break;
+            }
+        }
+    }
+}
+
+/// Runs blocking work, like reading files, outside of the event loop
+///
+/// # Arguments
+/// * `work: F` - Work that will be run.
+async fn blocking<T, F>(work: F) -> Result<T, ServerError>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, ServerError> + Send + 'static,
+{
+    match tokio::task::spawn_blocking(work).await {
+        Ok(result) => result,
+        Err(e) => Err(ServerError::Internal(format!("Request handler failed: {}", e)))
+    }
+}
+
+/// Reads, checks and routes the request of a connection
+///
+/// # Arguments
+/// * `stream: &mut TcpStream` - Stream that holds the connection.
+/// * `state: &Arc<ServerState>` - Server data, used to check the request.
+/// * `reused: bool` - Whether the connection already carried a request.
+///
+/// ## Returns
+/// Whether the connection stays open for another request
+async fn handle_request(stream: &mut TcpStream, state: &Arc<ServerState>, reused: bool) -> Result<bool, ServerError> {
+    let config = &state.config;
+    let idle_timeout = Duration::from_secs(if reused { config.keep_alive_timeout } else { config.idle_timeout });
+    let (request_head, body_start) = match http::read_head_async(stream, idle_timeout, Duration::from_secs(config.header_timeout)).await {
+        //A reused connection that the proxy closes or leaves idle ends without an answer
+        Ok((head, _)) if reused && head.is_empty() => return Ok(false),
+        Err(http::Error::Timeout(http::Timeout::Idle)) if reused => return Ok(false),
+        head => head?
+    };
+
+    let peer = stream.peer_addr()?;
+    let checking_state = Arc::clone(state);
+    let (mut request, user, decoder) = blocking(move || check_request(&checking_state, request_head, peer)).await?;
+    let keep_alive = keeps_alive(state, &request, &body_start);
+    let upload = request.is_upload();
+
+    //A few chunks wait between the connection and the disk, so a slow disk slows the upload down instead of filling memory
+    let (sender, receiver) = tokio::sync::mpsc::channel(4);
+    let routing_state = Arc::clone(state);
+    let routing = blocking(move || {
+        if upload {
+            receive_upload(&routing_state, &mut request, &user, &mut http::ChannelBody::new(receiver))?;
+        }
+        route(request, &routing_state, &user)
+    });
+    let receiving = async {
+        if upload {
+            http::send_body(stream, decoder, body_start, Duration::from_secs(config.body_timeout), sender).await
+        } else {
+            true
+        }
+    };
+    let (response, whole) = tokio::join!(routing, receiving);
+    let keep_alive = keep_alive && whole;
+    let response = keep_alive_response(response?, keep_alive);
+    write_all(stream, response.as_bytes(), Duration::from_secs(config.write_timeout)).await?;
+
+    Ok(keep_alive)
+}
@@ -0,0 +1,58 @@
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use colored::*;
+use shared::shutdown::Shutdown;
+use crate::{register_with_proxy, report, send_key_to_proxy, ServerState};
+
+/// Time between registration attempts while the proxy can not be reached
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often a sleeping heartbeat checks whether a shutdown was asked for
+const SHUTDOWN_CHECK: Duration = Duration::from_millis(100);
+
+/// Starts the thread that registers the secret-key at the proxy and keeps it registered
+///
+/// # Arguments
+/// * `state: Arc<ServerState>` - Server data, which holds the secret-key and the heartbeat interval.
+/// * `shutdown: Arc<Shutdown>` - Stops the heartbeat, so a removed key is not registered again.
+///
+/// The server keeps answering while the proxy is away, the proxy answers 503 until the key is registered again.
+///
+/// ## Returns
+/// The heartbeat thread, which must be joined before the key is removed on shutdown
+pub fn start(state: Arc<ServerState>, shutdown: Arc<Shutdown>) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let interval = Duration::from_secs(state.config.heartbeat_interval);
+        let mut registered = false;
+        //Only the first failure in a row is reported, instead of one every second
+        let mut failing = false;
+
+        while !shutdown.is_requested() {
+            if registered && send_key_to_proxy("/heartbeat", &state).is_err() {
+                report("Proxy no longer holds the Secret Key >>> Registering it again".to_string());
+                registered = false;
+            }
+
+            if !registered && !shutdown.is_requested() {
+                match register_with_proxy(&state) {
+                    Ok(()) => {
+                        registered = true;
+                        failing = false;
+                    },
+                    Err(e) if !failing => {
+                        eprintln!("[{}] {} {} >> {} >>> Retrying every second", "SERVER".blue(), "::".yellow(), "Could not register Secret Key".red(), e);
+                        failing = true;
+                    },
+                    Err(_) => {}
+                }
+            }
+
+            //Sleeps in short steps, so a shutdown does not wait for a whole interval
+            let wake_up = Instant::now() + if registered { interval } else { RETRY_INTERVAL };
+            while !shutdown.is_requested() && Instant::now() < wake_up {
+                thread::sleep(SHUTDOWN_CHECK.min(wake_up.saturating_duration_since(Instant::now())));
+            }
+        }
+    })
+}
@@ -0,0 +1,457 @@
+use std::io;
+use std::io::prelude::*;
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::sync::mpsc::{Receiver, Sender};
+pub use shared::http::{is_timeout, read_head, read_head_async, Error, Timeout};
+
+/// Maximum size of a line of a chunked body, like the size of a chunk or a trailer, in bytes
+const MAX_LINE_SIZE: usize = 1024;
+
+/// Where a [`Decoder`] is in a body
+///
+/// * `Length` - In a body sized by Content-Length, along with the bytes left.
+/// * `Size` - In the size line of a chunk, along with what arrived of it.
+/// * `Data` - In the data of a chunk, along with the bytes left.
+/// * `DataEnd` - In the line break after the data of a chunk, along with what arrived of it.
+/// * `Trailer` - In a trailer line after the last chunk, along with what arrived of it.
+/// * `Done` - Past the end of the body.
+enum Framing {
+    Length(u64),
+    Size(Vec<u8>),
+    Data(u64),
+    DataEnd(Vec<u8>),
+    Trailer(Vec<u8>),
+    Done,
+}
+
+/// Takes the body of a request out of the bytes read from the connection, whether Content-Length
+/// tells its size or it arrives in chunks
+///
+/// # Arguments
+/// * `framing` - Where the decoder is in the body.
+/// * `limit` - Bytes the body may have.
+/// * `size` - Bytes of the body found so far.
+pub struct Decoder {
+    framing: Framing,
+    limit: u64,
+    size: u64,
+}
+
+/// Adds bytes to a line until its line break arrives
+///
+/// # Arguments
+/// * `line: &mut Vec<u8>` - What arrived of the line.
+/// * `bytes: &[u8]` - Bytes that arrived.
+///
+/// ## Returns
+/// How many of the bytes belong to the line
+fn take_line(line: &mut Vec<u8>, bytes: &[u8]) -> Result<usize, Error> {
+    let taken = bytes.iter().position(|&byte| byte == b'\n').map_or(bytes.len(), |end| end + 1);
+    line.extend_from_slice(&bytes[..taken]);
+    if line.len() > MAX_LINE_SIZE {
+        return Err(Error::Malformed(format!("Chunked body has a line of more than {} bytes", MAX_LINE_SIZE)));
+    }
+
+    Ok(taken)
+}
+
+impl Decoder {
+    /// Decoder of a body whose size is told by Content-Length
+    ///
+    /// # Arguments
+    /// * `size: u64` - Size of the body.
+    pub fn length(size: u64) -> Decoder {
+        Decoder { framing: if size == 0 { Framing::Done } else { Framing::Length(size) }, limit: size, size: 0 }
+    }
+
+    /// Decoder of a body sent with ```Transfer-Encoding: chunked```
+    ///
+    /// # Arguments
+    /// * `limit: u64` - Bytes the body may have.
+    pub fn chunked(limit: u64) -> Decoder {
+        Decoder { framing: Framing::Size(Vec::new()), limit, size: 0 }
+    }
+
+    /// Whether the whole body was found
+    pub fn is_done(&self) -> bool {
+        matches!(self.framing, Framing::Done)
+    }
+
+    /// How many bytes to read next, so a body sized by Content-Length is never read past its end
+    ///
+    /// # Arguments
+    /// * `buffer: usize` - Size of the read buffer.
+    pub fn wanted(&self, buffer: usize) -> usize {
+        match self.framing {
+            Framing::Length(left) => left.min(buffer as u64) as usize,
+            _ => buffer
+        }
+    }
+
+    /// Takes the body out of bytes read from the connection
+    ///
+    /// # Arguments
+    /// * `bytes: &[u8]` - Bytes that were read.
+    /// * `body: &mut Vec<u8>` - Where the bytes of the body are placed.
+    ///
+    /// ## Returns
+    /// How many of the bytes were taken, the others are past the end of the body
+    pub fn decode(&mut self, bytes: &[u8], body: &mut Vec<u8>) -> Result<usize, Error> {
+        let mut at = 0;
+
+        while at < bytes.len() {
+            let rest = &bytes[at..];
+            self.framing = match std::mem::replace(&mut self.framing, Framing::Done) {
+                Framing::Done => break,
+                Framing::Length(left) => {
+                    let taken = left.min(rest.len() as u64);
+                    body.extend_from_slice(&rest[..taken as usize]);
+                    at += taken as usize;
+                    self.size += taken;
+                    if taken == left { Framing::Done } else { Framing::Length(left - taken) }
+                },
+                Framing::Data(left) => {
+                    let taken = left.min(rest.len() as u64);
+                    body.extend_from_slice(&rest[..taken as usize]);
+                    at += taken as usize;
+                    if taken == left { Framing::DataEnd(Vec::new()) } else { Framing::Data(left - taken) }
+                },
+                Framing::Size(mut line) => {
+                    at += take_line(&mut line, rest)?;
+                    if !line.ends_with(b"\n") {
+                        Framing::Size(line)
+                    } else {
+                        //Extensions after a semicolon are allowed, and ignored
+                        let text = String::from_utf8_lossy(&line);
+                        let text = text.split(';').next().unwrap_or_default().trim();
+                        let size = u64::from_str_radix(text, 16)
+                            .map_err(|_| Error::Malformed(format!("Chunked body has an invalid chunk size ({})", text)))?;
+                        if size == 0 {
+                            Framing::Trailer(Vec::new())
+                        } else if self.size.saturating_add(size) > self.limit {
+                            return Err(Error::TooLarge(format!("Body has more than the {} bytes it may have", self.limit)));
+                        } else {
+                            self.size += size;
+                            Framing::Data(size)
+                        }
+                    }
+                },
+                Framing::DataEnd(mut line) => {
+                    at += take_line(&mut line, rest)?;
+                    match line.as_slice() {
+                        b"\r\n" | b"\n" => Framing::Size(Vec::new()),
+                        line if line.ends_with(b"\n") || line.len() > 2 => return Err(Error::Malformed("Chunk does not end with a line break".to_string())),
+                        _ => Framing::DataEnd(line)
+                    }
+                },
+                //Trailer lines are read and ignored, a blank one ends the body
+                Framing::Trailer(mut line) => {
+                    at += take_line(&mut line, rest)?;
+                    match line.as_slice() {
+                        b"\r\n" | b"\n" => Framing::Done,
+                        line if line.ends_with(b"\n") => Framing::Trailer(Vec::new()),
+                        _ => Framing::Trailer(line)
+                    }
+                }
+            };
+        }
+
+        Ok(at)
+    }
+}
+
+/// Body of a request read off the connection as it is consumed, so it is never held whole
+///
+/// # Arguments
+/// * `stream` - Connection the body arrives on.
+/// * `decoder` - Takes the body out of what is read.
+/// * `read` - Bytes read but not decoded yet, starting with the ones read along with the head.
+/// * `decoded` - Bytes of the body that were not consumed yet.
+/// * `offset` - Where the bytes not consumed yet start in `decoded`.
+/// * `deadline` - When the whole body must have arrived.
+pub struct Body<'a> {
+    stream: &'a mut TcpStream,
+    decoder: Decoder,
+    read: Vec<u8>,
+    decoded: Vec<u8>,
+    offset: usize,
+    deadline: Instant,
+}
+
+impl Body<'_> {
+    /// # Arguments
+    /// * `stream: &mut TcpStream` - Stream that holds the connection.
+    /// * `decoder: Decoder` - Decoder of the body, as its head tells it is framed.
+    /// * `start: Vec<u8>` - Body bytes that were already read along with the head.
+    /// * `body_timeout: Duration` - How long the whole body may take to arrive.
+    pub fn new(stream: &mut TcpStream, decoder: Decoder, start: Vec<u8>, body_timeout: Duration) -> Body<'_> {
+        Body { stream, decoder, read: start, decoded: Vec::new(), offset: 0, deadline: Instant::now() + body_timeout }
+    }
+
+    /// Whether the whole body was read and nothing past it, so the connection may carry another request
+    pub fn is_whole(&self) -> bool {
+        self.decoder.is_done() && self.read.is_empty()
+    }
+}
+
+impl Read for Body<'_> {
+    /// Errors of the connection or of the body's framing come as an [`Error`] inside the io::Error
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        while self.offset == self.decoded.len() {
+            self.decoded.clear();
+            self.offset = 0;
+            let taken = self.decoder.decode(&self.read, &mut self.decoded).map_err(io::Error::other)?;
+            self.read.drain(..taken);
+            if !self.decoded.is_empty() {
+                break;
+            }
+            if self.decoder.is_done() {
+                return Ok(0);
+            }
+
+            let timeout = self.deadline.saturating_duration_since(Instant::now());
+            if timeout.is_zero() {
+                return Err(io::Error::other(Error::Timeout(Timeout::Body)));
+            }
+            self.stream.set_read_timeout(Some(timeout))?;
+            let mut chunk = [0; 8192];
+            let wanted = self.decoder.wanted(chunk.len());
+            match self.stream.read(&mut chunk[..wanted]) {
+                //A body cut short, like an upload the proxy gave up on, is never taken as whole
+                Ok(0) => return Err(io::Error::other(Error::Io(io::ErrorKind::UnexpectedEof.into()))),
+                Ok(bytes_read) => self.read.extend_from_slice(&chunk[..bytes_read]),
+                Err(e) if is_timeout(&e) => return Err(io::Error::other(Error::Timeout(Timeout::Body))),
+                Err(e) => return Err(io::Error::other(Error::Io(e)))
+            }
+        }
+
+        let size = buffer.len().min(self.decoded.len() - self.offset);
+        buffer[..size].copy_from_slice(&self.decoded[self.offset..self.offset + size]);
+        self.offset += size;
+        Ok(size)
+    }
+}
+
+/// Reads a body like [`Body`], but on the event loop, and passes it to a blocking thread in chunks
+///
+/// # Arguments
+/// * `stream: &mut S` - Non-blocking stream that holds the connection.
+/// * `decoder: Decoder` - Decoder of the body, as its head tells it is framed.
+/// * `start: Vec<u8>` - Body bytes that were already read along with the head.
+/// * `body_timeout: Duration` - How long the whole body may take to arrive.
+/// * `sender: Sender<io::Result<Vec<u8>>>` - Where the chunks go, read back by a [`ChannelBody`]. An empty chunk ends the body.
+///
+/// ## Returns
+/// Whether the whole body was passed on and nothing past it was read, so the connection may carry another request.
+/// Errors are passed on instead, for the thread that reads the body to fail with.
+pub async fn send_body<S: AsyncRead + Unpin>(stream: &mut S, mut decoder: Decoder, start: Vec<u8>, body_timeout: Duration, sender: Sender<io::Result<Vec<u8>>>) -> bool {
+    let deadline = tokio::time::Instant::now() + body_timeout;
+    let mut read = start;
+    let mut buffer = [0; 8192];
+
+    let error = loop {
+        let mut decoded = Vec::new();
+        let taken = match decoder.decode(&read, &mut decoded) {
+            Ok(taken) => taken,
+            Err(e) => break e
+        };
+        read.drain(..taken);
+        //A reader that gave up, like on an upload of the wrong type, takes nothing more
+        if !decoded.is_empty() && sender.send(Ok(decoded)).await.is_err() {
+            return false;
+        }
+        if decoder.is_done() {
+            return sender.send(Ok(Vec::new())).await.is_ok() && read.is_empty();
+        }
+
+        let wanted = decoder.wanted(buffer.len());
+        match tokio::time::timeout_at(deadline, stream.read(&mut buffer[..wanted])).await {
+            Ok(Ok(0)) => break Error::Io(io::ErrorKind::UnexpectedEof.into()),
+            Ok(Ok(bytes_read)) => read.extend_from_slice(&buffer[..bytes_read]),
+            Ok(Err(e)) => break Error::Io(e),
+            Err(_) => break Error::Timeout(Timeout::Body)
+        }
+    };
+
+    let _ = sender.send(Err(io::Error::other(error))).await;
+    false
+}
+
+/// Body of a request that the event loop reads off the connection, as seen by a blocking thread
+///
+/// # Arguments
+/// * `receiver` - Chunks sent by [`send_body`].
+/// * `chunk` - Chunk being consumed.
+/// * `offset` - Where the bytes not consumed yet start in `chunk`.
+/// * `ended` - Whether the empty chunk that ends the body arrived.
+pub struct ChannelBody {
+    receiver: Receiver<io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    offset: usize,
+    ended: bool,
+}
+
+impl ChannelBody {
+    /// # Arguments
+    /// * `receiver: Receiver<io::Result<Vec<u8>>>` - Chunks sent by [`send_body`].
+    pub fn new(receiver: Receiver<io::Result<Vec<u8>>>) -> ChannelBody {
+        ChannelBody { receiver, chunk: Vec::new(), offset: 0, ended: false }
+    }
+}
+
+impl Read for ChannelBody {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        while self.offset == self.chunk.len() {
+            if self.ended {
+                return Ok(0);
+            }
+            match self.receiver.blocking_recv() {
+                Some(Ok(chunk)) if chunk.is_empty() => self.ended = true,
+                Some(Ok(chunk)) => {
+                    self.chunk = chunk;
+                    self.offset = 0;
+                },
+                Some(Err(e)) => return Err(e),
+                //The event loop dropped the connection without ending the body, which is never taken as whole
+                None => return Err(io::Error::other(Error::Io(io::ErrorKind::UnexpectedEof.into())))
+            }
+        }
+
+        let size = buffer.len().min(self.chunk.len() - self.offset);
+        buffer[..size].copy_from_slice(&self.chunk[self.offset..self.offset + size]);
+        self.offset += size;
+        Ok(size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    /// Decodes a body a few bytes at a time, like reads from a socket
+    fn decode(mut decoder: Decoder, bytes: &[u8], chunk: usize) -> Result<(Vec<u8>, usize), Error> {
+        let mut body = Vec::new();
+        let mut taken = 0;
+        for bytes in bytes.chunks(chunk) {
+            taken += decoder.decode(bytes, &mut body)?;
+            if decoder.is_done() {
+                break;
+            }
+        }
+        assert!(decoder.is_done());
+        Ok((body, taken))
+    }
+
+    /// Connected pair of sockets, the first one writes and the second one reads
+    fn socket_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let writer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (reader, _) = listener.accept().unwrap();
+        (writer, reader)
+    }
+
+    #[test]
+    fn decodes_bodies_sized_by_content_length() {
+        assert!(Decoder::length(0).is_done());
+        assert_eq!(decode(Decoder::length(5), b"hello", 2).unwrap(), (b"hello".to_vec(), 5));
+        assert_eq!(Decoder::length(5).wanted(8192), 5);
+    }
+
+    #[test]
+    fn decodes_chunked_bodies_split_anywhere() {
+        let body = b"5;name=value\r\nhello\r\n6\r\n world\r\n0\r\nExpires: never\r\n\r\n";
+        for chunk in [1, 2, 3, 7, body.len()] {
+            assert_eq!(decode(Decoder::chunked(100), body, chunk).unwrap(), (b"hello world".to_vec(), body.len()));
+        }
+    }
+
+    #[test]
+    fn leaves_the_bytes_past_the_body() {
+        let (body, taken) = decode(Decoder::chunked(100), b"2\r\nab\r\n0\r\n\r\nGET / HTTP/1.1", 64).unwrap();
+        assert_eq!(body, b"ab");
+        assert_eq!(taken, 12);
+        assert_eq!(decode(Decoder::length(2), b"abcd", 64).unwrap().1, 2);
+    }
+
+    #[test]
+    fn refuses_chunked_bodies_over_the_limit() {
+        let body = b"4\r\nabcd\r\n4\r\nefgh\r\n0\r\n\r\n";
+        assert!(decode(Decoder::chunked(8), body, 3).is_ok());
+        assert!(matches!(decode(Decoder::chunked(7), body, 3), Err(Error::TooLarge(_))));
+    }
+
+    #[test]
+    fn refuses_malformed_chunks() {
+        for body in [&b"zz\r\n"[..], b"2\r\nabc\r\n", b"-1\r\n"] {
+            assert!(matches!(Decoder::chunked(100).decode(body, &mut Vec::new()), Err(Error::Malformed(_))));
+        }
+        let long = format!("1{}\r\n", "0".repeat(MAX_LINE_SIZE));
+        assert!(matches!(Decoder::chunked(u64::MAX).decode(long.as_bytes(), &mut Vec::new()), Err(Error::Malformed(_))));
+    }
+
+    #[test]
+    fn reads_a_body_off_the_connection() {
+        let (mut writer, mut reader) = socket_pair();
+        writer.write_all(b"llo\r\n0\r\n\r\n").unwrap();
+
+        let mut body = Body::new(&mut reader, Decoder::chunked(100), b"5\r\nhe".to_vec(), Duration::from_secs(5));
+        let mut content = Vec::new();
+        body.read_to_end(&mut content).unwrap();
+        assert_eq!(content, b"hello");
+        assert!(body.is_whole());
+    }
+
+    #[test]
+    fn never_takes_a_body_cut_short_as_whole() {
+        let (mut writer, mut reader) = socket_pair();
+        writer.write_all(b"hel").unwrap();
+        drop(writer);
+
+        let mut body = Body::new(&mut reader, Decoder::length(5), Vec::new(), Duration::from_secs(5));
+        let e = body.read_to_end(&mut Vec::new()).unwrap_err();
+        assert!(matches!(e.downcast::<Error>(), Ok(Error::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof));
+    }
+
+    #[test]
+    fn gives_up_on_a_slow_body() {
+        let (_writer, mut reader) = socket_pair();
+
+        let mut body = Body::new(&mut reader, Decoder::length(5), Vec::new(), Duration::from_millis(50));
+        let e = body.read_to_end(&mut Vec::new()).unwrap_err();
+        assert!(matches!(e.downcast::<Error>(), Ok(Error::Timeout(Timeout::Body))));
+    }
+
+    #[test]
+    fn passes_a_body_from_the_event_loop_to_a_thread() {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        let reading = std::thread::spawn(move || {
+            let mut content = Vec::new();
+            ChannelBody::new(receiver).read_to_end(&mut content).map(|_| content)
+        });
+
+        let mut stream = &b"6\r\n world\r\n0\r\n\r\n"[..];
+        let whole = runtime.block_on(send_body(&mut stream, Decoder::chunked(100), b"5\r\nhello\r\n".to_vec(), Duration::from_secs(5), sender));
+        assert!(whole);
+        assert_eq!(reading.join().unwrap().unwrap(), b"hello world");
+    }
+
+    #[test]
+    fn passes_on_a_body_cut_short_as_an_error() {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        let reading = std::thread::spawn(move || ChannelBody::new(receiver).read_to_end(&mut Vec::new()));
+
+        let mut stream = &b"hel"[..];
+        assert!(!runtime.block_on(send_body(&mut stream, Decoder::length(5), Vec::new(), Duration::from_secs(5), sender)));
+        assert!(reading.join().unwrap().is_err());
+
+        //A sender dropped before the end of the body is never taken as its end either
+        let (sender, receiver) = tokio::sync::mpsc::channel::<io::Result<Vec<u8>>>(1);
+        drop(sender);
+        assert!(ChannelBody::new(receiver).read_to_end(&mut Vec::new()).is_err());
+    }
+}
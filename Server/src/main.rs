@@ -1,16 +1,36 @@
 use std::fs;
+use std::io;
 use std::net::TcpListener;
-use std::net::TcpStream;
+use std::net::{SocketAddr, TcpStream};
 use std::io::prelude::*;
-use std::thread;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::thread::sleep;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread;
 use std::time::Duration;
 use rand::Rng;
 use sha2::{Sha256, Digest};
 use colored::*;
 
+mod acl;
+mod audit;
+mod config;
+mod csrf;
+mod error;
+mod event_loop;
+mod heartbeat;
+mod http;
+mod quota;
+mod redaction;
+mod storage;
+mod validation;
+use acl::{Operation, Policy, User, Users};
+use audit::{AuditLog, ContentHash};
+use config::{Config, IoMode};
+use error::ServerError;
+use shared::pool::WorkerPool;
+use redaction::Redaction;
+use shared::shutdown::Shutdown;
 
 /// Returns a random String
 /// 
@@ -31,50 +51,119 @@ fn generate_secret_key_string(size: usize, charset: &[u8]) -> String {
 /// # Arguments
 /// 
 /// * `message: String` - Message that will be printed out.
-fn report(message: String) -> () {
+fn report(message: String) {
     println!("[{}] {} {}", "SERVER".blue(), "::".yellow(), message.truecolor(0, 255, 234));
 }
 
-/// Try to register a secret-key at proxy
+/// Sends the secret-key to one of the proxy's key endpoints
 /// 
 /// # Arguments
 /// 
-/// * `secret: &str` - String reference that contains the secret-key.
+/// * `path: &str` - Endpoint, like ```/register-secret```.
+/// * `state: &ServerState` - Server data, which holds the secret-key, the address the proxy reaches the server at and the timeouts.
 /// 
 /// ## Returns
-/// Nothing if the registration is successfull
+/// Nothing if the proxy accepted it
 /// A String if any error occurr
-fn register_with_proxy(secret: &str) -> Result<(), String> {
-    match TcpStream::connect("0.0.0.0:2006") {
+fn send_key_to_proxy(path: &str, state: &ServerState) -> Result<(), String> {
+    let secret = state.secret.as_str();
+    let timeout = Duration::from_secs(state.config.header_timeout);
+    match TcpStream::connect_timeout(&SocketAddr::from(([0, 0, 0, 0], 2006)), timeout) {
         Ok(mut stream) => {
+            stream.set_read_timeout(Some(timeout)).map_err(|e| format!("Could not set timeouts: {}", e))?;
+            stream.set_write_timeout(Some(timeout)).map_err(|e| format!("Could not set timeouts: {}", e))?;
             let request = format!(
-                "POST /register-secret HTTP/1.1\r\n\
+                "POST {} HTTP/1.1\r\n\
                 Host: 0.0.0.0:2006\r\n\
+                Backend-Id: {}\r\n\
+                Backend-Address: {}\r\n\
+                Backend-Port: {}\r\n\
+                Backend-Weight: {}\r\n\
+                Backend-Prefixes: {}\r\n\
                 Content-Type: text/plain\r\n\
                 Content-Length: {}\r\n\
                 \r\n\
                 {}",
+                path,
+                state.config.backend_id,
+                state.config.address.ip(),
+                state.config.address.port(),
+                state.config.weight,
+                state.config.prefixes.join(", "),
                 secret.len(),
                 secret
             );
 
-            stream.write(request.as_bytes()).unwrap();
-            stream.flush().unwrap();
+            stream.write_all(request.as_bytes()).and_then(|_| stream.flush()).map_err(|e| format!("Could not send the key: {}", e))?;
 
             let mut response_buffer = [0; 512];
-            stream.read(&mut response_buffer).unwrap();
-            let response_str = String::from_utf8_lossy(&response_buffer);
+            let bytes_read = stream.read(&mut response_buffer).map_err(|e| format!("Proxy did not answer: {}", e))?;
+            let response_str = String::from_utf8_lossy(&response_buffer[..bytes_read]);
 
             if response_str.starts_with("HTTP/1.1 200 OK") {
-                report(format!("Secret Key has been setted up with proxy."));
-                stream.shutdown(std::net::Shutdown::Both).unwrap();
+                let _ = stream.shutdown(std::net::Shutdown::Both);
                 Ok(())
             } else {
-                Err(format!("Secret Key registration have failed. Proxy's answer: {}", response_str))
+                Err(format!("Proxy refused ({}). Proxy's answer: {}", path, response_str))
             }
         },
-        Err(_) => Err(format!("Connection with proxy have failed!"))
+        Err(_) => Err("Connection with proxy have failed!".to_string())
+    }
+}
+
+/// Try to register a secret-key at proxy
+/// 
+/// # Arguments
+/// 
+/// * `state: &ServerState` - Server data, which holds the secret-key.
+fn register_with_proxy(state: &ServerState) -> Result<(), String> {
+    send_key_to_proxy("/register-secret", state)?;
+    report("Secret Key has been setted up with proxy.".to_string());
+    Ok(())
+}
+
+/// Removes the secret-key from the proxy, which then answers 503 instead of forwarding to this server
+/// 
+/// # Arguments
+/// 
+/// * `state: &ServerState` - Server data, which holds the secret-key.
+fn deregister_from_proxy(state: &ServerState) -> Result<(), String> {
+    send_key_to_proxy("/deregister-secret", state)?;
+    report("Secret Key has been removed from proxy.".to_string());
+    Ok(())
+}
+
+/// Reports that the server is shutting down and removes its secret-key from the proxy
+/// 
+/// # Arguments
+/// 
+/// * `state: &ServerState` - Server data, which holds the secret-key.
+/// * `heartbeat: thread::JoinHandle<()>` - Heartbeat thread, which stops once a shutdown was asked for.
+fn stop_accepting(state: &ServerState, heartbeat: thread::JoinHandle<()>) {
+    report("Shutting down >>> Waiting for open connections to finish".to_string());
+    state.closing.store(true, Ordering::Relaxed);
+    //A heartbeat still on its way would register the key again right after it was removed
+    let _ = heartbeat.join();
+    //Without the key the proxy answers 503 instead of forwarding to a server that is going away
+    if let Err(e) = deregister_from_proxy(state) {
+        report(format!("Could not remove Secret Key from proxy: {}", e));
+    }
+}
+
+/// Answers the proxy's health check, only reached by requests signed with the secret-key
+/// 
+/// ## Returns
+/// A 200 response if the folders the server works with can be read
+/// A 503 error if they can not
+fn health_check() -> Result<String, ServerError> {
+    for folder in ["./pages", "./data"] {
+        if let Err(e) = fs::read_dir(folder) {
+            return Err(ServerError::Unavailable(format!("Health check failed, could not read ({}): {}", folder, e)));
+        }
     }
+
+    report("Health check passed >>> Sending 200 response".to_string());
+    Ok("HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nCache-Control: no-store\r\nContent-Length: 2\r\n\r\nok".to_string())
 }
 
 /// Container that store request data
@@ -85,97 +174,419 @@ fn register_with_proxy(secret: &str) -> Result<(), String> {
 /// * `method` - Request's method.
 /// * `uri` - Request's path.
 /// * `host` - Request's host.
-/// * `body` - Request's body.
+/// * `body` - Request's body, as bytes. Empty for uploads, whose file goes straight to disk as it arrives.
 /// * `file_name` - Request's file name.
+/// * `headers` - Request's header lines as (name, value) pairs.
+/// * `client` - IP of the client that made the request.
+/// * `reservation` - Quota held by an upload until its file is stored.
 #[allow(dead_code)]
 struct Request {
     signature: String,
     method: String,
     uri: String,
     host: String,
-    body: String,
+    body: Vec<u8>,
     file_name: String,
+    headers: Vec<(String, String)>,
+    client: String,
+    reservation: Option<quota::Reservation>,
+}
+
+impl Request {
+    /// Returns the value of a header, ignoring the case of its name
+    ///
+    /// # Arguments
+    /// * `name: &str` - Header's name.
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Whether the request uploads a file, whose body is checked and stored as it arrives
+    fn is_upload(&self) -> bool {
+        self.method == "POST" && self.uri == "/upload"
+    }
+}
+
+/// Container that store everything a connection needs to be handled
+///
+/// # Arguments
+/// * `secret` - Secret-key registered at the proxy.
+/// * `users` - Accounts that can authenticate.
+/// * `policy` - Access control list of ```./data```.
+/// * `config` - Server's settings.
+/// * `redaction` - PII redaction rules of ```./data```.
+/// * `audit` - Audit log of file operations.
+/// * `timed_out` - Amount of connections closed because the peer was too slow.
+/// * `closing` - Whether the server is shutting down, so connections are no longer kept alive.
+/// * `quota` - Bytes stored inside ```./data``` and held by the uploads that are being stored.
+struct ServerState {
+    secret: String,
+    users: Users,
+    policy: Policy,
+    config: Config,
+    redaction: Redaction,
+    audit: AuditLog,
+    timed_out: AtomicU64,
+    closing: AtomicBool,
+    quota: quota::Quota,
+}
+
+/// Reports an error and builds the page that answers it
+///
+/// # Arguments
+/// * `state: &ServerState` - Server data, which holds the timed out connections counter.
+/// * `error: &ServerError` - Why the request was given up.
+///
+/// ## Returns
+/// The whole response, or None if the client must not be answered
+fn error_response(state: &ServerState, error: &ServerError) -> Option<String> {
+    let mut context = error.to_string();
+    if let ServerError::Timeout(_) = error {
+        let total = state.timed_out.fetch_add(1, Ordering::Relaxed) + 1;
+        context = format!("{} (timed out connections: {})", context, total);
+    }
+
+    //A peer that never sent anything, or that is already gone, does not get an answer
+    let response = error.response();
+    match response {
+        Some(_) => report(format!("{} >>> Sending {} response", context, error.code())),
+        None => report(format!("{} >>> Closing connection", context))
+    }
+
+    response
+}
+
+/// Reports an error and answers the client with its page
+///
+/// # Arguments
+/// * `stream: &mut TcpStream` - Stream that holds the connection.
+/// * `state: &ServerState` - Server data, which holds the timed out connections counter.
+/// * `error: ServerError` - Why the request was given up.
+fn send_error(stream: &mut TcpStream, state: &ServerState, error: ServerError) {
+    if let Some(response) = error_response(state, &error)
+        && let Err(e) = stream.write_all(response.as_bytes()).and_then(|_| stream.flush()) {
+        report(format!("Could not send the {} response: {}", error.code(), e));
+    }
+}
+
+/// Reads a file that the server needs to answer a request
+///
+/// # Arguments
+/// * `path: &str` - Path of the file.
+fn read_file(path: &str) -> Result<String, ServerError> {
+    fs::read_to_string(path).map_err(|e| ServerError::Internal(format!("Could not read ({}): {}", path, e)))
+}
+
+/// Finds a requested file inside its folder, following any links, so it can never name a file outside of it
+///
+/// # Arguments
+/// * `folder: &str` - Folder the file is served from, ```pages``` or ```data```.
+/// * `file: &str` - Requested file, relative to its folder.
+///
+/// ## Returns
+/// The real path of the file and, when it lies inside ```./data```, its path relative to that folder
+/// A 404 error if the file does not exist, a 400 error if it lies outside of its folder
+fn resolve(folder: &str, file: &str) -> Result<(PathBuf, Option<String>), ServerError> {
+    let not_found = || ServerError::NotFound(format!("Requested file ({}) was not found", file));
+    let root = fs::canonicalize(format!("./{}", folder)).map_err(|_| not_found())?;
+    let path = fs::canonicalize(root.join(file)).map_err(|_| not_found())?;
+    if !path.starts_with(&root) {
+        return Err(ServerError::BadRequest(format!("Requested file ({}) is outside of its folder", file)));
+    }
+
+    //Told apart from the folder asked for, so the ACL and redaction follow where the file really is
+    let data_path = fs::canonicalize("./data").ok()
+        .and_then(|data| path.strip_prefix(data).ok().map(|relative| relative.to_string_lossy().to_string()));
+
+    Ok((path, data_path))
+}
+
+/// Records an operation made by a request in the audit log
+///
+/// # Arguments
+/// * `state: &ServerState` - Server data, which holds the audit log.
+/// * `request: &Request` - Request that made the operation.
+/// * `user: &User` - User that made the request.
+/// * `operation: &str` - Operation, like ```list```, ```read```, ```upload``` or ```delete```.
+/// * `path: &str` - File or URI the operation was made on.
+/// * `content: &[u8]` - Content that was read or written, empty when there is none.
+/// * `outcome: &str` - How the request ended, like ```ok```, ```denied``` or ```refused```.
+fn audit(state: &ServerState, request: &Request, user: &User, operation: &str, path: &str, content: &[u8], outcome: &str) {
+    audit_hashed(state, request, user, operation, path, &ContentHash::of(content), outcome);
+}
+
+/// Records an operation like [`audit`], for content that was hashed as it went by
+///
+/// # Arguments
+/// * `state: &ServerState` - Server data, which holds the audit log.
+/// * `request: &Request` - Request that made the operation.
+/// * `user: &User` - User that made the request.
+/// * `operation: &str` - Operation, like ```upload```.
+/// * `path: &str` - File the operation was made on.
+/// * `content: &ContentHash` - Size and hash of the content that was written.
+/// * `outcome: &str` - How the request ended.
+fn audit_hashed(state: &ServerState, request: &Request, user: &User, operation: &str, path: &str, content: &ContentHash, outcome: &str) {
+    state.audit.record(audit::Entry {
+        client: &request.client,
+        user: &user.name,
+        operation,
+        path,
+        content,
+        signature_valid: request.signature == state.secret,
+        outcome,
+    });
 }
 
 /// Turn a request string into a struct
 /// # Arguments
 /// * `request: String` - Request that will be processed.
-fn parse(request: String) -> Request {
-    let method;
-    let path;
-    let host;
-    let body;
-    let proxy_signature;
-    let main_header;
-    let f_name;
-    if request.contains("X-Proxy-Signature") {
-        let proxy_signature_line = request.lines().nth(0).unwrap();
-        proxy_signature = proxy_signature_line.split_once(": ").unwrap_or(("N/A", "N/A")).1;
-        main_header = request.lines().skip(1).next().unwrap();
+///
+/// ## Returns
+/// The request, or a 400 error if it has no request line
+fn parse(request: String) -> Result<Request, ServerError> {
+    let mut lines = request.lines();
+    let proxy_signature = if request.starts_with("X-Proxy-Signature") {
+        let proxy_signature_line = lines.next().unwrap_or_default();
+        proxy_signature_line.split_once(": ").unwrap_or(("N/A", "N/A")).1
     } else {
-        proxy_signature = "N/A";
-        main_header = request.lines().next().unwrap();
+        "N/A"
+    };
+    let Some(main_header) = lines.next() else {
+        return Err(ServerError::BadRequest("Request is empty".to_string()));
+    };
+
+    let mut headers = Vec::new();
+    for line in lines.by_ref() {
+        match line.split_once(": ") {
+            Some((name, value)) => headers.push((name.to_string(), value.to_string())),
+            None => break
+        }
     }
 
     let mut parts = main_header.split_whitespace();
-    method = parts.next().unwrap();
-    path = parts.next().unwrap();
-    host = "0.0.0.0:2006";
-    body = request.split_once("\r\n\r\n").unwrap().1;
-    if method == "POST" && path == "/upload" {
-        f_name = request.lines().nth(3).unwrap().split_once(": ").unwrap().1;
-    } else {
-        f_name = "None file has been passed";
-    }
+    let (Some(method), Some(path)) = (parts.next(), parts.next()) else {
+        return Err(ServerError::BadRequest(format!("Malformed request line ({})", main_header)));
+    };
+    let host = "0.0.0.0:2006";
+    let body = request.split_once("\r\n\r\n").map(|(_, body)| body).unwrap_or_default();
 
-    Request {
+    let mut request = Request {
         method: method.to_string(),
         signature: proxy_signature.to_string(),
         uri: path.to_string(),
         host: host.to_string(),
-        body: body.to_string(),
-        file_name: f_name.to_string()
+        body: body.as_bytes().to_vec(),
+        file_name: "None file has been passed".to_string(),
+        headers,
+        client: "N/A".to_string(),
+        reservation: None
+    };
+    if request.is_upload() {
+        request.file_name = storage::sanitize_name(request.header("File-Name").unwrap_or_default());
     }
+
+    Ok(request)
 }
 
 /// Handles the connection of a stream
 /// 
 /// # Arguments
 /// * `mut stream: TcpStream` - Stream that holds the connection.
-/// * `secret: Arc<String>` - Smart Pointer that holds the secret-key.
+/// * `state: Arc<ServerState>` - Smart Pointer that holds the secret-key and access control data.
 /// 
 /// # Functionality
 /// It recognizes a request, dissect it and if the request has the secret-key signature right,
 /// sends the important parts of request to be routed. If the request has not the secret-key signature right,
 /// or does not have any secret-key signature, it sends a error back.
-fn handle_connection(mut stream: TcpStream, secret: Arc<String>) {
-    let mut buffer = [0; 8192];
-    stream.read(&mut buffer).unwrap();
+/// Any error that gives up the request is reported and answered with its page.
+fn handle_connection(mut stream: TcpStream, state: Arc<ServerState>) {
+    //The proxy reuses its connections, so one connection may carry many requests
+    let mut reused = false;
+    loop {
+        match handle_request(&mut stream, &state, reused) {
+            Ok(true) => reused = true,
+            Ok(false) => break,
+            Err(e) => {
+                send_error(&mut stream, &state, e);
+                break;
+            }
+        }
+    }
+}
+
+/// Reads, checks and routes the request of a connection
+///
+/// # Arguments
+/// * `stream: &mut TcpStream` - Stream that holds the connection.
+/// * `state: &ServerState` - Server data, used to check the request.
+/// * `reused: bool` - Whether the connection already carried a request.
+///
+/// ## Returns
+/// Whether the connection stays open for another request
+fn handle_request(stream: &mut TcpStream, state: &ServerState, reused: bool) -> Result<bool, ServerError> {
+    let config = &state.config;
+    stream.set_write_timeout(Some(Duration::from_secs(config.write_timeout)))?;
+
+    let idle_timeout = Duration::from_secs(if reused { config.keep_alive_timeout } else { config.idle_timeout });
+    let (request_head, body_start) = match http::read_head(stream, idle_timeout, Duration::from_secs(config.header_timeout)) {
+        //A reused connection that the proxy closes or leaves idle ends without an answer
+        Ok((head, _)) if reused && head.is_empty() => return Ok(false),
+        Err(http::Error::Timeout(http::Timeout::Idle)) if reused => return Ok(false),
+        head => head?
+    };
+
+    let (mut request, user, decoder) = check_request(state, request_head, stream.peer_addr()?)?;
+    let mut keep_alive = keeps_alive(state, &request, &body_start);
+    if request.is_upload() {
+        let mut body = http::Body::new(stream, decoder, body_start, Duration::from_secs(config.body_timeout));
+        receive_upload(state, &mut request, &user, &mut body)?;
+        keep_alive &= body.is_whole();
+    }
+
+    let response = keep_alive_response(route(request, state, &user)?, keep_alive);
+    stream.write_all(response.as_bytes())?;
+    stream.flush()?;
+
+    Ok(keep_alive)
+}
+
+/// Whether a connection stays open for another request once a request is answered
+///
+/// # Arguments
+/// * `state: &ServerState` - Server data, which holds the keep-alive setting.
+/// * `request: &Request` - Request that is being answered.
+/// * `body_start: &[u8]` - Bytes read along with the request head.
+///
+/// Only requests that ask for it with ```Connection: keep-alive```, like the proxy's, keep their connection.
+fn keeps_alive(state: &ServerState, request: &Request, body_start: &[u8]) -> bool {
+    let asked = request.header("Connection").is_some_and(|value| value.trim().eq_ignore_ascii_case("keep-alive"));
+    //Bytes after a request without body would be taken as the start of the next one, an upload's body tells where it ends
+    let whole = request.is_upload() || body_start.is_empty();
+    asked && whole && state.config.keep_alive_timeout > 0 && !state.closing.load(Ordering::Relaxed)
+}
 
-    let request_string = String::from_utf8_lossy(&buffer[..]);
+/// Tells the proxy that the connection stays open after a response
+///
+/// # Arguments
+/// * `response: String` - Whole response.
+/// * `keep_alive: bool` - Whether the connection stays open.
+fn keep_alive_response(response: String, keep_alive: bool) -> String {
+    if keep_alive {
+        response.replacen("\r\n", "\r\nConnection: keep-alive\r\n", 1)
+    } else {
+        response
+    }
+}
 
-    let request = parse(request_string.to_string());
+/// Turns a request head into a request and checks it before its body is read
+///
+/// # Arguments
+/// * `state: &ServerState` - Server data, used to check the request.
+/// * `request_head: String` - Request line and headers.
+/// * `peer: SocketAddr` - Address of who sent the request.
+///
+/// ## Returns
+/// The request, the user that made it and the decoder of the body that must be read
+fn check_request(state: &ServerState, request_head: String, peer: SocketAddr) -> Result<(Request, User, http::Decoder), ServerError> {
+    let mut request = parse(request_head)?;
+    //Only the proxy can tell who the client is, everyone else is the client itself
+    request.client = match request.header("X-Forwarded-For") {
+        Some(client) if request.signature == state.secret => client.trim().to_string(),
+        _ => peer.ip().to_string()
+    };
 
     report(format!("Received new request => \nSignature: {}\nMethod: {}\nURI: {}\nHost: {}\nProvider: {}\n\nBody: {}\n",
-                            request.signature, request.method, request.uri, stream.peer_addr().unwrap(),request.host, request.body));
+                            request.signature, request.method, request.uri, peer, request.host, String::from_utf8_lossy(&request.body)));
     
-    if request.signature == secret.as_str() {
-        report(format!("Request Signature Validated >>> Routing"));
-        route(request, stream);
+    if request.signature != state.secret {
+        audit(state, &request, &User::anonymous(), &request.method.to_lowercase(), &request.uri, &[], "denied-signature");
+        return Err(ServerError::Forbidden("Request Signature is invalid".to_string()));
+    }
+
+    let user = state.users.authenticate(request.header("Authorization"));
+    report(format!("Request Signature Validated >>> Routing as user ({})", user.name));
+
+    if matches!(request.method.as_str(), "POST" | "PUT" | "DELETE")
+        && !csrf::verify(&state.secret, request.header("Cookie"), request.header("X-CSRF-Token")) {
+        audit(state, &request, &user, &request.method.to_lowercase(), &request.uri, &[], "refused-csrf");
+        return Err(ServerError::InvalidCsrf(format!("Request ({} {}) has an invalid CSRF token", request.method, request.uri)));
+    }
+
+    //Uploads are checked before their body is read
+    if !request.is_upload() {
+        return Ok((request, user, http::Decoder::length(0)));
+    }
+    if !state.policy.allows(&user, Operation::Upload, &request.file_name) {
+        audit(state, &request, &user, "upload", &request.file_name, &[], "denied");
+        return Err(ServerError::denied(&user, format!("User ({}) is not allowed to upload ({})", user.name, &request.file_name)));
+    }
+
+    //A chunked upload, which the proxy streams as it arrives, tells at most how big its file is
+    let chunked = request.header("Transfer-Encoding").is_some_and(|encoding| encoding.trim().eq_ignore_ascii_case("chunked"));
+    let size = if chunked {
+        request.header("Upload-Max-Length").and_then(|l| l.trim().parse().ok()).unwrap_or(u64::MAX).min(state.config.max_upload_size)
     } else {
-        report(format!("Request Signature is invalid >>> Sending 403 Response"));
-        let contents = fs::read_to_string("./pages/403.html").unwrap();
+        request.header("Content-Length").and_then(|l| l.trim().parse().ok()).unwrap_or(0)
+    };
+    match state.quota.reserve(&state.config, &user.name, size) {
+        Ok(reservation) => request.reservation = Some(reservation),
+        Err(limit) => {
+            audit(state, &request, &user, "upload", &request.file_name, &[], "refused-size");
+            return Err(ServerError::PayloadTooLarge(format!("Upload ({}) refused: {}", &request.file_name, limit)));
+        }
+    }
 
-        let response = format!(
-            "HTTP/1.1 403 FORBIDDEN\r\nContent-Length: {}\r\nContent-Type: text/html\r\n\r\n{}",
-            contents.len(),
-            contents
-        );
+    //The body is never read past what the quota holds, so an upload can not write more than it reserved
+    let decoder = if chunked { http::Decoder::chunked(size) } else { http::Decoder::length(size) };
+    Ok((request, user, decoder))
+}
 
-        stream.write(response.as_bytes()).unwrap();
-        stream.flush().unwrap();
+/// Checks the content of an upload as it arrives and stores it inside ```./data```
+///
+/// # Arguments
+/// * `state: &ServerState` - Server data, which holds the accepted types.
+/// * `request: &mut Request` - Upload request, which gets the name its file was stored with.
+/// * `user: &User` - User that made the request.
+/// * `body: &mut dyn Read` - Body of the upload, read off the connection while it is stored.
+///
+/// Only the first bytes, which tell the type of the content, are held to be checked.
+/// The rest goes straight to disk, so an upload takes the same memory whatever its size.
+fn receive_upload(state: &ServerState, request: &mut Request, user: &User, body: &mut dyn Read) -> Result<(), ServerError> {
+    //Failures of the connection come back from the body as they were, anything else is the disk's
+    let failed = |e: io::Error, context: &str| match e.downcast::<http::Error>() {
+        Ok(e) => {
+            if let http::Error::TooLarge(_) = e {
+                audit(state, request, user, "upload", &request.file_name, &[], "refused-size");
+            }
+            ServerError::from(e)
+        },
+        Err(e) => ServerError::Internal(format!("Could not {} ({}): {}", context, &request.file_name, e))
+    };
+
+    let mut start = Vec::new();
+    (&mut *body).take(validation::SNIFF_SIZE as u64).read_to_end(&mut start).map_err(|e| failed(e, "read"))?;
+    if let Err(reason) = validation::check_upload(&state.config, &request.file_name, &start) {
+        audit(state, request, user, "upload", &request.file_name, &start, "refused-type");
+        return Err(ServerError::UnsupportedMediaType {
+            context: format!("Upload ({}) refused: {}", &request.file_name, reason),
+            reason
+        });
+    }
+
+    report("Storing file of (POST) request".to_string());
+    let mut content = audit::Hashed::new(start.as_slice().chain(body));
+    let stored_name = storage::store(&request.file_name, &mut content).map_err(|e| failed(e, "store"))?;
+    quota::record_owner(&stored_name, &user.name);
+    if let Some(reservation) = request.reservation.take() {
+        reservation.stored(content.hash().size());
     }
+    audit_hashed(state, request, user, "upload", &stored_name, content.hash(), "ok");
+    report(format!("Client's file has been created as ({})", stored_name));
+    request.file_name = stored_name;
 
+    Ok(())
 }
 
 /// Secure texts that will be send in a html file
@@ -209,46 +620,116 @@ fn fill_template(html_template: &str, placeholder: &str, data: &str) -> String {
     html_template.replace(placeholder, &safe_data)
 }
 
-/// List all files in ```./data``` folder
-fn list_files() -> String {
+/// List all files in ```./data``` folder that an user is allowed to list and read
+///
+/// # Arguments
+/// * `user: &User` - User the listing is made for.
+/// * `policy: &Policy` - Access control list that decides which files are shown.
+/// * `csrf_token: &str` - Token placed in the page's forms.
+fn list_files(user: &User, policy: &Policy, csrf_token: &str) -> Result<String, ServerError> {
     let path = Path::new("./data");
-    let all_files = fs::read_dir(path).unwrap();
+    let all_files = fs::read_dir(path).map_err(|e| ServerError::Internal(format!("Could not list ./data: {}", e)))?;
 
     let mut file_names = Vec::new();
 
     for file in all_files {
-        let file = file.unwrap();
-        let file_type = file.file_type().unwrap();
+        let file = file.map_err(|e| ServerError::Internal(format!("Could not list ./data: {}", e)))?;
+        //A file removed while listing is just not shown
+        let Ok(file_type) = file.file_type() else {
+            continue;
+        };
 
         if file_type.is_file() {
             let file_os_name = file.file_name();
             let file_name = file_os_name.to_string_lossy().into_owned();
-            file_names.push(file_name);
+            if policy.allows(user, Operation::List, &file_name) && policy.allows(user, Operation::Read, &file_name) {
+                file_names.push(file_name);
+            }
         }
     }
 
     file_names.sort();
     let all_names = file_names.join("\n");
 
-    let index_content = fs::read_to_string("./pages/index.html").unwrap();
+    let index_content = read_file("./pages/index.html")?;
+
+    let index_with_token = fill_template(index_content.as_str(), "{{CSRF_TOKEN}}", csrf_token);
+    Ok(fill_template(&index_with_token, "{{NOMES_DOS_ARQUIVOS}}", &all_names))
+
+}
+
+/// Deletes a file of ```./data```, named by the request's path like ```DELETE /notes.txt```
+///
+/// # Arguments
+/// * `request: &Request` - Delete request.
+/// * `state: &ServerState` - Server data, which holds the access control list, the quotas and the audit log.
+/// * `user: &User` - User that made the request.
+///
+/// Files are stored without folders, so names with one are refused, and a link is removed itself,
+/// never the file it leads to.
+///
+/// ## Returns
+/// A 204 response once the file is gone
+fn delete_file(request: &Request, state: &ServerState, user: &User) -> Result<String, ServerError> {
+    let file = request.uri.split('?').next().unwrap_or_default().replacen("/", "", 1);
+    if file.is_empty() || file.contains('/') || file.contains('\\') || file.contains('\0') || file == ".." || file == "." {
+        return Err(ServerError::BadRequest(format!("File to delete ({}) is not a name inside ./data", file)));
+    }
+
+    //Checked before looking for the file, so a user who may not delete it does not learn whether it exists
+    if !state.policy.allows(user, Operation::Delete, &file) {
+        audit(state, request, user, "delete", &file, &[], "denied");
+        return Err(ServerError::denied(user, format!("User ({}) is not allowed to delete ({})", user.name, &file)));
+    }
 
-    fill_template(index_content.as_str(), "{{NOMES_DOS_ARQUIVOS}}", &all_names)
+    let path = Path::new("./data").join(&file);
+    let size = match fs::symlink_metadata(&path) {
+        Ok(metadata) if metadata.is_file() => metadata.len(),
+        Ok(metadata) if metadata.is_symlink() => 0,
+        _ => {
+            audit(state, request, user, "delete", &file, &[], "not-found");
+            return Err(ServerError::NotFound(format!("File to delete ({}) was not found", file)));
+        }
+    };
+    fs::remove_file(&path).map_err(|e| ServerError::Internal(format!("Could not delete ({}): {}", file, e)))?;
+    state.quota.removed(quota::forget_owner(&file).as_deref(), size);
+    audit(state, request, user, "delete", &file, &[], "ok");
 
+    report(format!("User ({}) deleted ({}) >>> Sending 204 response", user.name, file));
+    Ok("HTTP/1.1 204 NO CONTENT\r\n\r\n".to_string())
 }
 
-/// Routes a request and send it back to the stream
+/// Routes a request and builds the response that is sent back
 /// 
 /// # Arguments
 /// * `request: Request` - Request that will be routed.
-/// * `mut stream: TcpStream` - Stream that holds connection.
-fn route(request: Request, mut stream: TcpStream) {
+/// * `state: &ServerState` - Server data, used to enforce the access control list and redaction rules.
+/// * `user: &User` - User that made the request.
+///
+/// Uploads reach this function with their file already stored.
+fn route(request: Request, state: &ServerState, user: &User) -> Result<String, ServerError> {
+    if request.method == "GET" && request.uri == "/healthz" {
+        return health_check();
+    }
+
+    //The client keeps its CSRF token while it is valid, so pages opened in other tabs still work
+    let csrf_token = match csrf::from_cookies(request.header("Cookie")) {
+        Some(token) if csrf::is_valid(&state.secret, token) => token.to_string(),
+        _ => csrf::generate(&state.secret)
+    };
+    let csrf_cookie = format!("Set-Cookie: {}={}; Path=/; SameSite=Strict; HttpOnly\r\n", csrf::COOKIE_NAME, csrf_token);
+
     if request.method == "GET" {
-        report(format!("Sending back routed (GET) request a response"));
+        report("Sending back routed (GET) request a response".to_string());
         let file = match &request.uri {
             s if s.contains("?") => {
-                let (_, file_var_and_value) = request.uri.split_once("?").unwrap();
-                let (_, file_name) = file_var_and_value.split_once("=").unwrap();
-                file_name.to_string()
+                let file_name = request.uri.split_once("?")
+                    .and_then(|(_, file_var_and_value)| file_var_and_value.split_once("="))
+                    .map(|(_, file_name)| file_name);
+                match file_name {
+                    Some(file_name) => file_name.to_string(),
+                    None => return Err(ServerError::BadRequest(format!("Malformed query ({})", request.uri)))
+                }
             },
             _ => {
                 let file_name = request.uri.replacen("/", "", 1);
@@ -256,111 +737,155 @@ fn route(request: Request, mut stream: TcpStream) {
             }
         };
 
+        if file.starts_with('/') || file.contains('\\') || file.contains('\0') || file.split('/').any(|part| part == "..") {
+            return Err(ServerError::BadRequest(format!("Requested file ({}) is outside of its folder", file)));
+        }
+
         let (content_type, folder) = match file.as_str() {
-            s if s.contains("html") || s == "" => {
+            s if s.is_empty() || s.ends_with(".html") => {
                 ("text/html;charset=utf-8", "pages")
             },
-            s if s.contains("css") => {
+            s if s.ends_with(".css") => {
                 ("text/css", "pages")
             },
-            s if s.contains("jpg") => {
+            s if s.ends_with(".jpg") || s.ends_with(".jpeg") => {
                 ("image/jpeg", "data")
             },
-            s if s.contains("png") => {
+            s if s.ends_with(".png") => {
                 ("image/png", "data")
             }
             _ => ("text/html;charset=utf-8", "data")
         };
 
-        let path = format!("./{}/{}", folder, file);
-
-        let filepath = Path::new(path.as_str());
-        let response;
-        if filepath.exists() {
-            report(format!("Requested file ({}) was found >>> Sending response", &file));
-            let contents = match file {
-                s if s == "" => {
-                    let index_with_files_listed = list_files();
-
-                    let index_w_fl_ofn = fill_template(&index_with_files_listed, "{{NOME_ARQUIVO_ABERTO}}", "N/A");
-                    fill_template(&index_w_fl_ofn, "{{CONTEUDO_ARQUIVO_ABERTO}}", "")
-                },
-                _ => {
-                    if !&file.ends_with(".css") {
-                        let file_content = fs::read_to_string(filepath).unwrap();
-                        let file_content = escape_html(&file_content);
-
-                        let index_with_files_listed = list_files();
-
-                        let index_w_fl_ofn = fill_template(&index_with_files_listed, "{{NOME_ARQUIVO_ABERTO}}", &file);
-                        fill_template(&index_w_fl_ofn, "{{CONTEUDO_ARQUIVO_ABERTO}}", &file_content)
-                    } else {
-                        fs::read_to_string(filepath).unwrap()
-                    }
-                    
+        //Checked before looking for the file, so a user who may not read it does not learn whether it exists
+        if folder == "data" && !state.policy.allows(user, Operation::Read, &file) {
+            audit(state, &request, user, "read", &file, &[], "denied");
+            return Err(ServerError::denied(user, format!("User ({}) is not allowed to read ({})", user.name, &file)));
+        }
+        let (resolved, data_path) = match resolve(folder, &file) {
+            Ok(resolved) => resolved,
+            Err(e) => {
+                if folder == "data" {
+                    audit(state, &request, user, "read", &file, &[], "not-found");
                 }
-            };
-            
-            response = format!(
-                "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n{}",
-                content_type,
-                contents.len(),
-                contents
-            );
-            
-        } else {
-            let contents = fs::read_to_string("./pages/404.html").unwrap();
+                return Err(e);
+            }
+        };
+        //Links may lead somewhere else inside ./data, so the policy is checked again on the real file
+        if let Some(data_path) = &data_path
+            && !state.policy.allows(user, Operation::Read, data_path) {
+            audit(state, &request, user, "read", data_path, &[], "denied");
+            return Err(ServerError::denied(user, format!("User ({}) is not allowed to read ({})", user.name, &file)));
+        }
+        let path = resolved.to_string_lossy().to_string();
 
-            report(format!("Requested file ({}) was not found >>> Sending 404 response", &file));
+        report(format!("Requested file ({}) was found >>> Sending response", &file));
+        let is_stylesheet = file.ends_with(".css") && data_path.is_none();
+        let contents = match file {
+            s if s.is_empty() => {
+                audit(state, &request, user, "list", "", &[], "ok");
+                let index_with_files_listed = list_files(user, &state.policy, &csrf_token)?;
 
-            response = format!(
-                "HTTP/1.1 404 NOT FOUND\r\nContent-Length: {}\r\nContent-Type: text/html\r\n\r\n{}",
-                contents.len(),
-                contents
-            );
-        }
-        stream.write_all(response.as_bytes()).unwrap();
-        stream.flush().unwrap();
+                let index_w_fl_ofn = fill_template(&index_with_files_listed, "{{NOME_ARQUIVO_ABERTO}}", "N/A");
+                fill_template(&index_w_fl_ofn, "{{CONTEUDO_ARQUIVO_ABERTO}}", "")
+            },
+            _ => {
+                if !is_stylesheet {
+                    let mut file_content = read_file(&path)?;
+                    //Whatever folder the name pointed at, anything that really lies inside ./data is redacted
+                    if let Some(data_path) = &data_path {
+                        audit(state, &request, user, "read", data_path, file_content.as_bytes(), "ok");
+                        file_content = state.redaction.apply(data_path, user, &file_content);
+                    }
+                    let file_content = escape_html(&file_content);
 
-    } else if request.method == "POST" && request.uri == "/upload" {
-        report(format!("Storing file of (POST) request"));
-        let mut path = format!("./data/{}", &request.file_name);
-        let mut filepath = Path::new(&path);
-        let mut counter = 2;
-        while filepath.exists() {
-            let (name, extention) = request.file_name.split_once(".").unwrap();
-            path = format!("./data/{}_{}.{}", name, counter, extention);
-            filepath = Path::new(&path);
-            counter += 1;
-        }
+                    let index_with_files_listed = list_files(user, &state.policy, &csrf_token)?;
 
-        let mut file = fs::File::create(filepath).unwrap();
+                    let index_w_fl_ofn = fill_template(&index_with_files_listed, "{{NOME_ARQUIVO_ABERTO}}", &file);
+                    fill_template(&index_w_fl_ofn, "{{CONTEUDO_ARQUIVO_ABERTO}}", &file_content)
+                } else {
+                    read_file(&path)?
+                }
+                
+            }
+        };
 
-        file.write_all(&request.body.trim_end_matches('\0').as_bytes()).unwrap();
+        //Stylesheets are the same for everyone, so the proxy may keep them and ask through their ETag whether they changed.
+        //Everything else carries the user's files and CSRF token, so only the user's browser may keep it.
+        let (cache_control, etag) = if is_stylesheet {
+            let mut hasher = Sha256::new();
+            hasher.update(contents.as_bytes());
+            ("public, max-age=60", Some(format!("\"{}\"", &hex::encode(hasher.finalize())[..16])))
+        } else {
+            ("private, no-cache", None)
+        };
+        if let Some(etag) = &etag
+            && request.header("If-None-Match").is_some_and(|tags| tags.split(',').any(|tag| tag.trim() == etag)) {
+            report("Requested stylesheet has not changed >>> Sending 304 response".to_string());
+            return Ok(format!("HTTP/1.1 304 NOT MODIFIED\r\nETag: {}\r\nCache-Control: {}\r\n\r\n", etag, cache_control));
+        }
 
-        report(format!("Client's file has been created"));
+        let response = format!(
+            "HTTP/1.1 200 OK\r\n{}Content-Type: {}\r\nCache-Control: {}\r\n{}Content-Length: {}\r\n\r\n{}",
+            if content_type.starts_with("text/html") { csrf_cookie.as_str() } else { "" },
+            content_type,
+            cache_control,
+            etag.map(|etag| format!("ETag: {}\r\n", etag)).unwrap_or_default(),
+            contents.len(),
+            contents
+        );
 
+        Ok(response)
+    } else if request.method == "POST" && request.uri == "/upload" {
         let contents = {
-            let index_with_files_listed = list_files();
+            let index_with_files_listed = list_files(user, &state.policy, &csrf_token)?;
 
             let index_w_fl_ofn = fill_template(&index_with_files_listed, "{{NOME_ARQUIVO_ABERTO}}", "N/A");
             fill_template(&index_w_fl_ofn, "{{CONTEUDO_ARQUIVO_ABERTO}}", "")
         };
 
         let response = format!(
-                "HTTP/1.1 302 MOVED PERMANENTLY\r\nLocation:/\r\nContent-Type: text/html\r\nContent-Length: {}\r\n\r\n{}",
+                "HTTP/1.1 302 MOVED PERMANENTLY\r\nLocation:/\r\n{}Content-Type: text/html\r\nContent-Length: {}\r\n\r\n{}",
+                csrf_cookie,
                 contents.len(),
                 contents
         );
 
         report("Sending back response".to_string());
 
-        stream.write(&response.as_bytes()).unwrap();
-        stream.flush().unwrap();
+        Ok(response)
+    } else if request.method == "DELETE" {
+        delete_file(&request, state, user)
+    } else {
+        Err(ServerError::BadRequest(format!("Request ({} {}) is not supported", request.method, request.uri)))
     }
 }
 
 fn main() {
+    //Hashes a password read from the standard input for ./users.txt instead of starting the server
+    if std::env::args().nth(1).as_deref() == Some("--hash-password") {
+        let mut password = String::new();
+        if let Err(e) = std::io::stdin().read_line(&mut password) {
+            eprintln!("[{}] {} {} >> {}", "SERVER".blue(), "::".yellow(), "Could not read the password".red(), e);
+            std::process::exit(1);
+        }
+        println!("{}", acl::hash_password(password.trim_end_matches(['\r', '\n'])));
+        return;
+    }
+
+    //Checks the audit log chain instead of starting the server
+    if std::env::args().nth(1).as_deref() == Some("--verify-audit") {
+        let path = std::env::args().nth(2).unwrap_or("./audit.log".to_string());
+        match audit::verify(&path) {
+            Ok(entries) => report(format!("Audit log ({}) is intact >>> {} entries verified", path, entries)),
+            Err(e) => {
+                eprintln!("[{}] {} {} >> {}", "SERVER".blue(), "::".yellow(), "Audit log has been tampered".red(), e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
     const SECRET_KEY_CHARSET: &[u8] = b"ABCDEFGIJKLMNOPQRTUVWXYZ\
                                         abcdefghijklmnopqrstuvwxyz\
                                         0123456789\
@@ -377,24 +902,93 @@ fn main() {
 
     report(format!("Secret Key Generated! >>> {}", &secret_key[0..5]));
 
-    //Server will keep trying to register secret_key on proxy until it succeeded
-    while let Err(e) = register_with_proxy(&secret_key.as_str()) {
-        eprintln!("[{}] {} {} >> {}", "SERVER".blue(), "::".yellow(), "Critical Error".red(),e);
-        sleep(Duration::new(1, 0));
-    }
+    let config = Config::load("./server.conf");
+
+    let users = Users::load("./users.txt");
+    let policy = Policy::load("./policy.txt");
+    let redaction = Redaction::load("./redaction.txt");
+    let audit = match AuditLog::open("./audit.log") {
+        Ok(audit) => audit,
+        Err(e) => {
+            eprintln!("[{}] {} {} >> {}", "SERVER".blue(), "::".yellow(), "Could not open the audit log".red(), e);
+            std::process::exit(1);
+        }
+    };
+    storage::clean_temp();
+
+    //Initializes secret_key and access control data in a smart pointer to avoid borrowing checker issues
+    let arc_state = Arc::new(ServerState { secret: secret_key, users, policy, config, redaction, audit, timed_out: AtomicU64::new(0), closing: AtomicBool::new(false), quota: quota::Quota::load() });
+
+    let address = arc_state.config.address;
+    let listener = match TcpListener::bind(address) {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("[{}] {} {} >> {}", "SERVER".blue(), "::".yellow(), format!("Could not listen at {}", address).red(), e);
+            std::process::exit(1);
+        }
+    };
 
-    //Initializes secret_key in a smart pointer to avoid borrowing checker issues
-    let arc_secret_key = Arc::new(secret_key);
+    let shutdown = match Shutdown::install(address, report) {
+        Ok(shutdown) => shutdown,
+        Err(e) => {
+            eprintln!("[{}] {} {} >> {}", "SERVER".blue(), "::".yellow(), "Could not handle shutdown signals".red(), e);
+            std::process::exit(1);
+        }
+    };
+    let deadline = Duration::from_secs(arc_state.config.shutdown_timeout);
+
+    //Registering at the proxy happens in the background, so the server listens right away
+    let heartbeat = heartbeat::start(Arc::clone(&arc_state), Arc::clone(&shutdown));
+
+    let (still_open, unfinished) = if arc_state.config.io_mode == IoMode::Async {
+        report(format!("Initialized at {} >>> event loop with {} threads", address, arc_state.config.workers));
+        match event_loop::run(listener, Arc::clone(&arc_state), shutdown, heartbeat) {
+            Ok(still_open) => (still_open, "connections"),
+            Err(e) => {
+                eprintln!("[{}] {} {} >> {}", "SERVER".blue(), "::".yellow(), "Event loop has stopped".red(), e);
+                std::process::exit(1);
+            }
+        }
+    } else {
+        let state_clone = Arc::clone(&arc_state);
+        let pool = WorkerPool::new(arc_state.config.workers, arc_state.config.queue_size, move |stream| {
+            handle_connection(stream, Arc::clone(&state_clone));
+        }, report);
 
-    let listener =  TcpListener::bind("127.0.0.1:1445").unwrap();
+        report(format!("Initialized at {} >>> {} workers", address, pool.size()));
 
-    report(format!("Initialized at {}", listener.local_addr().unwrap()));
+        for stream in listener.incoming() {
+            //The signal handler connects once to wake this loop up
+            if shutdown.is_requested() {
+                break;
+            }
+            //A connection that failed before being accepted does not stop the server
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    report(format!("Could not accept a connection: {}", e));
+                    continue;
+                }
+            };
+            //Connections beyond the queue are refused right away instead of piling up
+            if let Err(mut stream) = pool.submit(stream) {
+                let _ = stream.set_write_timeout(Some(Duration::from_secs(1)));
+                send_error(&mut stream, &arc_state, ServerError::Unavailable("Every worker is busy and the queue is full".to_string()));
+            }
+        }
 
-    for stream in listener.incoming() {
-        let stream = stream.unwrap();
-        let secret_key_clone = Arc::clone(&arc_secret_key);
-        thread::spawn(move || {
-            handle_connection(stream, secret_key_clone);
-        });
+        //New connections are refused from now on, the queued ones get until the deadline
+        drop(listener);
+        stop_accepting(&arc_state, heartbeat);
+        //Counts the workers still busy, each one holding a connection
+        (pool.shutdown(deadline), "busy workers")
+    };
+
+    if still_open > 0 {
+        report(format!("{} {} did not finish in {}s >>> Closing them", still_open, unfinished, deadline.as_secs()));
     }
+    //Entries still waiting for the audit log's writer thread must reach the disk before the process ends
+    arc_state.audit.flush();
+    report("Server has stopped".to_string());
+    let _ = std::io::stdout().flush();
 }
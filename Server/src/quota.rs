@@ -0,0 +1,321 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::prelude::*;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use crate::config::Config;
+
+/// File that records who uploaded each file of ```./data```
+const OWNERS_PATH: &str = "./owners.txt";
+
+/// Held while ```./owners.txt``` is written, so a file that is forgotten does not lose the lines appended meanwhile
+static OWNERS_LOCK: Mutex<()> = Mutex::new(());
+
+/// Sizes of the files inside a folder, along with the ones of each uploader
+///
+/// # Arguments
+/// * `data: &Path` - Folder of the files, like ```./data```.
+/// * `owners: &Path` - File that records who uploaded each file, like ```./owners.txt```.
+///
+/// ## Returns
+/// The size of all files and the size of the files of each user
+fn data_usage(data: &Path, owners: &Path) -> (u64, HashMap<String, u64>) {
+    let sizes: HashMap<String, u64> = fs::read_dir(data).into_iter()
+        .flatten()
+        .filter_map(|file| file.ok())
+        .filter_map(|file| Some((file.file_name().to_string_lossy().into_owned(), file.metadata().ok()?)))
+        .filter(|(_, metadata)| metadata.is_file())
+        .map(|(name, metadata)| (name, metadata.len()))
+        .collect();
+
+    let mut by_user = HashMap::new();
+    for line in fs::read_to_string(owners).unwrap_or_default().lines() {
+        if let Some((file, user)) = line.rsplit_once(':')
+            && let Some(size) = sizes.get(file) {
+            *by_user.entry(user.to_string()).or_insert(0) += size;
+        }
+    }
+
+    (sizes.values().sum(), by_user)
+}
+
+/// Records the uploader of a file
+///
+/// # Arguments
+/// * `file_name: &str` - Name of the file inside ```./data```.
+/// * `user: &str` - Uploader's name.
+pub fn record_owner(file_name: &str, user: &str) {
+    let _owners_lock = OWNERS_LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let owners = fs::OpenOptions::new().create(true).append(true).open(OWNERS_PATH);
+    if let Ok(mut owners) = owners {
+        let _ = writeln!(owners, "{}:{}", file_name, user);
+    }
+}
+
+/// Forgets the uploader of a file that was deleted, so a new file stored with its name is not counted for them
+///
+/// # Arguments
+/// * `file_name: &str` - Name of the file inside ```./data```.
+///
+/// ## Returns
+/// The uploader of the file, None if it was not recorded
+pub fn forget_owner(file_name: &str) -> Option<String> {
+    forget_owner_in(Path::new(OWNERS_PATH), file_name)
+}
+
+/// Forgets the uploader of a file like [`forget_owner`], in any owners file
+///
+/// # Arguments
+/// * `owners: &Path` - File that records who uploaded each file.
+/// * `file_name: &str` - Name of the file.
+fn forget_owner_in(owners: &Path, file_name: &str) -> Option<String> {
+    let _owners_lock = OWNERS_LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let contents = fs::read_to_string(owners).ok()?;
+    let mut owner = None;
+    let kept: String = contents.lines()
+        .filter(|line| match line.rsplit_once(':') {
+            Some((file, user)) if file == file_name => {
+                owner = Some(user.to_string());
+                false
+            },
+            _ => true
+        })
+        .map(|line| format!("{}\n", line))
+        .collect();
+
+    if owner.is_some() && let Err(e) = fs::write(owners, kept) {
+        crate::report(format!("Could not forget the owner of ({}): {}", file_name, e));
+    }
+    owner
+}
+
+/// Bytes that count against the quotas, kept up to date as uploads are stored
+///
+/// # Arguments
+/// * `stored` - Bytes of the files inside ```./data```.
+/// * `stored_by` - Bytes of the files uploaded by each user.
+/// * `reserved` - Bytes held for every upload that is still being received.
+/// * `reserved_by` - Bytes held for the uploads of each user.
+#[derive(Default)]
+struct Usage {
+    stored: u64,
+    stored_by: HashMap<String, u64>,
+    reserved: u64,
+    reserved_by: HashMap<String, u64>,
+}
+
+/// Keeps the quotas of ```./data``` while several uploads are stored at once
+///
+/// Each upload holds its bytes from the moment it is checked until it is stored, so uploads
+/// that arrive together can not all fit in the same free space. The folder is only measured
+/// when the server starts, then each stored upload adds its size, so files changed by hand
+/// inside ```./data``` are only counted again on the next start.
+#[derive(Default)]
+pub struct Quota {
+    usage: Arc<Mutex<Usage>>,
+}
+
+/// Bytes held for one upload, given back when it is dropped, whether the upload was stored or not
+///
+/// # Arguments
+/// * `usage` - Bytes counted against the quotas.
+/// * `user` - Uploader's name.
+/// * `size` - Bytes held for this upload.
+pub struct Reservation {
+    usage: Arc<Mutex<Usage>>,
+    user: String,
+    size: u64,
+}
+
+impl Reservation {
+    /// Counts the stored file in place of the bytes held for it
+    ///
+    /// # Arguments
+    /// * `size: u64` - Size of the stored file, at most the bytes held.
+    pub fn stored(mut self, size: u64) {
+        let mut usage = self.usage.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        usage.stored += size;
+        *usage.stored_by.entry(self.user.clone()).or_insert(0) += size;
+        release(&mut usage, &self.user, self.size);
+        drop(usage);
+        self.size = 0;
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        let mut usage = self.usage.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        release(&mut usage, &self.user, self.size);
+    }
+}
+
+/// Gives back bytes held for an upload
+///
+/// # Arguments
+/// * `usage: &mut Usage` - Bytes counted against the quotas.
+/// * `user: &str` - Uploader's name.
+/// * `size: u64` - Bytes held for the upload.
+fn release(usage: &mut Usage, user: &str, size: u64) {
+    usage.reserved -= size;
+    if let Some(held) = usage.reserved_by.get_mut(user) {
+        *held -= size;
+        if *held == 0 {
+            usage.reserved_by.remove(user);
+        }
+    }
+}
+
+impl Quota {
+    /// Measures ```./data``` once, when the server starts
+    pub fn load() -> Quota {
+        Quota::load_from(Path::new("./data"), Path::new(OWNERS_PATH))
+    }
+
+    /// Measures a data folder
+    ///
+    /// # Arguments
+    /// * `data: &Path` - Folder of the files.
+    /// * `owners: &Path` - File that records who uploaded each file.
+    fn load_from(data: &Path, owners: &Path) -> Quota {
+        let (stored, stored_by) = data_usage(data, owners);
+        Quota { usage: Arc::new(Mutex::new(Usage { stored, stored_by, ..Usage::default() })) }
+    }
+
+    /// Stops counting a file that was deleted
+    ///
+    /// # Arguments
+    /// * `owner: Option<&str>` - Uploader of the file, None if it was not recorded.
+    /// * `size: u64` - Size of the file.
+    pub fn removed(&self, owner: Option<&str>, size: u64) {
+        let mut usage = self.usage.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        usage.stored = usage.stored.saturating_sub(size);
+        if let Some(owner) = owner
+            && let Some(stored) = usage.stored_by.get_mut(owner) {
+            *stored = stored.saturating_sub(size);
+        }
+    }
+
+    /// Checks if an upload fits in the configured limits and holds its bytes if it does
+    ///
+    /// # Arguments
+    /// * `config: &Config` - Server's settings.
+    /// * `user: &str` - Uploader's name.
+    /// * `size: u64` - Size of the uploaded file, in bytes.
+    ///
+    /// ## Returns
+    /// The bytes held for the upload, which must be kept until its file is stored
+    /// A String with the violated limit if it does not fit
+    pub fn reserve(&self, config: &Config, user: &str, size: u64) -> Result<Reservation, String> {
+        if size > config.max_upload_size {
+            return Err(format!("File has {} bytes, maximum is {}", size, config.max_upload_size));
+        }
+
+        //The check and the hold happen under the same lock, so no other upload slips in between
+        let mut usage = self.usage.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if usage.stored + usage.reserved + size > config.data_quota {
+            return Err(format!("Data folder quota of {} bytes would be exceeded", config.data_quota));
+        }
+        let used_by_user = usage.stored_by.get(user).copied().unwrap_or(0) + usage.reserved_by.get(user).copied().unwrap_or(0);
+        if config.user_quota > 0 && used_by_user + size > config.user_quota {
+            return Err(format!("Quota of {} bytes of user ({}) would be exceeded", config.user_quota, user));
+        }
+        usage.reserved += size;
+        *usage.reserved_by.entry(user.to_string()).or_insert(0) += size;
+
+        Ok(Reservation { usage: Arc::clone(&self.usage), user: user.to_string(), size })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(max_upload_size: u64, data_quota: u64, user_quota: u64) -> Config {
+        Config { max_upload_size, data_quota, user_quota, ..Config::default() }
+    }
+
+    #[test]
+    fn holds_bytes_until_the_reservation_is_dropped() {
+        let quota = Quota::default();
+        let config = config(100, 100, 0);
+
+        let first = quota.reserve(&config, "ana", 60).unwrap();
+        assert!(quota.reserve(&config, "bia", 60).is_err());
+        drop(first);
+        assert!(quota.reserve(&config, "bia", 60).is_ok());
+    }
+
+    #[test]
+    fn counts_stored_files_in_place_of_their_reservation() {
+        let quota = Quota::default();
+        let config = config(100, 100, 0);
+
+        //An upload may hold more than it ends up storing, like a streamed one
+        quota.reserve(&config, "ana", 90).unwrap().stored(30);
+        assert!(quota.reserve(&config, "ana", 71).is_err());
+        assert!(quota.reserve(&config, "ana", 70).is_ok());
+    }
+
+    #[test]
+    fn limits_each_user_apart() {
+        let quota = Quota::default();
+        let config = config(100, 1000, 100);
+
+        quota.reserve(&config, "ana", 80).unwrap().stored(80);
+        let held = quota.reserve(&config, "ana", 20).unwrap();
+        assert_eq!(quota.reserve(&config, "ana", 1).err().as_deref(), Some("Quota of 100 bytes of user (ana) would be exceeded"));
+        assert!(quota.reserve(&config, "bia", 100).is_ok());
+        drop(held);
+        assert!(quota.reserve(&config, "ana", 20).is_ok());
+    }
+
+    #[test]
+    fn refuses_files_over_the_maximum_size() {
+        let quota = Quota::default();
+        assert_eq!(quota.reserve(&config(10, 100, 0), "ana", 11).err().as_deref(), Some("File has 11 bytes, maximum is 10"));
+    }
+
+    #[test]
+    fn measures_the_data_folder_and_its_owners_when_loaded() {
+        let root = std::env::temp_dir().join(format!("quota_{}", std::process::id()));
+        let data = root.join("data");
+        fs::create_dir_all(&data).unwrap();
+        fs::write(data.join("a.txt"), [0; 40]).unwrap();
+        fs::write(data.join("b.txt"), [0; 25]).unwrap();
+        fs::write(data.join("c.txt"), [0; 5]).unwrap();
+        //Files that are gone, and lines without an owner, count for nobody
+        fs::write(root.join("owners.txt"), "a.txt:ana\nb.txt:bia\ngone.txt:ana\njunk\n").unwrap();
+
+        let quota = Quota::load_from(&data, &root.join("owners.txt"));
+        let _ = fs::remove_dir_all(&root);
+        let usage = quota.usage.lock().unwrap();
+        assert_eq!(usage.stored, 70);
+        assert_eq!(usage.stored_by.get("ana"), Some(&40));
+        assert_eq!(usage.stored_by.get("bia"), Some(&25));
+    }
+
+    #[test]
+    fn stops_counting_deleted_files() {
+        let quota = Quota::default();
+        let config = config(100, 100, 60);
+
+        quota.reserve(&config, "ana", 60).unwrap().stored(60);
+        assert!(quota.reserve(&config, "ana", 1).is_err());
+        quota.removed(Some("ana"), 60);
+        assert!(quota.reserve(&config, "ana", 60).is_ok());
+        //Files without a recorded owner only count against the folder
+        quota.removed(None, 1000);
+        assert_eq!(quota.usage.lock().unwrap().stored, 0);
+    }
+
+    #[test]
+    fn forgets_the_owner_of_a_deleted_file() {
+        let owners = std::env::temp_dir().join(format!("quota_owners_{}.txt", std::process::id()));
+        fs::write(&owners, "a.txt:ana\nb.txt:bia\na.txt:caio\n").unwrap();
+
+        assert_eq!(forget_owner_in(&owners, "a.txt").as_deref(), Some("caio"));
+        assert_eq!(fs::read_to_string(&owners).unwrap(), "b.txt:bia\n");
+        assert_eq!(forget_owner_in(&owners, "gone.txt"), None);
+        let _ = fs::remove_file(&owners);
+    }
+}
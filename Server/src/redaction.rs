@@ -0,0 +1,162 @@
+use std::fs;
+use regex::Regex;
+use shared::pattern::wildcard_match;
+use crate::acl::User;
+use crate::report;
+
+/// Text that replaces every redacted match
+const MASK: &str = "[REDACTED]";
+
+/// Expression of the built-in ```email``` rule
+const EMAIL_PATTERN: &str = r"[A-Za-z0-9._%+-]+@[A-Za-z0-9.-]+\.[A-Za-z]{2,}";
+
+/// Expression of the built-in ```phone``` rule, like ```(81) 95836-7492``` or ```+55 81 958367492```
+const PHONE_PATTERN: &str = r"(\+\d{1,3}\s?)?\(?\b\d{2,3}\)?[\s.-]?\d{4,5}[\s.-]?\d{4}\b";
+
+/// A single redaction line of the rules file
+struct Rule {
+    pattern: String,
+    expression: Regex,
+}
+
+/// PII redaction rules read from ```./redaction.txt```
+///
+/// Each line has one of the formats:
+/// * ```pattern rule``` - Masks matches of `rule` in files under ```./data``` matching `pattern`,
+///   where `rule` is ```email```, ```phone``` or ```regex:<expression>```.
+/// * ```exempt subject``` - Users (```name```) or groups (```@group```) that see files unredacted.
+pub struct Redaction {
+    rules: Vec<Rule>,
+    exempt: Vec<String>,
+}
+
+impl Redaction {
+    /// Read rules from a file. A missing file redacts nothing.
+    ///
+    /// # Arguments
+    /// * `path: &str` - Path of the rules file.
+    pub fn load(path: &str) -> Redaction {
+        let contents = fs::read_to_string(path).unwrap_or_default();
+        let mut rules = Vec::new();
+        let mut exempt = Vec::new();
+
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((pattern, rule)) = line.split_once(char::is_whitespace) else {
+                report(format!("Malformed redaction line ({}) >>> Ignoring", line));
+                continue;
+            };
+            let rule = rule.trim();
+
+            if pattern == "exempt" {
+                exempt.push(rule.to_string());
+                continue;
+            }
+
+            let expression = match rule {
+                "email" => EMAIL_PATTERN,
+                "phone" => PHONE_PATTERN,
+                r if r.starts_with("regex:") => &r["regex:".len()..],
+                _ => {
+                    report(format!("Unknown redaction rule ({}) >>> Ignoring", rule));
+                    continue;
+                }
+            };
+            match Regex::new(expression) {
+                Ok(expression) => rules.push(Rule { pattern: pattern.to_string(), expression }),
+                Err(e) => report(format!("Invalid redaction expression ({}): {} >>> Ignoring", expression, e))
+            }
+        }
+
+        Redaction { rules, exempt }
+    }
+
+    /// Checks if an user sees files without redaction
+    ///
+    /// # Arguments
+    /// * `user: &User` - User that will see the file.
+    fn is_exempt(&self, user: &User) -> bool {
+        if user.is_anonymous() {
+            return false;
+        }
+        self.exempt.iter().any(|subject| match subject.strip_prefix('@') {
+            Some(group) => user.groups.iter().any(|g| g == group),
+            None => *subject == user.name
+        })
+    }
+
+    /// Masks every match of the rules that apply to a file
+    ///
+    /// # Arguments
+    /// * `path: &str` - Path of the file relative to ```./data```.
+    /// * `user: &User` - User that will see the file.
+    /// * `content: &str` - File content.
+    pub fn apply(&self, path: &str, user: &User, content: &str) -> String {
+        if self.is_exempt(user) {
+            return content.to_string();
+        }
+
+        self.rules.iter()
+            .filter(|rule| wildcard_match(&rule.pattern, path))
+            .fold(content.to_string(), |text, rule| rule.expression.replace_all(&text, MASK).into_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes rules to a temporary file and loads them
+    fn redaction(name: &str, contents: &str) -> Redaction {
+        let path = std::env::temp_dir().join(format!("redaction-test-{}-{}.txt", std::process::id(), name));
+        fs::write(&path, contents).unwrap();
+        let redaction = Redaction::load(path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+        redaction
+    }
+
+    fn user(name: &str, groups: &[&str]) -> User {
+        User { name: name.to_string(), groups: groups.iter().map(|g| g.to_string()).collect() }
+    }
+
+    #[test]
+    fn masks_emails_and_phones() {
+        let redaction = redaction("builtin", "*.txt email\n*.txt phone\n");
+        let text = "Mail ana.silva@example.com or call (81) 95836-7492 or +55 81 958367492.";
+
+        assert_eq!(
+            redaction.apply("contacts.txt", &User::anonymous(), text),
+            "Mail [REDACTED] or call [REDACTED] or [REDACTED]."
+        );
+    }
+
+    #[test]
+    fn masks_only_matching_paths() {
+        let redaction = redaction("paths", "hr/* email\n");
+        let text = "ana@example.com";
+
+        assert_eq!(redaction.apply("hr/list.txt", &User::anonymous(), text), "[REDACTED]");
+        assert_eq!(redaction.apply("public/list.txt", &User::anonymous(), text), text);
+    }
+
+    #[test]
+    fn masks_custom_expressions() {
+        let redaction = redaction("regex", "* regex:\\d{3}\\.\\d{3}\\.\\d{3}-\\d{2}\n* regex:(\n* unknown\n");
+
+        assert_eq!(redaction.apply("a.txt", &User::anonymous(), "CPF 123.456.789-00"), "CPF [REDACTED]");
+    }
+
+    #[test]
+    fn exempt_users_and_groups_see_everything() {
+        let redaction = redaction("exempt", "* email\nexempt alice\nexempt @hr\nexempt anonymous\n");
+        let text = "ana@example.com";
+
+        assert_eq!(redaction.apply("a.txt", &user("alice", &[]), text), text);
+        assert_eq!(redaction.apply("a.txt", &user("bob", &["hr"]), text), text);
+        assert_eq!(redaction.apply("a.txt", &user("carol", &["staff"]), text), "[REDACTED]");
+        assert_eq!(redaction.apply("a.txt", &User::anonymous(), text), "[REDACTED]");
+    }
+}
@@ -0,0 +1,256 @@
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+use rand::Rng;
+
+/// Folder where uploads are written before they are moved into ```./data```
+///
+/// It must be on the same filesystem as ```./data```, so files can be linked or renamed across.
+const TEMP_DIR: &str = "./tmp";
+
+/// Multi-part extensions whose parts must be kept together when renaming
+const COMPOUND_EXTENSIONS: [&str; 4] = ["tar.gz", "tar.bz2", "tar.xz", "tar.zst"];
+
+/// Reduces a client given file name to a plain name, without any folders
+///
+/// # Arguments
+/// * `file_name: &str` - File name sent by the client.
+pub fn sanitize_name(file_name: &str) -> String {
+    let name = file_name.rsplit(['/', '\\']).next().unwrap_or("").trim();
+
+    match name {
+        "" | "." | ".." => "upload".to_string(),
+        _ => name.to_string()
+    }
+}
+
+/// Returns the name a file would be stored with at a given attempt.
+/// The first attempt keeps the name, the next ones get ```_2```, ```_3```... before the extension.
+///
+/// # Arguments
+/// * `file_name: &str` - Sanitized file name.
+/// * `attempt: u32` - Attempt number, starting at 1.
+fn candidate_name(file_name: &str, attempt: u32) -> String {
+    if attempt == 1 {
+        return file_name.to_string();
+    }
+
+    let lowercase = file_name.to_lowercase();
+    let compound = COMPOUND_EXTENSIONS.iter()
+        .find(|ext| lowercase.ends_with(&format!(".{}", ext)) && lowercase.len() > ext.len() + 1);
+    let split_at = match compound {
+        Some(ext) => Some(file_name.len() - ext.len() - 1),
+        //A leading dot marks a hidden file, not an extension
+        None => file_name.rfind('.').filter(|i| *i > 0)
+    };
+
+    match split_at {
+        Some(i) => format!("{}_{}{}", &file_name[..i], attempt, &file_name[i..]),
+        None => format!("{}_{}", file_name, attempt)
+    }
+}
+
+/// Removes uploads, and the names they claimed, left behind in the temporary folder by a crash
+pub fn clean_temp() {
+    if let Ok(files) = fs::read_dir(TEMP_DIR) {
+        for file in files.flatten() {
+            let _ = fs::remove_file(file.path());
+        }
+    }
+}
+
+/// Stores an uploaded file inside ```./data``` without ever overwriting another file
+///
+/// The content is copied to a temporary file as it is read, and only moved into ```./data``` once
+/// it is whole, so ```./data``` never has partial files. A rename would replace a file that took the name in the
+/// meantime, so the file is hard linked instead, which fails atomically when the name is taken.
+/// Filesystems without hard links fall back to [`rename_into`].
+///
+/// # Arguments
+/// * `file_name: &str` - File name sent by the client.
+/// * `content: &mut R` - Reader of the file content, like the body of the upload.
+///
+/// ## Returns
+/// The name the file was stored with
+/// The error of the reader, or of the disk, otherwise
+pub fn store<R: Read + ?Sized>(file_name: &str, content: &mut R) -> io::Result<String> {
+    let file_name = sanitize_name(file_name);
+
+    fs::create_dir_all(TEMP_DIR)?;
+    let temp_path = Path::new(TEMP_DIR).join(format!("{:016x}.part", rand::rng().random::<u64>()));
+    let mut temp_file = fs::OpenOptions::new().write(true).create_new(true).open(&temp_path)?;
+    let written = io::copy(content, &mut temp_file).and_then(|_| temp_file.sync_all());
+    drop(temp_file);
+    if let Err(e) = written {
+        let _ = fs::remove_file(&temp_path);
+        return Err(e);
+    }
+
+    let data = Path::new("./data");
+    let stored = match link_into(&temp_path, data, &file_name) {
+        Err(e) if matches!(e.kind(), io::ErrorKind::Unsupported | io::ErrorKind::PermissionDenied) => rename_into(&temp_path, data, &file_name),
+        stored => stored
+    };
+
+    let _ = fs::remove_file(&temp_path);
+    stored
+}
+
+/// Hard links a whole file into a folder, under the first of its candidate names that is free
+///
+/// # Arguments
+/// * `temp_path: &Path` - Whole file, which stays where it is.
+/// * `folder: &Path` - Folder the file is linked into.
+/// * `file_name: &str` - Sanitized file name.
+///
+/// ## Returns
+/// The name the file was linked with
+fn link_into(temp_path: &Path, folder: &Path, file_name: &str) -> io::Result<String> {
+    let mut attempt = 1;
+    loop {
+        let name = candidate_name(file_name, attempt);
+        match fs::hard_link(temp_path, folder.join(&name)) {
+            Ok(()) => return Ok(name),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => attempt += 1,
+            Err(e) => return Err(e)
+        }
+    }
+}
+
+/// Renames a whole file into a folder, under the first of its candidate names that is free
+///
+/// A rename replaces whatever has the name, so each name is claimed first by an empty ```.claim``` file
+/// next to the temporary file, which fails when another upload holds the name. The claim is only
+/// removed after the rename, so another upload sees either the claim or the stored file, and the
+/// folder never shows a placeholder. Claims left by a crash are removed by [`clean_temp`].
+///
+/// # Arguments
+/// * `temp_path: &Path` - Whole file, moved out of its folder.
+/// * `folder: &Path` - Folder the file is renamed into.
+/// * `file_name: &str` - Sanitized file name.
+///
+/// ## Returns
+/// The name the file was stored with
+fn rename_into(temp_path: &Path, folder: &Path, file_name: &str) -> io::Result<String> {
+    let temp_dir = temp_path.parent().unwrap_or(Path::new(TEMP_DIR));
+    let mut attempt = 1;
+    loop {
+        let name = candidate_name(file_name, attempt);
+        let claim = temp_dir.join(format!("{}.claim", name));
+        match fs::OpenOptions::new().write(true).create_new(true).open(&claim) {
+            Ok(_) => {},
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                attempt += 1;
+                continue;
+            },
+            Err(e) => return Err(e)
+        }
+
+        let path = folder.join(&name);
+        let renamed = if fs::symlink_metadata(&path).is_ok() { Err(io::ErrorKind::AlreadyExists.into()) } else { fs::rename(temp_path, &path) };
+        let _ = fs::remove_file(&claim);
+        match renamed {
+            Ok(()) => return Ok(name),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => attempt += 1,
+            Err(e) => return Err(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::path::PathBuf;
+    use std::sync::{Arc, Barrier};
+    use std::thread;
+
+    /// Empty data and temporary folders of a test
+    fn folders(test: &str) -> (PathBuf, PathBuf) {
+        let root = std::env::temp_dir().join(format!("storage_{}_{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let (data, temp) = (root.join("data"), root.join("tmp"));
+        fs::create_dir_all(&data).unwrap();
+        fs::create_dir_all(&temp).unwrap();
+        (data, temp)
+    }
+
+    /// Stores files with the same name from many threads at once, like concurrent uploads
+    fn store_together(test: &str, uploads: usize, place: fn(&Path, &Path, &str) -> io::Result<String>) -> PathBuf {
+        let (data, temp) = folders(test);
+        let barrier = Arc::new(Barrier::new(uploads));
+        let threads: Vec<_> = (0..uploads).map(|i| {
+            let (data, temp, barrier) = (data.clone(), temp.clone(), barrier.clone());
+            thread::spawn(move || {
+                let temp_path = temp.join(format!("{}.part", i));
+                fs::write(&temp_path, format!("upload {}", i)).unwrap();
+                barrier.wait();
+                let name = place(&temp_path, &data, "notes.txt").unwrap();
+                let _ = fs::remove_file(&temp_path);
+                (name, i)
+            })
+        }).collect();
+
+        let stored: Vec<_> = threads.into_iter().map(|thread| thread.join().unwrap()).collect();
+        let names: HashSet<_> = stored.iter().map(|(name, _)| name.clone()).collect();
+        let expected: HashSet<_> = (1..=uploads as u32).map(|attempt| candidate_name("notes.txt", attempt)).collect();
+        assert_eq!(names, expected);
+        for (name, i) in stored {
+            assert_eq!(fs::read_to_string(data.join(name)).unwrap(), format!("upload {}", i));
+        }
+        temp
+    }
+
+    #[test]
+    fn numbers_repeated_names_before_the_extension() {
+        assert_eq!(candidate_name("notes.txt", 1), "notes.txt");
+        assert_eq!(candidate_name("notes.txt", 2), "notes_2.txt");
+        assert_eq!(candidate_name("notes.txt", 3), "notes_3.txt");
+        assert_eq!(candidate_name("notes.v2.txt", 2), "notes.v2_2.txt");
+        assert_eq!(candidate_name("README", 2), "README_2");
+        assert_eq!(candidate_name(".env", 2), ".env_2");
+    }
+
+    #[test]
+    fn keeps_compound_extensions_together() {
+        assert_eq!(candidate_name("backup.tar.gz", 2), "backup_2.tar.gz");
+        assert_eq!(candidate_name("Backup.TAR.XZ", 3), "Backup_3.TAR.XZ");
+        //A name that is only the extension has nothing before it to number
+        assert_eq!(candidate_name(".tar.gz", 2), ".tar_2.gz");
+    }
+
+    #[test]
+    fn drops_folders_from_client_names() {
+        assert_eq!(sanitize_name("../../etc/passwd"), "passwd");
+        assert_eq!(sanitize_name("C:\\Users\\me\\notes.txt"), "notes.txt");
+        assert_eq!(sanitize_name("folder/.."), "upload");
+        assert_eq!(sanitize_name("  "), "upload");
+    }
+
+    #[test]
+    fn links_concurrent_uploads_of_one_name_under_different_names() {
+        store_together("link", 8, link_into);
+    }
+
+    #[test]
+    fn renames_concurrent_uploads_of_one_name_under_different_names() {
+        let temp = store_together("rename", 8, rename_into);
+        //Every claim is gone once its upload was stored
+        assert!(fs::read_dir(temp).unwrap().flatten().all(|file| !file.path().to_string_lossy().ends_with(".claim")));
+    }
+
+    #[test]
+    fn never_renames_over_a_file_or_a_claimed_name() {
+        let (data, temp) = folders("claimed");
+        fs::write(data.join("notes.txt"), "first").unwrap();
+        fs::write(temp.join("notes_2.txt.claim"), "").unwrap();
+
+        let temp_path = temp.join("a.part");
+        fs::write(&temp_path, "second").unwrap();
+        assert_eq!(rename_into(&temp_path, &data, "notes.txt").unwrap(), "notes_3.txt");
+        assert_eq!(fs::read_to_string(data.join("notes.txt")).unwrap(), "first");
+        assert!(!data.join("notes_2.txt").exists());
+        assert_eq!(fs::read_to_string(data.join("notes_3.txt")).unwrap(), "second");
+    }
+}
@@ -0,0 +1,203 @@
+use crate::config::Config;
+
+/// Content types that are never accepted, since they can run code on the server or in a browser
+const FORBIDDEN_TYPES: [&str; 3] = ["application/x-executable", "text/html", "image/svg+xml"];
+
+/// Tags that make a text be treated as markup (HTML or SVG), only right after a ```<```
+const MARKUP_TAGS: [&str; 8] = ["!doctype", "html", "script", "iframe", "object", "embed", "svg", "?xml"];
+
+/// Scheme of links that run scripts, only markup at the start of a text or inside a tag, never in prose that mentions it
+const SCRIPT_SCHEME: &str = "javascript:";
+
+/// Bytes at the start of an upload that its type is detected from, the rest goes straight to disk
+pub const SNIFF_SIZE: usize = 8192;
+
+/// Checks if a lowercase text holds markup, looking only where a tag opens
+///
+/// # Arguments
+/// * `text: &str` - Text of the file, already in lowercase.
+fn is_markup(text: &str) -> bool {
+    if text.trim_start().starts_with(SCRIPT_SCHEME) {
+        return true;
+    }
+    //Every part after a < is where a tag would open, only a name right after it makes a tag
+    text.split('<').skip(1).any(|tag| {
+        MARKUP_TAGS.iter().any(|name| tag.starts_with(name))
+            || (tag.starts_with(|c: char| c.is_ascii_alphabetic()) && tag.split('>').next().unwrap_or_default().contains(SCRIPT_SCHEME))
+    })
+}
+
+/// Detects the content type of a file by looking at its bytes
+///
+/// # Arguments
+/// * `content: &[u8]` - File content, or its first [`SNIFF_SIZE`] bytes.
+pub fn detect_type(content: &[u8]) -> &'static str {
+    match content {
+        [b'M', b'Z', ..] | [0x7f, b'E', b'L', b'F', ..] | [b'#', b'!', ..] |
+        [0xfe, 0xed, 0xfa, 0xce | 0xcf, ..] | [0xce | 0xcf, 0xfa, 0xed, 0xfe, ..] |
+        [0xca, 0xfe, 0xba, 0xbe, ..] => return "application/x-executable",
+        [0x89, b'P', b'N', b'G', ..] => return "image/png",
+        [0xff, 0xd8, 0xff, ..] => return "image/jpeg",
+        [b'G', b'I', b'F', b'8', ..] => return "image/gif",
+        [b'%', b'P', b'D', b'F', ..] => return "application/pdf",
+        _ => {}
+    }
+
+    let text = match std::str::from_utf8(content) {
+        Ok(text) => text,
+        //The first bytes of a longer file may end in the middle of a character
+        Err(e) if e.error_len().is_none() && content.len() >= SNIFF_SIZE => std::str::from_utf8(&content[..e.valid_up_to()]).unwrap_or_default(),
+        Err(_) => return "application/octet-stream"
+    };
+    if text.chars().any(|c| c.is_control() && !matches!(c, '\n' | '\r' | '\t')) {
+        return "application/octet-stream";
+    }
+
+    let lowercase = text.to_lowercase();
+    if lowercase.contains("<svg") {
+        "image/svg+xml"
+    } else if is_markup(&lowercase) {
+        "text/html"
+    } else {
+        "text/plain"
+    }
+}
+
+/// Content type that a file extension is expected to have
+///
+/// # Arguments
+/// * `extension: &str` - Lowercase file extension, without the dot.
+fn expected_type(extension: &str) -> &'static str {
+    match extension {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "pdf" => "application/pdf",
+        "html" | "htm" => "text/html",
+        "svg" => "image/svg+xml",
+        "exe" | "sh" | "bin" | "elf" => "application/x-executable",
+        _ => "text/plain"
+    }
+}
+
+/// Checks if an uploaded file may be stored
+///
+/// # Arguments
+/// * `config: &Config` - Server's settings, which hold the allowed extensions and types.
+/// * `file_name: &str` - Name of the uploaded file.
+/// * `content: &[u8]` - Content of the uploaded file, or its first [`SNIFF_SIZE`] bytes.
+///
+/// ## Returns
+/// Nothing if the file is accepted
+/// A String with the reason if it is not
+pub fn check_upload(config: &Config, file_name: &str, content: &[u8]) -> Result<(), String> {
+    let extension = match file_name.rsplit_once('.') {
+        Some((name, extension)) if !name.is_empty() => extension.to_lowercase(),
+        _ => return Err(format!("File ({}) has no extension", file_name))
+    };
+    if !config.allowed_extensions.contains(&extension) {
+        return Err(format!("Extension (.{}) is not allowed, allowed ones are: {}", extension, config.allowed_extensions.join(", ")));
+    }
+
+    let detected = detect_type(content);
+    if FORBIDDEN_TYPES.contains(&detected) {
+        return Err(format!("File content was detected as ({}), which is never accepted", detected));
+    }
+    if !config.allowed_types.iter().any(|t| t == detected) {
+        return Err(format!("Content type ({}) is not allowed", detected));
+    }
+    if detected != expected_type(&extension) {
+        return Err(format!("File content ({}) does not match its extension (.{})", detected, extension));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(extensions: &[&str], types: &[&str]) -> Config {
+        Config {
+            allowed_extensions: extensions.iter().map(|e| e.to_string()).collect(),
+            allowed_types: types.iter().map(|t| t.to_string()).collect(),
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn detects_types_by_content() {
+        assert_eq!(detect_type(b"\x89PNG\r\n\x1a\n"), "image/png");
+        assert_eq!(detect_type(b"\xff\xd8\xff\xe0"), "image/jpeg");
+        assert_eq!(detect_type(b"GIF89a"), "image/gif");
+        assert_eq!(detect_type(b"%PDF-1.7"), "application/pdf");
+        assert_eq!(detect_type(b"\x7fELF\x02\x01"), "application/x-executable");
+        assert_eq!(detect_type(b"MZ\x90\x00"), "application/x-executable");
+        assert_eq!(detect_type(b"#!/bin/sh\nrm -rf /"), "application/x-executable");
+        assert_eq!(detect_type(b"hello\r\n\tworld"), "text/plain");
+        assert_eq!(detect_type(b""), "text/plain");
+        assert_eq!(detect_type(b"\x00\x01\x02"), "application/octet-stream");
+        assert_eq!(detect_type(b"\xc3\x28"), "application/octet-stream");
+    }
+
+    #[test]
+    fn detects_markup_in_any_case() {
+        assert_eq!(detect_type(b"<HTML><body>hi</body></HTML>"), "text/html");
+        assert_eq!(detect_type(b"notes <ScRiPt>alert(1)</script>"), "text/html");
+        assert_eq!(detect_type(b"<a href=\"JavaScript:alert(1)\">x</a>"), "text/html");
+        assert_eq!(detect_type(b"<?xml version=\"1.0\"?><SVG></SVG>"), "image/svg+xml");
+        assert_eq!(detect_type(b"  JavaScript:alert(1)"), "text/html");
+    }
+
+    #[test]
+    fn looks_for_markup_only_where_a_tag_opens() {
+        assert_eq!(detect_type(b"Never paste javascript: links in the address bar"), "text/plain");
+        assert_eq!(detect_type(b"if a < b, the script and html tags stay text, as does javascript:"), "text/plain");
+        assert_eq!(detect_type(b"see <b>bold</b> and javascript: here"), "text/plain");
+        assert_eq!(detect_type(b"<img src=x onerror=1 href=javascript:alert(1)>"), "text/html");
+    }
+
+    #[test]
+    fn detects_text_cut_in_the_middle_of_a_character() {
+        let mut start = "a".repeat(SNIFF_SIZE - 1).into_bytes();
+        start.push(0xc3);
+        assert_eq!(detect_type(&start), "text/plain");
+        assert_eq!(detect_type(&start[SNIFF_SIZE - 2..]), "application/octet-stream");
+    }
+
+    #[test]
+    fn accepts_allowed_files() {
+        let config = config(&["txt", "png"], &["text/plain", "image/png"]);
+
+        assert_eq!(check_upload(&config, "notes.txt", b"plain text"), Ok(()));
+        assert_eq!(check_upload(&config, "Photo.PNG", b"\x89PNG\r\n\x1a\n"), Ok(()));
+        assert_eq!(check_upload(&config, "security.txt", b"Links starting with javascript: run code when clicked."), Ok(()));
+    }
+
+    #[test]
+    fn refuses_bad_names_and_extensions() {
+        let config = config(&["txt"], &["text/plain"]);
+
+        assert!(check_upload(&config, "notes", b"text").is_err());
+        assert!(check_upload(&config, ".txt", b"text").is_err());
+        assert!(check_upload(&config, "notes.md", b"text").is_err());
+    }
+
+    #[test]
+    fn refuses_forbidden_and_mismatched_content() {
+        let config = config(&["txt", "html", "png"], &["text/plain", "text/html", "image/png"]);
+
+        assert!(check_upload(&config, "page.html", b"<html></html>").is_err());
+        assert!(check_upload(&config, "notes.txt", b"<script>alert(1)</script>").is_err());
+        assert!(check_upload(&config, "notes.txt", b"\x7fELF\x02").is_err());
+        assert!(check_upload(&config, "notes.txt", b"\x89PNG\r\n\x1a\n").is_err());
+        assert!(check_upload(&config, "image.png", b"plain text").is_err());
+    }
+
+    #[test]
+    fn refuses_types_not_allowed() {
+        let config = config(&["pdf"], &["text/plain"]);
+
+        assert!(check_upload(&config, "doc.pdf", b"%PDF-1.7").is_err());
+    }
+}
@@ -0,0 +1,224 @@
+use std::fmt;
+use std::io;
+use std::io::prelude::*;
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Maximum size of a request head (request line and headers), in bytes
+pub const MAX_HEAD_SIZE: usize = 16 * 1024;
+
+/// Why a read was given up because the peer was too slow
+///
+/// * `Idle` - The peer never sent a single byte.
+/// * `Head` - The head did not arrive in time.
+/// * `Body` - The body did not arrive in time.
+#[derive(Debug)]
+pub enum Timeout {
+    Idle,
+    Head,
+    Body,
+}
+
+/// Why a request or response could not be read
+///
+/// * `Timeout` - The peer was too slow.
+/// * `Io` - The connection failed, like when the peer resets it.
+/// * `Malformed` - The head was cut short, or the body is not framed the way its head says.
+/// * `TooLarge` - The body has more bytes than it may.
+/// * `HeadTooLarge` - The head is longer than [`MAX_HEAD_SIZE`].
+#[derive(Debug)]
+pub enum Error {
+    Timeout(Timeout),
+    Io(io::Error),
+    Malformed(String),
+    TooLarge(String),
+    HeadTooLarge(String),
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Timeout(timeout) => write!(f, "Connection timed out ({:?})", timeout),
+            Error::Io(e) => write!(f, "Connection failed: {}", e),
+            Error::Malformed(context) | Error::TooLarge(context) | Error::HeadTooLarge(context) => write!(f, "{}", context)
+        }
+    }
+}
+
+//Lets body readers pass these errors through io::Error, and the handler take them back out
+impl std::error::Error for Error {}
+
+/// Checks if an I/O error was caused by a socket timeout
+///
+/// # Arguments
+/// * `e: &io::Error` - Error returned by a socket operation.
+pub fn is_timeout(e: &io::Error) -> bool {
+    matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
+
+/// Reads a request or response head, everything up to the blank line that ends the headers
+///
+/// # Arguments
+/// * `stream: &mut TcpStream` - Stream that holds connection with client or server.
+/// * `idle_timeout: Duration` - How long to wait for the first byte.
+/// * `head_timeout: Duration` - How long the whole head may take after its first byte.
+///
+/// ## Returns
+/// The head as a String and the bytes of the body that were read along with it
+/// An empty head if the peer closed the connection before sending anything
+/// A Malformed error if it closed the connection in the middle of the head
+/// A HeadTooLarge error if the head is longer than [`MAX_HEAD_SIZE`]
+pub fn read_head(stream: &mut TcpStream, idle_timeout: Duration, head_timeout: Duration) -> Result<(String, Vec<u8>), Error> {
+    let mut request = Vec::new();
+    let mut buffer = [0; 8192];
+    let mut deadline: Option<Instant> = None;
+
+    loop {
+        let end = request.windows(4).position(|w| w == b"\r\n\r\n");
+        if end.map_or(request.len(), |end| end + 4) > MAX_HEAD_SIZE {
+            return Err(Error::HeadTooLarge(format!("Head is longer than {} bytes", MAX_HEAD_SIZE)));
+        }
+        if let Some(end) = end {
+            let body = request.split_off(end + 4);
+            return Ok((String::from_utf8_lossy(&request).to_string(), body));
+        }
+
+        //A deadline for the whole head keeps slow clients from holding the thread byte by byte
+        let timeout = match deadline {
+            None => idle_timeout,
+            Some(deadline) => deadline.saturating_duration_since(Instant::now())
+        };
+        if timeout.is_zero() {
+            return Err(Error::Timeout(Timeout::Head));
+        }
+        stream.set_read_timeout(Some(timeout))?;
+
+        let bytes_read = match stream.read(&mut buffer) {
+            Ok(bytes_read) => bytes_read,
+            Err(e) if is_timeout(&e) => return Err(Error::Timeout(if deadline.is_none() { Timeout::Idle } else { Timeout::Head })),
+            Err(e) => return Err(Error::Io(e))
+        };
+        //A peer may close its connection between requests, but not in the middle of a head
+        if bytes_read == 0 && request.is_empty() {
+            return Ok((String::new(), Vec::new()));
+        }
+        if bytes_read == 0 {
+            return Err(Error::Malformed("Connection closed before the end of the head".to_string()));
+        }
+        deadline.get_or_insert(Instant::now() + head_timeout);
+        request.extend_from_slice(&buffer[..bytes_read]);
+    }
+}
+
+/// Reads a head like [`read_head`], but waits on the event loop instead of blocking a thread
+///
+/// # Arguments
+/// * `stream: &mut S` - Non-blocking stream that holds connection with client or server.
+/// * `idle_timeout: Duration` - How long to wait for the first byte.
+/// * `head_timeout: Duration` - How long the whole head may take after its first byte.
+///
+/// ## Returns
+/// The head as a String and the bytes of the body that were read along with it
+/// An empty head if the peer closed the connection before sending anything
+/// A Malformed error if it closed the connection in the middle of the head
+/// A HeadTooLarge error if the head is longer than [`MAX_HEAD_SIZE`]
+pub async fn read_head_async<S: AsyncRead + Unpin>(stream: &mut S, idle_timeout: Duration, head_timeout: Duration) -> Result<(String, Vec<u8>), Error> {
+    let mut request = Vec::new();
+    let mut buffer = [0; 8192];
+    let mut deadline: Option<Instant> = None;
+
+    loop {
+        let end = request.windows(4).position(|w| w == b"\r\n\r\n");
+        if end.map_or(request.len(), |end| end + 4) > MAX_HEAD_SIZE {
+            return Err(Error::HeadTooLarge(format!("Head is longer than {} bytes", MAX_HEAD_SIZE)));
+        }
+        if let Some(end) = end {
+            let body = request.split_off(end + 4);
+            return Ok((String::from_utf8_lossy(&request).to_string(), body));
+        }
+
+        let timeout = match deadline {
+            None => idle_timeout,
+            Some(deadline) => deadline.saturating_duration_since(Instant::now())
+        };
+        let bytes_read = match tokio::time::timeout(timeout, stream.read(&mut buffer)).await {
+            Ok(result) => result?,
+            Err(_) => return Err(Error::Timeout(if deadline.is_none() { Timeout::Idle } else { Timeout::Head }))
+        };
+        //A peer may close its connection between requests, but not in the middle of a head
+        if bytes_read == 0 && request.is_empty() {
+            return Ok((String::new(), Vec::new()));
+        }
+        if bytes_read == 0 {
+            return Err(Error::Malformed("Connection closed before the end of the head".to_string()));
+        }
+        deadline.get_or_insert(Instant::now() + head_timeout);
+        request.extend_from_slice(&buffer[..bytes_read]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    /// Connected pair of sockets, the first one writes and the second one reads
+    fn socket_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let writer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (reader, _) = listener.accept().unwrap();
+        (writer, reader)
+    }
+
+    fn read_async(bytes: &[u8]) -> Result<(String, Vec<u8>), Error> {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        runtime.block_on(read_head_async(&mut &bytes[..], Duration::from_secs(5), Duration::from_secs(5)))
+    }
+
+    #[test]
+    fn reads_a_head_and_the_start_of_its_body() {
+        let (mut writer, mut reader) = socket_pair();
+        writer.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\nbody").unwrap();
+        let (head, body) = read_head(&mut reader, Duration::from_secs(5), Duration::from_secs(5)).unwrap();
+        assert_eq!(head, "HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\n");
+        assert_eq!(body, b"body");
+
+        assert_eq!(read_async(b"GET / HTTP/1.1\r\n\r\nbody").unwrap(), ("GET / HTTP/1.1\r\n\r\n".to_string(), b"body".to_vec()));
+    }
+
+    #[test]
+    fn refuses_a_head_over_the_limit() {
+        let (mut writer, mut reader) = socket_pair();
+        let head = format!("GET / HTTP/1.1\r\nX-Filler: {}\r\n\r\n", "a".repeat(MAX_HEAD_SIZE));
+        let copy = head.clone();
+        std::thread::spawn(move || writer.write_all(copy.as_bytes()));
+        assert!(matches!(read_head(&mut reader, Duration::from_secs(5), Duration::from_secs(5)), Err(Error::HeadTooLarge(_))));
+
+        assert!(matches!(read_async(head.as_bytes()), Err(Error::HeadTooLarge(_))));
+        assert!(matches!(read_async(&[b'a'; MAX_HEAD_SIZE + 1]), Err(Error::HeadTooLarge(_))));
+    }
+
+    #[test]
+    fn refuses_a_head_cut_by_the_peer() {
+        let (mut writer, mut reader) = socket_pair();
+        writer.write_all(b"GET / HTTP/1.1\r\nHost: x\r\n").unwrap();
+        drop(writer);
+        assert!(matches!(read_head(&mut reader, Duration::from_secs(5), Duration::from_secs(5)), Err(Error::Malformed(_))));
+        assert!(matches!(read_async(b"GET / HTTP/1.1\r\n"), Err(Error::Malformed(_))));
+    }
+
+    #[test]
+    fn takes_a_connection_closed_before_any_byte_as_an_empty_head() {
+        let (writer, mut reader) = socket_pair();
+        drop(writer);
+        assert_eq!(read_head(&mut reader, Duration::from_secs(5), Duration::from_secs(5)).unwrap(), (String::new(), Vec::new()));
+        assert_eq!(read_async(b"").unwrap(), (String::new(), Vec::new()));
+    }
+}
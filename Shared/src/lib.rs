@@ -0,0 +1,10 @@
+//! Code used the same way by the Server and by the Reverse Proxy
+//!
+//! * `http` - Reading of request and response heads.
+//! * `pattern` - Matching of paths against patterns with ```*```.
+//! * `pool` - Worker threads of the `io_mode = threads` mode.
+//! * `shutdown` - Handling of SIGINT and SIGTERM.
+pub mod http;
+pub mod pattern;
+pub mod pool;
+pub mod shutdown;
@@ -0,0 +1,55 @@
+/// Matches a text against a pattern where ```*``` stands for any sequence of characters
+///
+/// # Arguments
+/// * `pattern: &str` - Pattern to match with.
+/// * `text: &str` - Text that will be matched.
+pub fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    let mut last_star = None;
+    let mut star_text = 0;
+
+    while t < text.len() {
+        if p < pattern.len() && pattern[p] == '*' {
+            last_star = Some(p);
+            star_text = t;
+            p += 1;
+        } else if p < pattern.len() && pattern[p] == text[t] {
+            p += 1;
+            t += 1;
+        } else if let Some(star) = last_star {
+            p = star + 1;
+            star_text += 1;
+            t = star_text;
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wildcard_matches_any_sequence() {
+        assert!(wildcard_match("*", ""));
+        assert!(wildcard_match("*", "a/b.txt"));
+        assert!(wildcard_match("public/*", "public/notes.txt"));
+        assert!(wildcard_match("*.txt", "a/b/c.txt"));
+        assert!(wildcard_match("a*b*c", "aXXbYYc"));
+        assert!(wildcard_match("a*b", "abab"));
+    }
+
+    #[test]
+    fn wildcard_rejects_mismatches() {
+        assert!(!wildcard_match("public/*", "private/notes.txt"));
+        assert!(!wildcard_match("*.txt", "notes.txt.exe"));
+        assert!(!wildcard_match("notes.txt", "notes.txt2"));
+        assert!(!wildcard_match("a*b", "acb-"));
+        assert!(!wildcard_match("", "a"));
+    }
+}
@@ -0,0 +1,107 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Fixed amount of worker threads fed by a bounded queue
+///
+/// # Arguments
+/// * `sender` - Side of the queue where accepted items are put.
+/// * `workers` - Handles of the worker threads.
+pub struct WorkerPool<T: Send + 'static> {
+    sender: SyncSender<T>,
+    workers: Vec<thread::JoinHandle<()>>,
+}
+
+/// Loop of a single worker, which takes items out of the queue until it is closed
+///
+/// # Arguments
+/// * `id: usize` - Worker's number, used in reports.
+/// * `receiver: Arc<Mutex<Receiver<T>>>` - Side of the queue shared by all workers.
+/// * `handler: Arc<F>` - Function that handles each item.
+/// * `report: fn(String)` - Prints a message the way the program that runs the pool does.
+fn work<T, F>(id: usize, receiver: Arc<Mutex<Receiver<T>>>, handler: Arc<F>, report: fn(String))
+where
+    T: Send + 'static,
+    F: Fn(T) + Send + Sync + 'static,
+{
+    loop {
+        //The lock is only held while waiting, never while handling
+        let item = receiver.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).recv();
+        let Ok(item) = item else {
+            break;
+        };
+
+        //A panicking handler must not take the worker down with it
+        if panic::catch_unwind(AssertUnwindSafe(|| handler(item))).is_err() {
+            report(format!("Worker ({}) recovered from a panic >>> Taking next connection", id));
+        }
+    }
+}
+
+impl<T: Send + 'static> WorkerPool<T> {
+    /// Starts the worker threads
+    ///
+    /// # Arguments
+    /// * `size: usize` - Amount of worker threads.
+    /// * `queue_size: usize` - Amount of items that may wait for a free worker.
+    /// * `handler: F` - Function that handles each item.
+    /// * `report: fn(String)` - Prints a message the way the program that runs the pool does.
+    pub fn new<F>(size: usize, queue_size: usize, handler: F, report: fn(String)) -> WorkerPool<T>
+    where
+        F: Fn(T) + Send + Sync + 'static,
+    {
+        let (sender, receiver) = mpsc::sync_channel(queue_size);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let handler = Arc::new(handler);
+
+        let workers = (1..=size).map(|id| {
+            let receiver = Arc::clone(&receiver);
+            let handler = Arc::clone(&handler);
+            thread::spawn(move || work(id, receiver, handler, report))
+        }).collect();
+
+        WorkerPool { sender, workers }
+    }
+
+    /// Puts an item in the queue without waiting
+    ///
+    /// # Arguments
+    /// * `item: T` - Item that will be handled by a worker.
+    ///
+    /// ## Returns
+    /// Nothing if the item was queued
+    /// The item back if the queue is full
+    pub fn submit(&self, item: T) -> Result<(), T> {
+        match self.sender.try_send(item) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(item) | TrySendError::Disconnected(item)) => Err(item)
+        }
+    }
+
+    /// Amount of worker threads
+    pub fn size(&self) -> usize {
+        self.workers.len()
+    }
+
+    /// Stops taking items and waits for the workers to finish the ones already queued
+    ///
+    /// # Arguments
+    /// * `deadline: Duration` - How long to wait for the workers.
+    ///
+    /// ## Returns
+    /// The amount of workers that were still busy when the deadline passed
+    pub fn shutdown(self, deadline: Duration) -> usize {
+        let WorkerPool { sender, workers } = self;
+        //Workers leave their loop once the queue is closed and empty
+        drop(sender);
+
+        let end = Instant::now() + deadline;
+        while Instant::now() < end && workers.iter().any(|worker| !worker.is_finished()) {
+            thread::sleep(Duration::from_millis(50));
+        }
+
+        workers.iter().filter(|worker| !worker.is_finished()).count()
+    }
+}
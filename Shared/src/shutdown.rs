@@ -0,0 +1,56 @@
+use std::net::{SocketAddr, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::sync::Notify;
+
+/// Tells every part of the program that a shutdown was asked for by SIGINT or SIGTERM
+///
+/// # Arguments
+/// * `requested` - Whether a shutdown was asked for.
+/// * `notify` - Wakes everything on an event loop that waits for the shutdown.
+pub struct Shutdown {
+    requested: AtomicBool,
+    notify: Notify,
+}
+
+impl Shutdown {
+    /// Installs the signal handler
+    ///
+    /// # Arguments
+    /// * `address: SocketAddr` - Address the listener is bound to, connected to once to wake a blocking accept.
+    /// * `report: fn(String)` - Prints a message the way the program that is shut down does.
+    ///
+    /// A second signal stops the program right away, without waiting for connections to finish.
+    pub fn install(address: SocketAddr, report: fn(String)) -> Result<Arc<Shutdown>, ctrlc::Error> {
+        let shutdown = Arc::new(Shutdown { requested: AtomicBool::new(false), notify: Notify::new() });
+
+        let handler_shutdown = Arc::clone(&shutdown);
+        ctrlc::set_handler(move || {
+            if handler_shutdown.requested.swap(true, Ordering::SeqCst) {
+                report("Shutdown asked for again >>> Stopping now".to_string());
+                std::process::exit(1);
+            }
+            report("Shutdown asked for >>> No longer accepting connections".to_string());
+            handler_shutdown.notify.notify_waiters();
+            let _ = TcpStream::connect_timeout(&address, Duration::from_secs(1));
+        })?;
+
+        Ok(shutdown)
+    }
+
+    /// Whether a shutdown was asked for
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    /// Waits on the event loop until a shutdown is asked for
+    pub async fn wait(&self) {
+        //The waiter is registered before the flag is read, so a signal between the two is not missed
+        let mut notified = std::pin::pin!(self.notify.notified());
+        notified.as_mut().enable();
+        if !self.is_requested() {
+            notified.await;
+        }
+    }
+}